@@ -0,0 +1,63 @@
+//! Decoding throughput, run with `cargo bench --bench deserialize`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use v8_format::{ObjectMap, Value, ValuePool};
+
+/// A message of `len` records, each an object with an array and a set in it.
+fn message(len: i32) -> Vec<u8> {
+    let records = (0..len)
+        .map(|i| {
+            let mut record = ObjectMap::new();
+            record.insert(String::from("id"), Value::from(i));
+            record.insert(
+                String::from("tags"),
+                Value::Array((0..4).map(|tag| Value::from(i * 4 + tag)).collect()),
+            );
+            record.insert(
+                String::from("seen"),
+                Value::Set(vec![Value::from(i), Value::Null]),
+            );
+            Value::Object(record)
+        })
+        .collect();
+    v8_format::value_to_vec(&Value::Array(records)).unwrap()
+}
+
+fn deserialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deserialize");
+    // Many small messages, decoded and dropped one after another, and one
+    // large one
+    for &(name, len, count) in &[("small", 16, 1000), ("large", 10_000, 1)] {
+        let data = message(len);
+        group.throughput(Throughput::Bytes((data.len() * count) as u64));
+        group.bench_function(format!("from_slice Value {}", name), |b| {
+            b.iter(|| {
+                for _ in 0..count {
+                    black_box(v8_format::from_slice::<Value>(black_box(&data)).unwrap());
+                }
+            })
+        });
+        group.bench_function(format!("from_slice_checked {}", name), |b| {
+            b.iter(|| {
+                for _ in 0..count {
+                    black_box(v8_format::from_slice_checked(black_box(&data)).unwrap());
+                }
+            })
+        });
+        // The pool is warm after the first message
+        let mut pool = ValuePool::new();
+        group.bench_function(format!("from_slice_pooled {}", name), |b| {
+            b.iter(|| {
+                for _ in 0..count {
+                    let value = v8_format::from_slice_pooled(black_box(&data), &mut pool).unwrap();
+                    black_box(&*value);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, deserialize);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+//! Serialization throughput, run with `cargo bench --bench serialize`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use serde::Serialize;
+
+use v8_format::{Serializer, Value};
+
+fn serialize(c: &mut Criterion) {
+    // Varints of up to three bytes, and up to five for the `u32`s
+    let ints: Vec<i32> = (0..100_000)
+        .map(|i| (i * 7919) % 1_000_000 - 500_000)
+        .collect();
+    let uints: Vec<u32> = (0..100_000).map(|i| i * 40_503).collect();
+    let strings: Vec<String> = (0..10_000).map(|i| format!("item-{}", i)).collect();
+    let value = Value::Array(ints.iter().map(|&n| Value::Int32(n)).collect());
+
+    let mut group = c.benchmark_group("serialize");
+    group.throughput(Throughput::Elements(ints.len() as u64));
+    group.bench_function("to_vec Vec<i32>", |b| {
+        b.iter(|| v8_format::to_vec(black_box(&ints)).unwrap())
+    });
+    group.bench_function("to_vec Vec<u32>", |b| {
+        b.iter(|| v8_format::to_vec(black_box(&uints)).unwrap())
+    });
+    group.bench_function("value_to_vec Int32", |b| {
+        b.iter(|| v8_format::value_to_vec(black_box(&value)).unwrap())
+    });
+
+    // Reusing the serializer's buffer leaves only the encoding to measure
+    let mut serializer = Serializer::with_capacity(1 << 20);
+    group.bench_function("reused Serializer Vec<i32>", |b| {
+        b.iter(|| {
+            serializer.reset();
+            black_box(&ints).serialize(&mut serializer).unwrap();
+            black_box(serializer.as_bytes());
+        })
+    });
+
+    group.throughput(Throughput::Elements(strings.len() as u64));
+    group.bench_function("to_vec Vec<String>", |b| {
+        b.iter(|| v8_format::to_vec(black_box(&strings)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, serialize);
+criterion_main!(benches);
@@ -1,16 +1,92 @@
 use serde::{de, ser};
 use std::{
-    collections::{HashMap, HashSet},
     fmt::{self, Display},
+    iter::FromIterator,
+    ops::Index,
 };
 
+/// Properties of a `Value::Object`.
+///
+/// With the `preserve_order` feature (on by default) properties keep their
+/// insertion order, which is the order V8 writes and reads them in, so
+/// objects round-trip byte for byte. Without it they are sorted by key, which
+/// is still deterministic but doesn't match V8's output.
+#[cfg(feature = "preserve_order")]
+pub type ObjectMap = indexmap::IndexMap<String, Value>;
+#[cfg(not(feature = "preserve_order"))]
+pub type ObjectMap = std::collections::BTreeMap<String, Value>;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Error {
-    Expected { to_be: u8, but_got: u8 },
-    Unexpected { byte: u8, at: usize },
+    Expected {
+        to_be: u8,
+        but_got: u8,
+        at: usize,
+    },
+    Unexpected {
+        byte: u8,
+        at: usize,
+    },
+    UnexpectedEof {
+        at: usize,
+    },
+    DepthLimitExceeded {
+        at: usize,
+    },
+    StringTooLong {
+        len: usize,
+        offset: usize,
+    },
+    LimitExceeded {
+        len: usize,
+        at: usize,
+    },
+    /// A tuple was serialized with a different number of elements than
+    /// it said it had.
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidView {
+        ty: ArrayBufferViewType,
+        at: usize,
+    },
+    /// A view's type byte isn't one of the typed arrays or `DataView`,
+    /// likely one added by a newer V8.
+    UnknownViewType {
+        byte: u8,
+        at: usize,
+    },
+    /// The output needed `len` bytes but only `capacity` were given.
+    BufferTooSmall {
+        len: usize,
+        capacity: usize,
+    },
+    /// Bytes were left after the value, see
+    /// `DeserializerOptions::allow_trailing`.
+    TrailingBytes {
+        remaining: usize,
+    },
+    /// NaN or an infinity was serialized with
+    /// `SerializerOptions::reject_nonfinite` set.
+    NonFiniteFloat,
+    Malformed(Warning),
     Message(String),
 }
 
+/// A recoverable issue found while decoding.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    /// A length or count in a trailer doesn't match what was decoded.
+    LengthMismatch {
+        expected: u32,
+        actual: u32,
+        at: usize,
+    },
+    /// A varint was encoded with more bytes than necessary.
+    NonCanonicalVarint { at: usize },
+}
+
 impl ser::Error for Error {
     fn custom<T: Display>(msg: T) -> Self {
         Error::Message(msg.to_string())
@@ -26,13 +102,64 @@ impl de::Error for Error {
 impl Display for Error {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Error::Expected { to_be, but_got } => {
-                formatter.write_str(&format!("Expected byte {} but got {}", to_be, but_got))
-            }
+            Error::Expected { to_be, but_got, at } => formatter.write_str(&format!(
+                "Expected byte {} but got {} at {}",
+                to_be, but_got, at
+            )),
             Error::Unexpected { byte, at } => {
                 formatter.write_str(&format!("Unexpected byte {} at {}", byte, at))
             }
-            Error::Message(msg) => formatter.write_str(&msg),
+            Error::UnexpectedEof { at } => {
+                formatter.write_str(&format!("Unexpected end of input at {}", at))
+            }
+            Error::DepthLimitExceeded { at } => {
+                formatter.write_str(&format!("Depth limit exceeded at {}", at))
+            }
+            Error::StringTooLong { len, offset } => formatter.write_str(&format!(
+                "String of length {} exceeds the limit at {}",
+                len, offset
+            )),
+            Error::LimitExceeded { len, at } => {
+                formatter.write_str(&format!("Length {} exceeds the limit at {}", len, at))
+            }
+            Error::LengthMismatch { expected, actual } => formatter.write_str(&format!(
+                "Expected {} elements but got {}",
+                expected, actual
+            )),
+            Error::InvalidView { ty, at } => {
+                formatter.write_str(&format!("Invalid {:?} view at {}", ty, at))
+            }
+            Error::UnknownViewType { byte, at } => {
+                formatter.write_str(&format!("Unknown view type {:?} at {}", *byte as char, at))
+            }
+            Error::BufferTooSmall { len, capacity } => formatter.write_str(&format!(
+                "Output of {} bytes doesn't fit in a buffer of {}",
+                len, capacity
+            )),
+            Error::TrailingBytes { remaining } => {
+                formatter.write_str(&format!("{} trailing bytes after the value", remaining))
+            }
+            Error::NonFiniteFloat => formatter.write_str("Non-finite float"),
+            Error::Malformed(warning) => Display::fmt(warning, formatter),
+            Error::Message(msg) => formatter.write_str(msg),
+        }
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::LengthMismatch {
+                expected,
+                actual,
+                at,
+            } => formatter.write_str(&format!(
+                "Expected length {} but got {} at {}",
+                expected, actual, at
+            )),
+            Warning::NonCanonicalVarint { at } => {
+                formatter.write_str(&format!("Non-canonical varint at {}", at))
+            }
         }
     }
 }
@@ -41,6 +168,67 @@ impl std::error::Error for Error {}
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+#[test]
+fn test_error_display() {
+    let error = Error::Expected {
+        to_be: b'$',
+        but_got: b'T',
+        at: 5,
+    };
+    assert_eq!(error.to_string(), "Expected byte 36 but got 84 at 5");
+    assert_eq!(
+        Error::UnexpectedEof { at: 3 }.to_string(),
+        "Unexpected end of input at 3"
+    );
+    assert_eq!(
+        Error::Malformed(Warning::NonCanonicalVarint { at: 2 }).to_string(),
+        "Non-canonical varint at 2"
+    );
+    assert_eq!(<Error as ser::Error>::custom("oops").to_string(), "oops");
+
+    let boxed: Box<dyn std::error::Error> = Box::new(Error::DepthLimitExceeded { at: 7 });
+    assert_eq!(boxed.to_string(), "Depth limit exceeded at 7");
+    assert_eq!(
+        Error::StringTooLong { len: 9, offset: 3 }.to_string(),
+        "String of length 9 exceeds the limit at 3"
+    );
+    assert_eq!(
+        Error::LimitExceeded { len: 10, at: 2 }.to_string(),
+        "Length 10 exceeds the limit at 2"
+    );
+    assert_eq!(
+        Error::LengthMismatch {
+            expected: 3,
+            actual: 2
+        }
+        .to_string(),
+        "Expected 3 elements but got 2"
+    );
+    let error = Error::InvalidView {
+        ty: ArrayBufferViewType::Int32Array,
+        at: 4,
+    };
+    assert_eq!(error.to_string(), "Invalid Int32Array view at 4");
+    assert_eq!(
+        Error::UnknownViewType { byte: b'x', at: 6 }.to_string(),
+        "Unknown view type 'x' at 6"
+    );
+    assert_eq!(Error::NonFiniteFloat.to_string(), "Non-finite float");
+    assert_eq!(
+        Error::TrailingBytes { remaining: 2 }.to_string(),
+        "2 trailing bytes after the value"
+    );
+    assert_eq!(
+        Error::BufferTooSmall {
+            len: 9,
+            capacity: 4
+        }
+        .to_string(),
+        "Output of 9 bytes doesn't fit in a buffer of 4"
+    );
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ArrayBufferViewType {
     Int8Array,
     Uint8Array,
@@ -49,6 +237,7 @@ pub enum ArrayBufferViewType {
     Uint16Array,
     Int32Array,
     Uint32Array,
+    Float16Array,
     Float32Array,
     Float64Array,
     BigInt64Array,
@@ -56,6 +245,44 @@ pub enum ArrayBufferViewType {
     DataView,
 }
 
+impl ArrayBufferViewType {
+    /// Bytes per element, 1 for a `DataView`.
+    pub fn element_size(self) -> usize {
+        match self {
+            ArrayBufferViewType::Int8Array
+            | ArrayBufferViewType::Uint8Array
+            | ArrayBufferViewType::Uint8ClampedArray
+            | ArrayBufferViewType::DataView => 1,
+            ArrayBufferViewType::Int16Array
+            | ArrayBufferViewType::Uint16Array
+            | ArrayBufferViewType::Float16Array => 2,
+            ArrayBufferViewType::Int32Array
+            | ArrayBufferViewType::Uint32Array
+            | ArrayBufferViewType::Float32Array => 4,
+            ArrayBufferViewType::Float64Array
+            | ArrayBufferViewType::BigInt64Array
+            | ArrayBufferViewType::BigUint64Array => 8,
+        }
+    }
+
+    /// Whether a view fits in its buffer and is aligned to its elements, V8
+    /// refuses to read it otherwise.
+    // `is_multiple_of` needs Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    pub(crate) fn is_valid_view(
+        self,
+        byte_offset: u32,
+        byte_length: u32,
+        buffer_len: usize,
+    ) -> bool {
+        let size = self.element_size();
+        byte_offset as usize + byte_length as usize <= buffer_len
+            && byte_offset as usize % size == 0
+            && byte_length as usize % size == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ErrorType {
     EvalError,
     RangeError,
@@ -66,6 +293,7 @@ pub enum ErrorType {
     Unknown,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Undefined,
     Null,
@@ -78,7 +306,7 @@ pub enum Value {
     ObjectReference {
         id: u32,
     },
-    Object(HashMap<String, Value>),
+    Object(ObjectMap),
     Array(Vec<Value>),
     Date(f64),
     NumberObject(f64),
@@ -88,8 +316,11 @@ pub enum Value {
         expr: String,
         flags: u32,
     },
-    Map(HashMap<Value, Value>),
-    Set(HashSet<Value>),
+    /// Entries in insertion order. Keys aren't deduplicated, JS compares
+    /// object keys by identity so equal looking keys can be distinct.
+    Map(Vec<(Value, Value)>),
+    /// Values in insertion order, see `Map` about duplicates.
+    Set(Vec<Value>),
     ArrayBuffer(Vec<u8>),
     ArrayBufferTransfer {
         transfer_id: u32,
@@ -108,167 +339,962 @@ pub enum Value {
         message: Option<String>,
         stack: Option<String>,
     },
+    /// A `WebAssembly.Module` written as its wire bytes. Newer V8 versions
+    /// only transfer modules by id (`'w'`), which is `Unsupported`.
+    WasmModule(Vec<u8>),
+    /// A `WebAssembly.Memory`, whose shared buffer is transferred by id.
+    /// `maximum_pages` is -1 if the memory has no maximum.
+    WasmMemory {
+        maximum_pages: i32,
+        transfer_id: u32,
+    },
+    /// An object written by the embedder's delegate (`'\\'`), as the bytes
+    /// after the tag. Their layout is up to the delegate, see
+    /// `Deserializer::on_host_object`.
+    HostObject(Vec<u8>),
+    /// An object this crate can step over but doesn't model, kept as its tag
+    /// and the bytes after it so it's written back unchanged. Only produced
+    /// with `DeserializerOptions::allow_unsupported`.
+    ///
+    /// V8 refuses to serialize functions, so those never show up here.
+    Unsupported {
+        tag: u8,
+        data: Vec<u8>,
+    },
 }
 
 impl Value {
-    pub fn is_undefined(&self) -> bool {
-        match self {
-            &Value::Undefined => true,
-            _ => false,
+    /// A `DataView` over `byte_length` bytes of `buffer` from `byte_offset`.
+    /// Unlike typed arrays, data views may start and end at any byte.
+    pub fn data_view(buffer: Vec<u8>, byte_offset: u32, byte_length: u32) -> Value {
+        Value::ArrayBufferView {
+            ty: ArrayBufferViewType::DataView,
+            byte_offset,
+            byte_length,
+            buffer,
         }
     }
 
-    pub fn is_null(&self) -> bool {
-        match self {
-            &Value::Null => true,
-            _ => false,
+    /// A typed array over its own buffer, see `uint8_array` and friends.
+    fn typed_array(ty: ArrayBufferViewType, buffer: Vec<u8>) -> Value {
+        Value::ArrayBufferView {
+            ty,
+            byte_offset: 0,
+            byte_length: buffer.len() as u32,
+            buffer,
         }
     }
 
+    /// A `Uint8Array` holding a copy of `values`.
+    ///
+    /// Like the other typed array constructors, the elements are packed
+    /// little endian, the byte order of every platform V8 runs on in
+    /// practice.
+    pub fn uint8_array(values: &[u8]) -> Value {
+        Value::typed_array(ArrayBufferViewType::Uint8Array, values.to_vec())
+    }
+
+    pub fn uint8_clamped_array(values: &[u8]) -> Value {
+        Value::typed_array(ArrayBufferViewType::Uint8ClampedArray, values.to_vec())
+    }
+
+    pub fn int8_array(values: &[i8]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::Int8Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    pub fn uint16_array(values: &[u16]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::Uint16Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    pub fn int16_array(values: &[i16]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::Int16Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    pub fn uint32_array(values: &[u32]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::Uint32Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    pub fn int32_array(values: &[i32]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::Int32Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    /// A `Float16Array` of `values` rounded to the nearest half precision
+    /// float, those out of its range to an infinity.
+    pub fn float16_array(values: &[f32]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::Float16Array,
+            values
+                .iter()
+                .flat_map(|&v| f32_to_f16(v).to_le_bytes())
+                .collect(),
+        )
+    }
+
+    pub fn float32_array(values: &[f32]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::Float32Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    pub fn float64_array(values: &[f64]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::Float64Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    pub fn bigint64_array(values: &[i64]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::BigInt64Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    pub fn biguint64_array(values: &[u64]) -> Value {
+        Value::typed_array(
+            ArrayBufferViewType::BigUint64Array,
+            values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        )
+    }
+
+    pub fn is_undefined(&self) -> bool {
+        matches!(self, Value::Undefined)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
     pub fn is_boolean(&self) -> bool {
-        match self {
-            &Value::Boolean(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Boolean(_))
     }
 
     pub fn is_int32(&self) -> bool {
+        matches!(self, Value::Int32(_))
+    }
+
+    pub fn is_uint32(&self) -> bool {
+        matches!(self, Value::Uint32(_))
+    }
+
+    pub fn is_double(&self) -> bool {
+        matches!(self, Value::Double(_))
+    }
+
+    pub fn is_bigint(&self) -> bool {
+        matches!(self, Value::BigInt(_))
+    }
+
+    pub fn is_string(&self) -> bool {
+        matches!(self, Value::String(_, _))
+    }
+
+    pub fn is_object_reference(&self) -> bool {
+        matches!(self, Value::ObjectReference { .. })
+    }
+
+    pub fn is_object(&self) -> bool {
+        matches!(self, Value::Object(_))
+    }
+
+    pub fn is_array(&self) -> bool {
+        matches!(self, Value::Array(_))
+    }
+
+    pub fn is_date(&self) -> bool {
+        matches!(self, Value::Date(_))
+    }
+
+    pub fn is_number_object(&self) -> bool {
+        matches!(self, Value::NumberObject(_))
+    }
+
+    pub fn is_bigint_object(&self) -> bool {
+        matches!(self, Value::BigIntObject())
+    }
+
+    pub fn is_string_object(&self) -> bool {
+        matches!(self, Value::StringObject(_))
+    }
+
+    pub fn is_regexp(&self) -> bool {
+        matches!(self, Value::RegExp { .. })
+    }
+
+    pub fn is_map(&self) -> bool {
+        matches!(self, Value::Map(_))
+    }
+
+    pub fn is_set(&self) -> bool {
+        matches!(self, Value::Set(_))
+    }
+
+    pub fn is_array_buffer(&self) -> bool {
+        matches!(self, Value::ArrayBuffer(_))
+    }
+
+    pub fn is_array_buffer_transfer(&self) -> bool {
+        matches!(self, Value::ArrayBufferTransfer { .. })
+    }
+
+    pub fn is_array_buffer_view(&self) -> bool {
+        matches!(self, Value::ArrayBufferView { .. })
+    }
+
+    pub fn is_shared_array_buffer(&self) -> bool {
+        matches!(self, Value::SharedArrayBuffer { .. })
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Value::Error { .. })
+    }
+
+    pub fn is_wasm_module(&self) -> bool {
+        matches!(self, Value::WasmModule(_))
+    }
+
+    pub fn is_wasm_memory(&self) -> bool {
+        matches!(self, Value::WasmMemory { .. })
+    }
+
+    pub fn is_host_object(&self) -> bool {
+        matches!(self, Value::HostObject(_))
+    }
+
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self, Value::Unsupported { .. })
+    }
+
+    /// A name for the variant, for messages like "expected object, got
+    /// array".
+    pub fn type_name(&self) -> &'static str {
         match self {
-            &Value::Int32(_) => true,
-            _ => false,
+            Value::Undefined => "undefined",
+            Value::Null => "null",
+            Value::Boolean(_) => "boolean",
+            Value::Int32(_) => "int32",
+            Value::Uint32(_) => "uint32",
+            Value::Double(_) => "double",
+            Value::BigInt(_) => "bigint",
+            Value::String(..) => "string",
+            Value::ObjectReference { .. } => "object reference",
+            Value::Object(_) => "object",
+            Value::Array(_) => "array",
+            Value::Date(_) => "date",
+            Value::NumberObject(_) => "number object",
+            Value::BigIntObject() => "bigint object",
+            Value::StringObject(_) => "string object",
+            Value::RegExp { .. } => "regexp",
+            Value::Map(_) => "map",
+            Value::Set(_) => "set",
+            Value::ArrayBuffer(_) => "array buffer",
+            Value::ArrayBufferTransfer { .. } => "array buffer transfer",
+            Value::ArrayBufferView { .. } => "array buffer view",
+            Value::SharedArrayBuffer { .. } => "shared array buffer",
+            Value::Error { .. } => "error",
+            Value::WasmModule(_) => "wasm module",
+            Value::WasmMemory { .. } => "wasm memory",
+            Value::HostObject(_) => "host object",
+            Value::Unsupported { .. } => "unsupported",
         }
     }
 
-    pub fn is_uint32(&self) -> bool {
+    pub fn as_bool(&self) -> Option<bool> {
         match self {
-            &Value::Uint32(_) => true,
-            _ => false,
+            Value::Boolean(value) => Some(*value),
+            _ => None,
         }
     }
 
-    pub fn is_double(&self) -> bool {
+    pub fn as_i32(&self) -> Option<i32> {
         match self {
-            &Value::Double(_) => true,
-            _ => false,
+            Value::Int32(value) => Some(*value),
+            _ => None,
         }
     }
 
-    pub fn is_bigint(&self) -> bool {
+    pub fn as_u32(&self) -> Option<u32> {
         match self {
-            &Value::BigInt(_) => true,
-            _ => false,
+            Value::Uint32(value) => Some(*value),
+            _ => None,
         }
     }
 
-    pub fn is_string(&self) -> bool {
+    /// Returns any JS number (`Int32`, `Uint32` or `Double`) as an `f64`.
+    pub fn as_f64(&self) -> Option<f64> {
         match self {
-            &Value::String(_, _) => true,
-            _ => false,
+            Value::Int32(value) => Some(*value as f64),
+            Value::Uint32(value) => Some(*value as f64),
+            Value::Double(value) => Some(*value),
+            _ => None,
         }
     }
 
-    pub fn is_object_reference(&self) -> bool {
+    /// ```
+    /// use v8_format::Value;
+    ///
+    /// let value = Value::String(String::from("hello"), false);
+    /// assert_eq!(value.as_str(), Some("hello"));
+    /// assert_eq!(Value::Null.as_str(), None);
+    /// ```
+    pub fn as_str(&self) -> Option<&str> {
         match self {
-            &Value::ObjectReference { .. } => true,
-            _ => false,
+            Value::String(value, _) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_object(&self) -> bool {
+    /// ```
+    /// use v8_format::{Deserializer, Value};
+    ///
+    /// let data = [0xFF, v8_format::FORMAT_VERSION, b'A', 2, b'T', b'F', b'$', 0, 2];
+    /// let value = Deserializer::new().deserialize(&data).unwrap();
+    ///
+    /// let elements = value.as_array().unwrap();
+    /// assert_eq!(elements.len(), 2);
+    /// assert_eq!(elements[0].as_bool(), Some(true));
+    /// ```
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
         match self {
-            &Value::Object(_) => true,
-            _ => false,
+            Value::Array(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_array(&self) -> bool {
+    pub fn as_array_mut(&mut self) -> Option<&mut Vec<Value>> {
         match self {
-            &Value::Array(_) => true,
-            _ => false,
+            Value::Array(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_date(&self) -> bool {
+    /// ```
+    /// use v8_format::{ObjectMap, Value};
+    ///
+    /// let mut properties = ObjectMap::new();
+    /// properties.insert(String::from("x"), Value::Int32(69));
+    /// let value = Value::Object(properties);
+    ///
+    /// let object = value.as_object().unwrap();
+    /// assert_eq!(object["x"].as_i32(), Some(69));
+    /// assert_eq!(object["x"].as_f64(), Some(69.0));
+    /// ```
+    pub fn as_object(&self) -> Option<&ObjectMap> {
         match self {
-            &Value::Date(_) => true,
-            _ => false,
+            Value::Object(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_number_object(&self) -> bool {
+    pub fn as_object_mut(&mut self) -> Option<&mut ObjectMap> {
         match self {
-            &Value::NumberObject(_) => true,
-            _ => false,
+            Value::Object(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_bigint_object(&self) -> bool {
+    pub fn as_map(&self) -> Option<&Vec<(Value, Value)>> {
         match self {
-            &Value::BigIntObject() => true,
-            _ => false,
+            Value::Map(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_string_object(&self) -> bool {
+    pub fn as_map_mut(&mut self) -> Option<&mut Vec<(Value, Value)>> {
         match self {
-            &Value::StringObject(_) => true,
-            _ => false,
+            Value::Map(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_regexp(&self) -> bool {
+    pub fn as_set(&self) -> Option<&Vec<Value>> {
         match self {
-            &Value::RegExp { .. } => true,
-            _ => false,
+            Value::Set(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_map(&self) -> bool {
+    pub fn as_set_mut(&mut self) -> Option<&mut Vec<Value>> {
         match self {
-            &Value::Map(_) => true,
-            _ => false,
+            Value::Set(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_set(&self) -> bool {
+    /// Returns the contents of an `ArrayBuffer`.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
         match self {
-            &Value::Set(_) => true,
-            _ => false,
+            Value::ArrayBuffer(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_array_buffer(&self) -> bool {
+    pub fn as_bytes_mut(&mut self) -> Option<&mut Vec<u8>> {
         match self {
-            &Value::ArrayBuffer(_) => true,
-            _ => false,
+            Value::ArrayBuffer(value) => Some(value),
+            _ => None,
         }
     }
 
-    pub fn is_array_buffer_transfer(&self) -> bool {
+    /// Returns the bytes a `DataView` covers, `None` for anything else or a
+    /// view that doesn't fit in its buffer.
+    pub fn as_data_view(&self) -> Option<&[u8]> {
+        self.typed_array_bytes(ArrayBufferViewType::DataView)
+    }
+
+    /// The bytes a view of type `ty` covers. The end can overflow where
+    /// `usize` is 32 bits, such a view doesn't fit in its buffer either.
+    fn typed_array_bytes(&self, ty: ArrayBufferViewType) -> Option<&[u8]> {
         match self {
-            &Value::ArrayBufferTransfer { .. } => true,
-            _ => false,
+            Value::ArrayBufferView {
+                ty: view_ty,
+                byte_offset,
+                byte_length,
+                buffer,
+            } if *view_ty == ty => {
+                let start = *byte_offset as usize;
+                buffer.get(start..start.checked_add(*byte_length as usize)?)
+            }
+            _ => None,
         }
     }
 
-    pub fn is_array_buffer_view(&self) -> bool {
+    /// Copies the elements of a `Uint8Array` (or `Uint8ClampedArray`).
+    ///
+    /// Like the other typed array accessors, this is `None` for any other
+    /// value, a typed array of another type included, or a view that
+    /// doesn't fit in its buffer. Elements are read little endian, see
+    /// `uint8_array`.
+    pub fn as_u8_slice(&self) -> Option<Vec<u8>> {
+        self.typed_array_bytes(ArrayBufferViewType::Uint8Array)
+            .or_else(|| self.typed_array_bytes(ArrayBufferViewType::Uint8ClampedArray))
+            .map(<[u8]>::to_vec)
+    }
+
+    pub fn as_i8_slice(&self) -> Option<Vec<i8>> {
+        self.typed_array_bytes(ArrayBufferViewType::Int8Array)
+            .map(|bytes| elements(bytes, i8::from_le_bytes))
+    }
+
+    pub fn as_u16_slice(&self) -> Option<Vec<u16>> {
+        self.typed_array_bytes(ArrayBufferViewType::Uint16Array)
+            .map(|bytes| elements(bytes, u16::from_le_bytes))
+    }
+
+    pub fn as_i16_slice(&self) -> Option<Vec<i16>> {
+        self.typed_array_bytes(ArrayBufferViewType::Int16Array)
+            .map(|bytes| elements(bytes, i16::from_le_bytes))
+    }
+
+    pub fn as_u32_slice(&self) -> Option<Vec<u32>> {
+        self.typed_array_bytes(ArrayBufferViewType::Uint32Array)
+            .map(|bytes| elements(bytes, u32::from_le_bytes))
+    }
+
+    pub fn as_i32_slice(&self) -> Option<Vec<i32>> {
+        self.typed_array_bytes(ArrayBufferViewType::Int32Array)
+            .map(|bytes| elements(bytes, i32::from_le_bytes))
+    }
+
+    /// Copies the elements of a `Float16Array`, widened to `f32`s.
+    pub fn as_f16_slice(&self) -> Option<Vec<f32>> {
+        self.typed_array_bytes(ArrayBufferViewType::Float16Array)
+            .map(|bytes| elements(bytes, |bytes| f16_to_f32(u16::from_le_bytes(bytes))))
+    }
+
+    pub fn as_f32_slice(&self) -> Option<Vec<f32>> {
+        self.typed_array_bytes(ArrayBufferViewType::Float32Array)
+            .map(|bytes| elements(bytes, f32::from_le_bytes))
+    }
+
+    pub fn as_f64_slice(&self) -> Option<Vec<f64>> {
+        self.typed_array_bytes(ArrayBufferViewType::Float64Array)
+            .map(|bytes| elements(bytes, f64::from_le_bytes))
+    }
+
+    pub fn as_i64_slice(&self) -> Option<Vec<i64>> {
+        self.typed_array_bytes(ArrayBufferViewType::BigInt64Array)
+            .map(|bytes| elements(bytes, i64::from_le_bytes))
+    }
+
+    pub fn as_u64_slice(&self) -> Option<Vec<u64>> {
+        self.typed_array_bytes(ArrayBufferViewType::BigUint64Array)
+            .map(|bytes| elements(bytes, u64::from_le_bytes))
+    }
+
+    /// Converts the value to a boolean the way JavaScript's `ToBoolean` does.
+    ///
+    /// The falsy values are `undefined`, `null`, `false`, `0`, `-0`, `NaN`,
+    /// `0n` and the empty string. Everything else is truthy, including empty
+    /// arrays and objects, and boxed primitives such as `new Number(0)` or
+    /// `new String("")`, since those are objects.
+    pub fn is_truthy(&self) -> bool {
         match self {
-            &Value::ArrayBufferView { .. } => true,
-            _ => false,
+            Value::Undefined | Value::Null => false,
+            Value::Boolean(value) => *value,
+            Value::Int32(value) => *value != 0,
+            Value::Uint32(value) => *value != 0,
+            Value::Double(value) => *value != 0.0 && !value.is_nan(),
+            Value::BigInt(value) => *value != 0,
+            Value::String(value, _) => !value.is_empty(),
+            _ => true,
         }
     }
 
-    pub fn is_shared_array_buffer(&self) -> bool {
+    /// Looks up a property of an object, or the entry of a map under a
+    /// string key.
+    ///
+    /// ```
+    /// use v8_format::Value;
+    ///
+    /// let user: Value = vec![(String::from("id"), Value::Int32(7))]
+    ///     .into_iter()
+    ///     .collect();
+    /// assert_eq!(user.get("id"), Some(&Value::Int32(7)));
+    /// assert_eq!(user["id"], Value::Int32(7));
+    /// assert_eq!(user.get("name"), None);
+    /// ```
+    pub fn get(&self, key: &str) -> Option<&Value> {
         match self {
-            &Value::SharedArrayBuffer { .. } => true,
-            _ => false,
+            Value::Object(properties) => properties.get(key),
+            Value::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, value)| value),
+            _ => None,
         }
     }
 
-    pub fn is_error(&self) -> bool {
+    /// Looks up an element of an array.
+    pub fn get_index(&self, index: usize) -> Option<&Value> {
         match self {
-            &Value::Error { .. } => true,
-            _ => false,
+            Value::Array(values) => values.get(index),
+            _ => None,
+        }
+    }
+}
+
+/// Splits `bytes` into elements of `N` bytes, dropping any left over.
+fn elements<T, const N: usize>(bytes: &[u8], from_bytes: fn([u8; N]) -> T) -> Vec<T> {
+    bytes
+        .chunks_exact(N)
+        .map(|chunk| {
+            let mut element = [0; N];
+            element.copy_from_slice(chunk);
+            from_bytes(element)
+        })
+        .collect()
+}
+
+/// The IEEE 754 half precision bits closest to `value`, ties to even.
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xFF) as i32;
+    let mantissa = bits & 0x7F_FFFF;
+    if exponent == 0xFF {
+        // Infinities, and NaNs kept quiet
+        return sign | 0x7C00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1F {
+        return sign | 0x7C00;
+    }
+    if exponent <= 0 {
+        // Subnormal, or too small even for that
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let rounded = mantissa + (1 << (shift - 1)) - 1 + ((mantissa >> shift) & 1);
+        return sign | (rounded >> shift) as u16;
+    }
+    // A carry out of the mantissa bumps the exponent, up to infinity
+    let rounded = mantissa + 0xFFF + ((mantissa >> 13) & 1);
+    sign | (((exponent as u32) << 10) + (rounded >> 13)) as u16
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1F) as i32;
+    let mantissa = (bits & 0x3FF) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1F if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1F => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// Like `Value::get`, but panics if there's no such property.
+impl Index<&str> for Value {
+    type Output = Value;
+
+    fn index(&self, key: &str) -> &Value {
+        match self.get(key) {
+            Some(value) => value,
+            None => panic!("no property {:?} in {}", key, self.type_name()),
+        }
+    }
+}
+
+/// Like `Value::get_index`, but panics if there's no such element.
+impl Index<usize> for Value {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Value {
+        match self.get_index(index) {
+            Some(value) => value,
+            None => panic!("no element {} in {}", index, self.type_name()),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Boolean(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Int32(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Uint32(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Double(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_owned(), false)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value, false)
+    }
+}
+
+/// Bytes become an `ArrayBuffer`.
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::ArrayBuffer(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Value::Array(value)
+    }
+}
+
+/// Collects into an `Array`.
+impl FromIterator<Value> for Value {
+    fn from_iter<I: IntoIterator<Item = Value>>(iter: I) -> Self {
+        Value::Array(iter.into_iter().collect())
+    }
+}
+
+/// Collects properties into an `Object`.
+impl FromIterator<(String, Value)> for Value {
+    fn from_iter<I: IntoIterator<Item = (String, Value)>>(iter: I) -> Self {
+        Value::Object(iter.into_iter().collect())
+    }
+}
+
+/// Collects entries into a `Map`.
+impl FromIterator<(Value, Value)> for Value {
+    fn from_iter<I: IntoIterator<Item = (Value, Value)>>(iter: I) -> Self {
+        Value::Map(iter.into_iter().collect())
+    }
+}
+
+#[test]
+fn test_from() {
+    assert_eq!(Value::from(true), Value::Boolean(true));
+    assert_eq!(Value::from(42), Value::Int32(42));
+    assert_eq!(Value::from(42u32), Value::Uint32(42));
+    assert_eq!(Value::from(0.5), Value::Double(0.5));
+    assert_eq!(Value::from("hi"), Value::String(String::from("hi"), false));
+    assert_eq!(
+        Value::from(String::from("hi")),
+        Value::String(String::from("hi"), false)
+    );
+    assert_eq!(Value::from(vec![1u8, 2]), Value::ArrayBuffer(vec![1, 2]));
+    assert_eq!(
+        Value::from(vec![Value::Null]),
+        Value::Array(vec![Value::Null])
+    );
+}
+
+#[test]
+fn test_from_iter() {
+    let value: Value = (0..3).map(Value::from).collect();
+    assert_eq!(
+        value,
+        Value::Array(vec![Value::Int32(0), Value::Int32(1), Value::Int32(2)])
+    );
+
+    let value: Value = vec![(String::from("x"), Value::from(1))]
+        .into_iter()
+        .collect();
+    assert_eq!(value.as_object().unwrap()["x"], Value::Int32(1));
+
+    let value: Value = vec![(Value::from(1), Value::from("one"))]
+        .into_iter()
+        .collect();
+    assert_eq!(
+        value,
+        Value::Map(vec![(Value::Int32(1), Value::from("one"))])
+    );
+}
+
+#[test]
+fn test_accessors() {
+    assert_eq!(Value::Boolean(true).as_bool(), Some(true));
+    assert_eq!(Value::Int32(-1).as_i32(), Some(-1));
+    assert_eq!(Value::Int32(-1).as_u32(), None);
+    assert_eq!(Value::Uint32(1).as_u32(), Some(1));
+    assert_eq!(Value::Uint32(1).as_f64(), Some(1.0));
+    assert_eq!(Value::Double(0.5).as_f64(), Some(0.5));
+    assert_eq!(Value::BigInt(1).as_f64(), None);
+    assert_eq!(Value::Null.as_bool(), None);
+
+    let mut value = Value::ArrayBuffer(vec![1, 2]);
+    value.as_bytes_mut().unwrap().push(3);
+    assert_eq!(value.as_bytes(), Some(&[1, 2, 3][..]));
+    assert!(value.as_array().is_none());
+
+    let mut value = Value::Array(Vec::new());
+    value.as_array_mut().unwrap().push(Value::Null);
+    assert_eq!(value.as_array().map(Vec::len), Some(1));
+    assert!(value.as_object().is_none());
+    assert!(value.as_map().is_none());
+    assert!(value.as_set().is_none());
+}
+
+#[test]
+fn test_type_name() {
+    assert_eq!(Value::Undefined.type_name(), "undefined");
+    assert_eq!(Value::Boolean(false).type_name(), "boolean");
+    assert_eq!(Value::Int32(1).type_name(), "int32");
+    assert_eq!(Value::from("a").type_name(), "string");
+    assert_eq!(Value::Object(ObjectMap::new()).type_name(), "object");
+    assert_eq!(Value::Array(Vec::new()).type_name(), "array");
+    assert_eq!(
+        Value::data_view(Vec::new(), 0, 0).type_name(),
+        "array buffer view"
+    );
+}
+
+#[test]
+fn test_is_truthy() {
+    assert!(!Value::Undefined.is_truthy());
+    assert!(!Value::Null.is_truthy());
+    assert!(!Value::Boolean(false).is_truthy());
+    assert!(!Value::Int32(0).is_truthy());
+    assert!(!Value::Uint32(0).is_truthy());
+    assert!(!Value::Double(0.0).is_truthy());
+    assert!(!Value::Double(-0.0).is_truthy());
+    assert!(!Value::Double(f64::NAN).is_truthy());
+    assert!(!Value::BigInt(0).is_truthy());
+    assert!(!Value::String(String::new(), false).is_truthy());
+
+    assert!(Value::Boolean(true).is_truthy());
+    assert!(Value::Int32(-1).is_truthy());
+    assert!(Value::Uint32(1).is_truthy());
+    assert!(Value::Double(f64::INFINITY).is_truthy());
+    assert!(Value::BigInt(1).is_truthy());
+    assert!(Value::String(String::from("0"), false).is_truthy());
+    assert!(Value::Array(Vec::new()).is_truthy());
+    assert!(Value::Object(ObjectMap::new()).is_truthy());
+    assert!(Value::NumberObject(0.0).is_truthy());
+    assert!(Value::StringObject(String::new()).is_truthy());
+}
+
+#[test]
+fn test_get() {
+    let user: Value = vec![
+        (String::from("id"), Value::Int32(7)),
+        (
+            String::from("tags"),
+            Value::Array(vec![Value::from("a"), Value::from("b")]),
+        ),
+        (
+            String::from("meta"),
+            Value::Map(vec![
+                (Value::Int32(1), Value::Null),
+                (Value::from("since"), Value::Date(0.0)),
+            ]),
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    assert_eq!(user["id"], Value::Int32(7));
+    assert_eq!(user["tags"][1], Value::from("b"));
+    assert_eq!(user["meta"]["since"], Value::Date(0.0));
+    assert_eq!(user.get("name"), None);
+    assert_eq!(user["tags"].get_index(2), None);
+    assert_eq!(user["meta"].get("1"), None);
+    assert_eq!(user.get_index(0), None);
+    assert_eq!(Value::Null.get("id"), None);
+}
+
+#[test]
+fn test_typed_arrays() {
+    let value = Value::float64_array(&[1.5, -2.0]);
+    match &value {
+        Value::ArrayBufferView {
+            ty,
+            byte_offset,
+            byte_length,
+            buffer,
+        } => {
+            assert_eq!(*ty, ArrayBufferViewType::Float64Array);
+            assert_eq!((*byte_offset, *byte_length), (0, 16));
+            assert_eq!(buffer[..8], 1.5f64.to_le_bytes());
+            assert_eq!(buffer[8..], (-2.0f64).to_le_bytes());
+        }
+        _ => panic!("expected a view, got {:?}", value),
+    }
+
+    let value = Value::bigint64_array(&[-1, i64::MAX]);
+    let mut expected = vec![0xFF; 8];
+    expected.extend(i64::MAX.to_le_bytes());
+    assert_eq!(
+        value,
+        Value::ArrayBufferView {
+            ty: ArrayBufferViewType::BigInt64Array,
+            byte_offset: 0,
+            byte_length: 16,
+            buffer: expected,
         }
+    );
+
+    // Valid views, which encode and decode as they are
+    for value in [
+        Value::uint8_array(&[1, 2, 3]),
+        Value::int16_array(&[-1]),
+        Value::float32_array(&[]),
+        Value::float64_array(&[0.25]),
+    ] {
+        let data = crate::value_to_vec(&value).unwrap();
+        assert_eq!(crate::from_slice_checked(&data), Ok(value));
+    }
+}
+
+#[test]
+fn test_typed_array_accessors() {
+    let value = Value::float64_array(&[1.5, -2.0, f64::INFINITY]);
+    assert_eq!(value.as_f64_slice(), Some(vec![1.5, -2.0, f64::INFINITY]));
+    assert_eq!(value.as_f32_slice(), None);
+    assert_eq!(value.as_u8_slice(), None);
+    assert_eq!(Value::Null.as_f64_slice(), None);
+
+    // Only the part of the buffer the view covers
+    let mut buffer = vec![0xEE; 4];
+    buffer.extend(0.5f64.to_le_bytes());
+    buffer.extend(0.25f64.to_le_bytes());
+    let view = Value::ArrayBufferView {
+        ty: ArrayBufferViewType::Float64Array,
+        byte_offset: 4,
+        byte_length: 8,
+        buffer,
+    };
+    assert_eq!(view.as_f64_slice(), Some(vec![0.5]));
+
+    // Views past the end of their buffer are `None`, however far past
+    for (byte_offset, byte_length) in [(8, 16), (u32::MAX, 8), (u32::MAX, u32::MAX)] {
+        let view = Value::ArrayBufferView {
+            ty: ArrayBufferViewType::Float64Array,
+            byte_offset,
+            byte_length,
+            buffer: vec![0; 16],
+        };
+        assert_eq!(view.as_f64_slice(), None);
     }
+    assert_eq!(
+        Value::data_view(vec![0; 4], u32::MAX, 1).as_data_view(),
+        None
+    );
+
+    assert_eq!(
+        Value::int32_array(&[-1, 7]).as_i32_slice(),
+        Some(vec![-1, 7])
+    );
+    assert_eq!(
+        Value::bigint64_array(&[i64::MIN]).as_i64_slice(),
+        Some(vec![i64::MIN])
+    );
+    assert_eq!(
+        Value::uint8_clamped_array(&[255]).as_u8_slice(),
+        Some(vec![255])
+    );
+    assert_eq!(Value::data_view(vec![1], 0, 1).as_u8_slice(), None);
+}
+
+#[test]
+fn test_float16_array() {
+    let value = Value::float16_array(&[1.0, -2.5, 65504.0, 0.1]);
+    let encoded = crate::value_to_vec(&value).unwrap();
+    assert_eq!(encoded[encoded.len() - 4..], [b'V', b'h', 0, 8]);
+    assert_eq!(encoded[4..8], [0x00, 0x3C, 0x00, 0xC1]);
+    let decoded = crate::from_slice_checked(&encoded).unwrap();
+    assert_eq!(decoded, value);
+    // 0.1 isn't exact in half precision either
+    assert_eq!(
+        decoded.as_f16_slice(),
+        Some(vec![1.0, -2.5, 65504.0, 0.099975586])
+    );
+    assert_eq!(decoded.as_u16_slice(), None);
+
+    assert_eq!(f32_to_f16(0.0), 0);
+    assert_eq!(f32_to_f16(-0.0), 0x8000);
+    assert_eq!(f32_to_f16(f32::INFINITY), 0x7C00);
+    assert_eq!(f32_to_f16(65520.0), 0x7C00);
+    assert_eq!(f32_to_f16(2f32.powi(-24)), 1);
+    assert_eq!(f32_to_f16(2f32.powi(-25)), 0);
+    // Ties go to the even mantissa
+    assert_eq!(f32_to_f16(1.0 + 2f32.powi(-11)), 0x3C00);
+    assert_eq!(f32_to_f16(1.0 + 3.0 * 2f32.powi(-11)), 0x3C02);
+    assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+    for bits in (0..0x7C00).step_by(7) {
+        assert_eq!(f32_to_f16(f16_to_f32(bits)), bits);
+    }
+}
+
+#[test]
+#[should_panic(expected = "no element 2 in array")]
+fn test_index_missing() {
+    let _ = &Value::Array(vec![Value::Null, Value::Null])[2];
 }
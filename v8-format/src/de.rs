@@ -1,74 +1,380 @@
 #![allow(dead_code)]
 
 use crate::common::Value;
+use crate::common::ArrayBufferViewType;
 use crate::common::Error;
+use crate::common::ErrorType;
 use crate::common::Result;
+use crate::common::Warning;
+use crate::pointer::JsonPointer;
+use crate::pool::{Pooled, ValuePool};
+
+use integer_encoding::VarInt;
+use serde::de;
+use serde::de::IntoDeserializer;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+
+/// Default nesting limit for objects, arrays and other containers.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// Default limit on the number of values decoded, copies of referenced
+/// objects included.
+pub const DEFAULT_MAX_NODES: usize = 1 << 24;
+
+#[derive(Clone, Debug)]
+pub struct DeserializerOptions {
+    /// How deeply containers may be nested before parsing fails with
+    /// `Error::DepthLimitExceeded`.
+    pub max_depth: usize,
+    /// How many values, counting every value inside containers, may be
+    /// decoded before parsing fails with `Error::LimitExceeded`. A `'^'`
+    /// reference decodes to a copy of the object it points to and counts as
+    /// all of its values, so a few bytes of references to references can't
+    /// expand into an exponentially large `Value`.
+    pub max_nodes: usize,
+    /// Record recoverable issues as `Warning`s instead of failing with
+    /// `Error::Malformed`. Truncated input and unknown tags are still errors.
+    pub collect_warnings: bool,
+    /// Longest string, in code units, that is accepted before parsing fails
+    /// with `Error::StringTooLong`. `None` means no limit.
+    pub max_string_len: Option<usize>,
+    /// Longest buffer (`ArrayBuffer` or WebAssembly module), in bytes, that
+    /// is accepted before parsing fails with `Error::LimitExceeded`. `None`
+    /// means no limit.
+    pub max_buffer_len: Option<usize>,
+    /// Most elements an array, set or map, or properties an object, may have
+    /// before parsing fails with `Error::LimitExceeded`. `None` means no
+    /// limit.
+    pub max_collection_len: Option<usize>,
+    /// Decode objects whose layout is known but that `Value` doesn't model
+    /// (boolean objects, BigInt objects, WebAssembly modules and shared
+    /// objects) as `Value::Unsupported` instead of failing.
+    pub allow_unsupported: bool,
+    /// Replace unpaired surrogates in two-byte strings with U+FFFD instead of
+    /// failing. JS strings don't have to be valid UTF-16, Rust ones do.
+    pub lossy_strings: bool,
+    /// Ignore bytes left after the value instead of failing with
+    /// `Error::TrailingBytes`, which usually means the input is corrupt or
+    /// several values were concatenated. `next_value` reads values one
+    /// after another and never checks.
+    pub allow_trailing: bool,
+}
+
+impl Default for DeserializerOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_nodes: DEFAULT_MAX_NODES,
+            collect_warnings: false,
+            max_string_len: None,
+            max_buffer_len: None,
+            max_collection_len: None,
+            allow_unsupported: false,
+            lossy_strings: false,
+            allow_trailing: false,
+        }
+    }
+}
 
 pub struct Deserializer<'a> {
     data: &'a [u8],
     offset: usize,
+    depth: usize,
+    options: DeserializerOptions,
+    warnings: Vec<Warning>,
+    /// Decoded objects by id, `None` while an object is still being decoded.
+    objects: Vec<Option<Value>>,
+    /// How many values each object in `objects` is, while it's being decoded
+    /// the count at which it began.
+    object_nodes: Vec<usize>,
+    /// Values decoded so far, see `DeserializerOptions::max_nodes`.
+    nodes: usize,
+    host_object: Option<Box<HostObjectHook<'a>>>,
+    /// Where containers are taken from, empty unless decoding with
+    /// `from_slice_pooled`.
+    pool: ValuePool,
+}
+
+/// Given the data after a host object's tag, returns how much of it is the
+/// object's, see `Deserializer::on_host_object`.
+type HostObjectHook<'a> = dyn FnMut(&[u8]) -> Option<usize> + 'a;
+
+impl<'a> Default for Deserializer<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> Deserializer<'a> {
     pub fn new() -> Self {
+        Self::with_options(DeserializerOptions::default())
+    }
+
+    pub fn with_options(options: DeserializerOptions) -> Self {
         Self {
             data: &[],
             offset: 0,
+            depth: 0,
+            options,
+            warnings: Vec::new(),
+            objects: Vec::new(),
+            object_nodes: Vec::new(),
+            nodes: 0,
+            host_object: None,
+            pool: ValuePool::new(),
+        }
+    }
+
+    /// Sets how deeply containers may be nested before parsing fails with
+    /// `Error::DepthLimitExceeded`. Defaults to `DEFAULT_MAX_DEPTH`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_depth = max_depth;
+        self
+    }
+
+    /// Sets `DeserializerOptions::max_nodes`.
+    pub fn with_max_nodes(mut self, max_nodes: usize) -> Self {
+        self.options.max_nodes = max_nodes;
+        self
+    }
+
+    /// Sets `DeserializerOptions::max_buffer_len`.
+    pub fn with_max_buffer_len(mut self, max_buffer_len: usize) -> Self {
+        self.options.max_buffer_len = Some(max_buffer_len);
+        self
+    }
+
+    /// Sets `DeserializerOptions::max_collection_len`.
+    pub fn with_max_collection_len(mut self, max_collection_len: usize) -> Self {
+        self.options.max_collection_len = Some(max_collection_len);
+        self
+    }
+
+    /// Decodes host objects as `Value::HostObject`, using `hook` to find
+    /// where they end.
+    ///
+    /// Host objects are written by the embedder's delegate (e.g. Node.js
+    /// writes typed arrays this way) in a layout only it knows, without a
+    /// length, so they can't be stepped over otherwise and are an error by
+    /// default. `hook` is given everything after the tag and returns the
+    /// length of the object's data, or `None` if it doesn't recognize it.
+    /// Fully supporting them takes a reimplementation of the delegate.
+    pub fn on_host_object<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&[u8]) -> Option<usize> + 'a,
+    {
+        self.host_object = Some(Box::new(hook));
+        self
+    }
+
+    /// Sets the data `next_value` reads values from.
+    pub fn with_input(mut self, data: &'a [u8]) -> Self {
+        self.data = data;
+        self.offset = 0;
+        self
+    }
+
+    /// Reports a recoverable issue, which is only an error if warnings
+    /// aren't being collected.
+    fn warn(&mut self, warning: Warning) -> Result<()> {
+        if self.options.collect_warnings {
+            self.warnings.push(warning);
+            Ok(())
+        } else {
+            Err(Error::Malformed(warning))
+        }
+    }
+
+    /// Returns the byte at the current offset without consuming it.
+    #[inline(always)]
+    fn peek(&self) -> Result<u8> {
+        match self.data.get(self.offset) {
+            Some(byte) => Ok(*byte),
+            None => Err(Error::UnexpectedEof { at: self.offset }),
         }
     }
 
+    /// Returns the byte at the current offset and moves past it.
     #[inline(always)]
-    fn byte(&self) -> u8 {
-        self.data[self.offset]
+    fn read_byte(&mut self) -> Result<u8> {
+        let byte = self.peek()?;
+        self.next()?;
+        Ok(byte)
     }
 
     fn expect_next(&mut self, to_be: u8) -> Result<()> {
-        if self.byte() == to_be {
-            self.next();
+        let byte = self.peek()?;
+        if byte == to_be {
+            self.next()?;
             Ok(())
         } else {
             Err(Error::Expected {
                 to_be,
-                but_got: self.byte(),
+                but_got: byte,
+                at: self.offset,
             })
         }
     }
 
     #[inline(always)]
-    fn next(&mut self) -> usize {
-        self.offset += 1;
-        self.offset
+    fn next(&mut self) -> Result<()> {
+        self.advance(1)
+    }
+
+    /// Moves `n` bytes ahead, which must all be there. Every move forward
+    /// goes through here, so the offset can't wrap or run past the end
+    /// whatever lengths the input claims.
+    fn advance(&mut self, n: usize) -> Result<()> {
+        match self.offset.checked_add(n) {
+            Some(end) if end <= self.data.len() => {
+                self.offset = end;
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedEof {
+                at: self.data.len(),
+            }),
+        }
+    }
+
+    fn varint<V: VarInt>(&mut self) -> Result<V> {
+        let start = self.offset;
+        while self.read_byte()? & 0x80 != 0 {}
+
+        let bytes = &self.data[start..self.offset];
+        let value = match V::decode_var(bytes) {
+            Some((value, _)) => value,
+            None => return Err(Error::Message(format!("Invalid varint at {}", start))),
+        };
+
+        // A trailing zero group means the value could have been encoded shorter
+        if bytes.len() > 1 && bytes[bytes.len() - 1] == 0 {
+            self.warn(Warning::NonCanonicalVarint { at: start })?;
+        }
+
+        Ok(value)
+    }
+
+    /// Reads the length prefixed payload of a string, `unit` bytes per code
+    /// unit. The length is checked against `max_string_len` before anything
+    /// is read.
+    fn read_string(&mut self, unit: usize) -> Result<&'a [u8]> {
+        let offset = self.offset;
+        let len = self.varint::<u32>()? as usize;
+        if let Some(max) = self.options.max_string_len {
+            if len / unit > max {
+                return Err(Error::StringTooLong {
+                    len: len / unit,
+                    offset,
+                });
+            }
+        }
+
+        self.read_bytes(len)
+    }
+
+    /// Reads the length of a buffer, checked against `max_buffer_len`.
+    fn read_buffer_len(&mut self) -> Result<usize> {
+        let at = self.offset;
+        let len = self.varint::<u32>()? as usize;
+        Self::check_len(self.options.max_buffer_len, len, at)?;
+        Ok(len)
+    }
+
+    /// Reads the declared length of an array, checked against
+    /// `max_collection_len` before any elements are read.
+    fn read_collection_len(&mut self) -> Result<usize> {
+        let at = self.offset;
+        let len = self.varint::<u32>()? as usize;
+        self.check_collection_len(len, at)?;
+        Ok(len)
+    }
+
+    /// Checks the number of entries seen so far in a collection without a
+    /// declared length.
+    fn check_collection_len(&self, len: usize, at: usize) -> Result<()> {
+        Self::check_len(self.options.max_collection_len, len, at)
+    }
+
+    fn check_len(max: Option<usize>, len: usize, at: usize) -> Result<()> {
+        match max {
+            Some(max) if len > max => Err(Error::LimitExceeded { len, at }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the next `len` bytes and moves past them.
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let start = self.offset;
+        self.advance(len)?;
+        Ok(&self.data[start..self.offset])
+    }
+
+    /// Gives the object starting here the next id. Like V8, objects are
+    /// numbered in the order they begin, so containers come before their
+    /// contents.
+    fn begin_object(&mut self) -> usize {
+        self.objects.push(None);
+        self.object_nodes.push(self.nodes);
+        self.objects.len() - 1
+    }
+
+    /// Records the decoded object `id` so later references can resolve to it.
+    fn end_object(&mut self, id: usize, value: Value) -> Value {
+        self.objects[id] = Some(self.pool.clone_value(&value));
+        // The object itself was counted before it began
+        self.object_nodes[id] = self.nodes - self.object_nodes[id] + 1;
+        value
+    }
+
+    /// Counts `count` more decoded values against `max_nodes`.
+    fn count_nodes(&mut self, count: usize, at: usize) -> Result<()> {
+        self.nodes = self.nodes.saturating_add(count);
+        Self::check_len(Some(self.options.max_nodes), self.nodes, at)
+    }
+
+    /// Called before descending into a container.
+    fn enter(&mut self) -> Result<()> {
+        if self.depth >= self.options.max_depth {
+            return Err(Error::DepthLimitExceeded { at: self.offset });
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
     }
 
     fn is_version(&self) -> bool {
-        self.byte() == 0xFF
+        self.peek() == Ok(0xFF)
     }
 
     fn is_undefined(&self) -> bool {
-        self.byte() == '_' as u8
+        self.peek() == Ok(b'_')
     }
 
     fn parse_undefined(&mut self) -> Result<Value> {
-        self.expect_next('_' as u8)?;
+        self.expect_next(b'_')?;
         Ok(Value::Undefined)
     }
 
     fn is_null(&self) -> bool {
-        self.byte() == '0' as u8
+        self.peek() == Ok(b'0')
     }
 
     fn parse_null(&mut self) -> Result<Value> {
-        self.expect_next('0' as u8)?;
-        Ok(Value::Undefined)
+        self.expect_next(b'0')?;
+        Ok(Value::Null)
     }
 
     fn is_bool(&self) -> bool {
-        let byte = self.byte();
-        byte == 'T' as u8 || byte == 'F' as u8
+        let byte = self.peek();
+        byte == Ok(b'T') || byte == Ok(b'F')
     }
 
     fn parse_bool(&mut self) -> Result<Value> {
-        let byte = self.byte();
+        let byte = self.peek()?;
 
         let res = match byte as char {
             'T' => Ok(Value::Boolean(true)),
@@ -79,74 +385,3078 @@ impl<'a> Deserializer<'a> {
             }),
         };
 
-        if res.is_ok() {
-            self.next();
-        }
-
-        res
+        let value = res?;
+        self.next()?;
+        Ok(value)
     }
 
     fn is_int32(&self) -> bool {
-        self.byte() == 'I' as u8
+        self.peek() == Ok(b'I')
+    }
+
+    fn read_int32(&mut self) -> Result<i32> {
+        self.expect_next(b'I')?;
+        self.varint()
     }
 
     fn is_uint32(&self) -> bool {
-        self.byte() == 'U' as u8
+        self.peek() == Ok(b'U')
+    }
+
+    fn read_uint32(&mut self) -> Result<u32> {
+        self.expect_next(b'U')?;
+        self.varint()
     }
 
     fn is_double(&self) -> bool {
-        self.byte() == 'N' as u8
+        self.peek() == Ok(b'N')
+    }
+
+    fn read_double(&mut self) -> Result<f64> {
+        self.expect_next(b'N')?;
+        self.read_f64()
+    }
+
+    /// Reads a double in host byte order, see `Serializer::write_f64`.
+    fn read_f64(&mut self) -> Result<f64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(f64::from_ne_bytes(bytes))
     }
 
     fn is_bigint(&self) -> bool {
-        self.byte() == 'Z' as u8
+        self.peek() == Ok(b'Z')
+    }
+
+    /// Reads a BigInt as its sign and magnitude, see `write_bigint_digits`.
+    fn read_bigint(&mut self) -> Result<(bool, u64)> {
+        let at = self.offset;
+        self.expect_next(b'Z')?;
+        let flags = self.varint::<u32>()?;
+        let negative = flags & 1 == 1;
+        let digits = self.read_bytes((flags >> 1) as usize)?;
+
+        // todo: support i128 too
+        if digits.len() > 8 && digits[8..].iter().any(|&byte| byte != 0) {
+            return Err(Error::Message(format!(
+                "BigInt does not fit in 64 bits at {}",
+                at
+            )));
+        }
+        let mut bytes = [0; 8];
+        let len = digits.len().min(8);
+        bytes[..len].copy_from_slice(&digits[..len]);
+        Ok((negative, u64::from_le_bytes(bytes)))
+    }
+
+    fn parse_bigint(&mut self) -> Result<Value> {
+        let at = self.offset;
+        match bigint_to_i64(self.read_bigint()?) {
+            Some(value) => Ok(Value::BigInt(value)),
+            None => Err(Error::Message(format!(
+                "BigInt does not fit in 64 bits at {}",
+                at
+            ))),
+        }
     }
 
     fn is_utf8_string(&self) -> bool {
-        self.byte() == 'S' as u8
+        self.peek() == Ok(b'S')
+    }
+
+    /// Unlike `'"'` strings, which are Latin-1 with one byte per character,
+    /// `'S'` strings are UTF-8 and their length is in bytes, not characters.
+    fn parse_utf8_string(&mut self) -> Result<Value> {
+        Ok(Value::String(self.read_utf8_string()?.to_owned(), false))
+    }
+
+    /// V8 stopped writing UTF-8 strings long ago but still reads them, and
+    /// they're the one kind of string that can always be borrowed.
+    fn read_utf8_string(&mut self) -> Result<&'a str> {
+        self.expect_next(b'S')?;
+        let at = self.offset;
+        std::str::from_utf8(self.read_string(1)?)
+            .map_err(|_| Error::Message(format!("Invalid UTF-8 string at {}", at)))
     }
 
     fn is_one_byte_string(&self) -> bool {
-        self.byte() == '"' as u8
+        self.peek() == Ok(b'"')
+    }
+
+    fn parse_one_byte_string(&mut self) -> Result<Value> {
+        Ok(Value::String(self.read_one_byte_string()?, false))
+    }
+
+    /// Every byte is a character (U+0000 to U+00FF), so anything outside
+    /// ASCII needs re-encoding as UTF-8, see `parse_utf8_string`.
+    fn read_one_byte_string(&mut self) -> Result<String> {
+        self.expect_next(b'"')?;
+        Ok(latin1(self.read_string(1)?).into_owned())
+    }
+
+    /// Reads a string where only a string can be, e.g. a RegExp's source.
+    fn read_string_value(&mut self) -> Result<String> {
+        self.skip_padding()?;
+        let at = self.offset;
+        match self.peek()? {
+            b'"' => self.read_one_byte_string(),
+            b'c' => self.read_two_byte_string(),
+            b'S' => Ok(self.read_utf8_string()?.to_owned()),
+            byte => Err(Error::Unexpected { byte, at }),
+        }
     }
 
     fn is_two_byte_string(&self) -> bool {
-        self.byte() == 'c' as u8
+        self.peek() == Ok(b'c')
+    }
+
+    fn parse_two_byte_string(&mut self) -> Result<Value> {
+        Ok(Value::String(self.read_two_byte_string()?, true))
+    }
+
+    /// Two-byte strings are UTF-16 in the host's byte order, like doubles.
+    // `is_multiple_of` needs Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    fn read_two_byte_string(&mut self) -> Result<String> {
+        self.expect_next(b'c')?;
+        let at = self.offset;
+        let bytes = self.read_string(2)?;
+        if bytes.len() % 2 != 0 {
+            return Err(Error::Message(format!(
+                "Two-byte string of {} bytes at {}",
+                bytes.len(),
+                at
+            )));
+        }
+
+        let units = bytes
+            .chunks_exact(2)
+            .map(|unit| u16::from_ne_bytes([unit[0], unit[1]]));
+        if self.options.lossy_strings {
+            Ok(char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect())
+        } else {
+            char::decode_utf16(units)
+                .collect::<std::result::Result<String, _>>()
+                .map_err(|_| Error::Message(format!("Unpaired surrogate in string at {}", at)))
+        }
+    }
+
+    /// V8 pads with zero bytes so two-byte strings start aligned, they can
+    /// be skipped wherever a tag is expected.
+    fn skip_padding(&mut self) -> Result<()> {
+        while self.peek() == Ok(0) {
+            self.next()?;
+        }
+        Ok(())
     }
 
     fn is_object_reference(&self) -> bool {
-        self.byte() == '^' as u8
+        self.peek() == Ok(b'^')
+    }
+
+    /// Resolves a reference to a copy of the object it points to. `Value`
+    /// can't express cycles, so references to an object that is still being
+    /// decoded stay a `Value::ObjectReference`.
+    fn parse_object_reference(&mut self) -> Result<Value> {
+        let at = self.offset;
+        self.expect_next(b'^')?;
+        let id = self.varint::<u32>()?;
+        match self.objects.get(id as usize) {
+            Some(Some(value)) => {
+                // The reference was counted as one value, its copy is more.
+                // Checked before copying, which is what could blow up.
+                let nodes = self
+                    .nodes
+                    .saturating_add(self.object_nodes[id as usize] - 1);
+                Self::check_len(Some(self.options.max_nodes), nodes, at)?;
+                self.nodes = nodes;
+                Ok(self.pool.clone_value(value))
+            }
+            Some(None) => Ok(Value::ObjectReference { id }),
+            None => Err(Error::Message(format!(
+                "Invalid object reference {} at {}",
+                id, at
+            ))),
+        }
+    }
+
+    /// Fails on the value here, which serde has no equivalent of or
+    /// `expected` doesn't take, naming it like `Value::type_name` does.
+    fn invalid_type<T>(&mut self, expected: &dyn de::Expected) -> Result<T> {
+        let value = self.parse()?;
+        Err(de::Error::invalid_type(
+            de::Unexpected::Other(value.type_name()),
+            expected,
+        ))
     }
 
     fn is_object(&self) -> bool {
-        self.byte() == 'o' as u8
+        self.peek() == Ok(b'o')
+    }
+
+    fn parse_object(&mut self) -> Result<Value> {
+        self.enter()?;
+        self.expect_next(b'o')?;
+        let id = self.begin_object();
+        let mut properties = self.pool.take_object();
+        while self.peek()? != b'{' {
+            self.check_collection_len(properties.len() + 1, self.offset)?;
+            let key = self.parse_property_key()?;
+            let value = self.parse()?;
+            properties.insert(key, value);
+        }
+        self.read_end(b'{', properties.len() as u32)?;
+        self.leave();
+
+        Ok(self.end_object(id, Value::Object(properties)))
+    }
+
+    /// Property keys are strings or, for integer indices, numbers.
+    fn parse_property_key(&mut self) -> Result<String> {
+        let at = self.offset;
+        match self.parse()? {
+            Value::String(key, _) => Ok(key),
+            Value::Int32(key) => Ok(key.to_string()),
+            Value::Uint32(key) => Ok(key.to_string()),
+            Value::Double(key) => Ok(key.to_string()),
+            _ => Err(Error::Unexpected {
+                byte: self.data[at],
+                at,
+            }),
+        }
+    }
+
+    fn is_map(&self) -> bool {
+        self.peek() == Ok(b';')
+    }
+
+    fn parse_map(&mut self) -> Result<Value> {
+        self.enter()?;
+        self.expect_next(b';')?;
+        let id = self.begin_object();
+        let mut entries = self.pool.take_entries();
+        while self.peek()? != b':' {
+            self.check_collection_len(entries.len() + 1, self.offset)?;
+            let key = self.parse()?;
+            let value = self.parse()?;
+            entries.push((key, value));
+        }
+        // Keys and values are counted separately
+        self.read_end(b':', entries.len() as u32 * 2)?;
+        self.leave();
+
+        Ok(self.end_object(id, Value::Map(entries)))
+    }
+
+    fn is_set(&self) -> bool {
+        self.peek() == Ok(b'\'')
+    }
+
+    fn parse_set(&mut self) -> Result<Value> {
+        self.enter()?;
+        self.expect_next(b'\'')?;
+        let id = self.begin_object();
+        let mut values = self.pool.take_array();
+        while self.peek()? != b',' {
+            self.check_collection_len(values.len() + 1, self.offset)?;
+            values.push(self.parse()?);
+        }
+        self.read_end(b',', values.len() as u32)?;
+        self.leave();
+
+        Ok(self.end_object(id, Value::Set(values)))
+    }
+
+    fn is_array_buffer(&self) -> bool {
+        self.peek() == Ok(b'B')
+    }
+
+    fn parse_array_buffer(&mut self) -> Result<Value> {
+        let id = self.begin_object();
+        let buffer = self.read_array_buffer()?;
+        let value = self.end_object(id, Value::ArrayBuffer(buffer.to_vec()));
+        if self.is_array_buffer_view() {
+            return self.parse_array_buffer_view(buffer);
+        }
+        Ok(value)
+    }
+
+    fn read_array_buffer(&mut self) -> Result<&'a [u8]> {
+        self.expect_next(b'B')?;
+        let len = self.read_buffer_len()?;
+        self.read_bytes(len)
+    }
+
+    fn is_array_buffer_transfer(&self) -> bool {
+        self.peek() == Ok(b't')
+    }
+
+    /// Transferred buffers are passed alongside the data, by index.
+    fn parse_array_buffer_transfer(&mut self) -> Result<Value> {
+        self.expect_next(b't')?;
+        let id = self.begin_object();
+        let transfer_id = self.varint::<u32>()?;
+        Ok(self.end_object(id, Value::ArrayBufferTransfer { transfer_id }))
+    }
+
+    fn is_shared_array_buffer(&self) -> bool {
+        self.peek() == Ok(b'u')
+    }
+
+    fn parse_shared_array_buffer(&mut self) -> Result<Value> {
+        self.expect_next(b'u')?;
+        let id = self.begin_object();
+        let transfer_id = self.varint::<u32>()?;
+        Ok(self.end_object(id, Value::SharedArrayBuffer { transfer_id }))
+    }
+
+    fn is_array_buffer_view(&self) -> bool {
+        self.peek() == Ok(b'V')
+    }
+
+    /// Views directly follow the buffer they're on.
+    fn parse_array_buffer_view(&mut self, buffer: &[u8]) -> Result<Value> {
+        let id = self.begin_object();
+        let (ty, byte_offset, byte_length) = self.read_array_buffer_view(buffer.len())?;
+        let view = Value::ArrayBufferView {
+            ty,
+            byte_offset,
+            byte_length,
+            buffer: buffer.to_vec(),
+        };
+        Ok(self.end_object(id, view))
+    }
+
+    /// Reads a view's type, byte offset and byte length, checking that it
+    /// fits in a buffer of `buffer_len` bytes and is aligned.
+    fn read_array_buffer_view(
+        &mut self,
+        buffer_len: usize,
+    ) -> Result<(ArrayBufferViewType, u32, u32)> {
+        let at = self.offset;
+        self.expect_next(b'V')?;
+        let ty = match self.peek()? {
+            b'b' => ArrayBufferViewType::Int8Array,
+            b'B' => ArrayBufferViewType::Uint8Array,
+            b'C' => ArrayBufferViewType::Uint8ClampedArray,
+            b'w' => ArrayBufferViewType::Int16Array,
+            b'W' => ArrayBufferViewType::Uint16Array,
+            b'd' => ArrayBufferViewType::Int32Array,
+            b'D' => ArrayBufferViewType::Uint32Array,
+            b'h' => ArrayBufferViewType::Float16Array,
+            b'f' => ArrayBufferViewType::Float32Array,
+            b'F' => ArrayBufferViewType::Float64Array,
+            b'q' => ArrayBufferViewType::BigInt64Array,
+            b'Q' => ArrayBufferViewType::BigUint64Array,
+            b'?' => ArrayBufferViewType::DataView,
+            byte => {
+                return Err(Error::UnknownViewType {
+                    byte,
+                    at: self.offset,
+                })
+            }
+        };
+        self.next()?;
+        let byte_offset = self.varint::<u32>()?;
+        let byte_length = self.varint::<u32>()?;
+        if !ty.is_valid_view(byte_offset, byte_length, buffer_len) {
+            return Err(Error::InvalidView { ty, at });
+        }
+        Ok((ty, byte_offset, byte_length))
+    }
+
+    fn is_wasm_module(&self) -> bool {
+        self.peek() == Ok(b'W')
+    }
+
+    /// The wire bytes are followed by the compiled module, which is specific
+    /// to the V8 build that wrote it and skipped.
+    fn parse_wasm_module(&mut self) -> Result<Value> {
+        self.expect_next(b'W')?;
+        let id = self.begin_object();
+        // Raw wire bytes is the only encoding there is
+        self.expect_next(b'y')?;
+        let len = self.read_buffer_len()?;
+        let wire_bytes = self.read_bytes(len)?.to_vec();
+        let len = self.read_buffer_len()?;
+        self.read_bytes(len)?;
+        Ok(self.end_object(id, Value::WasmModule(wire_bytes)))
+    }
+
+    fn is_wasm_memory(&self) -> bool {
+        self.peek() == Ok(b'm')
+    }
+
+    /// The buffer is a `SharedArrayBuffer` with an id of its own.
+    fn parse_wasm_memory(&mut self) -> Result<Value> {
+        self.expect_next(b'm')?;
+        let id = self.begin_object();
+        let maximum_pages = self.varint::<i32>()?;
+        self.expect_next(b'u')?;
+        let buffer_id = self.begin_object();
+        let transfer_id = self.varint::<u32>()?;
+        self.end_object(buffer_id, Value::SharedArrayBuffer { transfer_id });
+        let memory = Value::WasmMemory {
+            maximum_pages,
+            transfer_id,
+        };
+        Ok(self.end_object(id, memory))
+    }
+
+    fn is_host_object(&self) -> bool {
+        self.peek() == Ok(b'\\')
+    }
+
+    fn parse_host_object(&mut self) -> Result<Value> {
+        let at = self.offset;
+        self.expect_next(b'\\')?;
+        let id = self.begin_object();
+        let rest = &self.data[self.offset..];
+        let len = match self.host_object.as_mut().and_then(|hook| hook(rest)) {
+            Some(len) if len <= rest.len() => len,
+            _ => {
+                return Err(Error::Message(format!(
+                    "Unrecognized host object at {}",
+                    at
+                )))
+            }
+        };
+        let data = self.read_bytes(len)?.to_vec();
+        Ok(self.end_object(id, Value::HostObject(data)))
+    }
+
+    fn is_unsupported(&self) -> bool {
+        matches!(
+            self.peek(),
+            Ok(b'y') | Ok(b'x') | Ok(b'z') | Ok(b'w') | Ok(b'p')
+        )
+    }
+
+    fn parse_unsupported(&mut self) -> Result<Value> {
+        let id = self.begin_object();
+        let tag = self.read_byte()?;
+        let start = self.offset;
+        match tag {
+            // BigInt object, same payload as a BigInt
+            b'z' => {
+                let flags = self.varint::<u32>()?;
+                self.read_bytes((flags >> 1) as usize)?;
+            }
+            // WebAssembly module transfer and shared object, both an id
+            b'w' | b'p' => {
+                self.varint::<u32>()?;
+            }
+            // true and false objects
+            _ => {}
+        }
+
+        let data = self.data[start..self.offset].to_vec();
+        Ok(self.end_object(id, Value::Unsupported { tag, data }))
+    }
+
+    /// Reads the tag ending an object, map or set and the count after it.
+    fn read_end(&mut self, tag: u8, count: u32) -> Result<()> {
+        let at = self.offset;
+        self.expect_next(tag)?;
+        let actual = self.varint::<u32>()?;
+        if actual != count {
+            self.warn(Warning::LengthMismatch {
+                expected: count,
+                actual,
+                at,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn is_date(&self) -> bool {
+        self.peek() == Ok(b'D')
+    }
+
+    fn parse_date(&mut self) -> Result<Value> {
+        self.expect_next(b'D')?;
+        let id = self.begin_object();
+        let date = self.read_f64()?;
+        Ok(self.end_object(id, Value::Date(date)))
+    }
+
+    fn is_number_object(&self) -> bool {
+        self.peek() == Ok(b'n')
+    }
+
+    /// `new Number(value)`
+    fn parse_number_object(&mut self) -> Result<Value> {
+        self.expect_next(b'n')?;
+        let id = self.begin_object();
+        let value = self.read_f64()?;
+        Ok(self.end_object(id, Value::NumberObject(value)))
+    }
+
+    fn is_string_object(&self) -> bool {
+        self.peek() == Ok(b's')
+    }
+
+    /// `new String(value)`
+    fn parse_string_object(&mut self) -> Result<Value> {
+        self.expect_next(b's')?;
+        let id = self.begin_object();
+        let value = self.read_string_value()?;
+        Ok(self.end_object(id, Value::StringObject(value)))
+    }
+
+    fn is_regexp(&self) -> bool {
+        self.peek() == Ok(b'R')
+    }
+
+    fn parse_regexp(&mut self) -> Result<Value> {
+        self.expect_next(b'R')?;
+        let id = self.begin_object();
+        let expr = self.read_string_value()?;
+        let flags = self.varint::<u32>()?;
+        Ok(self.end_object(id, Value::RegExp { expr, flags }))
+    }
+
+    fn is_error(&self) -> bool {
+        self.peek() == Ok(b'r')
+    }
+
+    fn parse_error(&mut self) -> Result<Value> {
+        self.expect_next(b'r')?;
+        let id = self.begin_object();
+        let mut ty = ErrorType::Unknown;
+        let mut message = None;
+        let mut stack = None;
+        // Like V8, accept the sub-sections in any order until the end tag.
+        loop {
+            let at = self.offset;
+            match self.read_byte()? {
+                b'E' => ty = ErrorType::EvalError,
+                b'R' => ty = ErrorType::RangeError,
+                b'F' => ty = ErrorType::ReferenceError,
+                b'C' => ty = ErrorType::SyntaxError,
+                b'T' => ty = ErrorType::TypeError,
+                b'U' => ty = ErrorType::UriError,
+                b'm' => message = Some(self.read_string_value()?),
+                b's' => stack = Some(self.read_string_value()?),
+                b'.' => break,
+                byte => return Err(Error::Unexpected { byte, at }),
+            }
+        }
+        Ok(self.end_object(id, Value::Error { ty, message, stack }))
+    }
+
+    fn is_dense_array(&self) -> bool {
+        self.peek() == Ok(b'A')
+    }
+
+    fn parse_dense_array(&mut self) -> Result<Value> {
+        self.enter()?;
+        self.expect_next(b'A')?;
+        let id = self.begin_object();
+        let len = self.read_collection_len()?;
+
+        // Every element takes at least one byte, don't trust len any further.
+        let mut elements = self.pool.take_array();
+        elements.reserve(len.min(self.data.len() - self.offset));
+        for _ in 0..len {
+            elements.push(self.parse()?);
+        }
+        self.read_array_end(len)?;
+        self.leave();
+
+        Ok(self.end_object(id, Value::Array(elements)))
+    }
+
+    /// Reads the `'$'` trailer of a dense array with `len` elements.
+    fn read_array_end(&mut self, len: usize) -> Result<()> {
+        let at = self.offset;
+        self.expect_next(b'$')?;
+        let properties = self.varint::<u32>()?;
+        if properties != 0 {
+            self.warn(Warning::LengthMismatch {
+                expected: 0,
+                actual: properties,
+                at,
+            })?;
+        }
+        let length = self.varint::<u32>()?;
+        if length as usize != len {
+            self.warn(Warning::LengthMismatch {
+                expected: len as u32,
+                actual: length,
+                at,
+            })?;
+        }
+        Ok(())
     }
 
+    /// Decodes the value at the current offset, dispatching on its tag.
     fn parse(&mut self) -> Result<Value> {
-        if self.is_undefined() {
-            self.parse_undefined()
-        } else if self.is_null() {
-            self.parse_null()
-        } else if self.is_bool() {
-            self.parse_bool()
-        } else {
-            Err(Error::Unexpected {
-                byte: self.byte(),
+        self.skip_padding()?;
+        self.count_nodes(1, self.offset)?;
+        match self.peek()? {
+            b'_' => self.parse_undefined(),
+            b'0' => self.parse_null(),
+            b'T' | b'F' => self.parse_bool(),
+            b'I' => Ok(Value::Int32(self.read_int32()?)),
+            b'U' => Ok(Value::Uint32(self.read_uint32()?)),
+            b'N' => Ok(Value::Double(self.read_double()?)),
+            b'Z' => self.parse_bigint(),
+            b'"' => self.parse_one_byte_string(),
+            b'c' => self.parse_two_byte_string(),
+            b'S' => self.parse_utf8_string(),
+            b'^' => self.parse_object_reference(),
+            b'o' => self.parse_object(),
+            b'A' => self.parse_dense_array(),
+            b'D' => self.parse_date(),
+            b'n' => self.parse_number_object(),
+            b's' => self.parse_string_object(),
+            b'R' => self.parse_regexp(),
+            b'r' => self.parse_error(),
+            b';' => self.parse_map(),
+            b'\'' => self.parse_set(),
+            b'B' => self.parse_array_buffer(),
+            b't' => self.parse_array_buffer_transfer(),
+            b'u' => self.parse_shared_array_buffer(),
+            b'\\' if self.host_object.is_some() => self.parse_host_object(),
+            b'W' => self.parse_wasm_module(),
+            b'm' => self.parse_wasm_memory(),
+            b'y' | b'x' | b'z' | b'w' | b'p' if self.options.allow_unsupported => {
+                self.parse_unsupported()
+            }
+            byte => Err(Error::Unexpected {
+                byte,
                 at: self.offset,
-            })
+            }),
         }
     }
 
     pub fn deserialize(mut self, data: &'a [u8]) -> Result<Value> {
+        let value = self.deserialize_root(data)?;
+        self.end()?;
+        Ok(value)
+    }
+
+    /// Decodes the next of several values written one after another, each
+    /// with its own header, from the data given to `with_input`. Returns
+    /// `None` once all of it is consumed.
+    ///
+    /// Object ids start over with each value, like they do in V8.
+    pub fn next_value(&mut self) -> Result<Option<Value>> {
+        if self.offset >= self.data.len() {
+            return Ok(None);
+        }
+        self.read_header()?;
+        self.parse().map(Some)
+    }
+
+    /// How far into the input decoding has got, e.g. where the value after
+    /// the last one `next_value` returned starts.
+    pub fn position(&self) -> usize {
+        self.offset
+    }
+
+    /// The input from `position` on.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.offset..]
+    }
+
+    /// Fails if there's anything after the value just read, unless
+    /// `DeserializerOptions::allow_trailing` is set.
+    fn end(&self) -> Result<()> {
+        let remaining = self.data.len() - self.offset;
+        if remaining > 0 && !self.options.allow_trailing {
+            return Err(Error::TrailingBytes { remaining });
+        }
+        Ok(())
+    }
+
+    fn deserialize_root(&mut self, data: &'a [u8]) -> Result<Value> {
+        self.start(data)?;
+
+        // Parse Value
+        self.parse()
+    }
+
+    /// Copies the value at the current offset to `out`, see `transcode`.
+    fn transcode_value<F>(
+        &mut self,
+        pointer: &mut JsonPointer,
+        f: &mut F,
+        out: &mut Vec<u8>,
+    ) -> Result<()>
+    where
+        F: FnMut(&JsonPointer, Value) -> Option<Value>,
+    {
+        let start = self.offset;
+        if self.is_object() {
+            self.enter()?;
+            self.next()?;
+            self.begin_object();
+            out.push(b'o');
+            let mut properties = 0;
+            while self.peek()? != b'{' {
+                let key_start = self.offset;
+                let key = self.parse_property_key()?;
+                out.extend(&self.data[key_start..self.offset]);
+
+                pointer.push(key);
+                self.transcode_value(pointer, f, out)?;
+                pointer.pop();
+                properties += 1;
+            }
+            let end = self.offset;
+            self.read_end(b'{', properties)?;
+            out.extend(&self.data[end..self.offset]);
+            self.leave();
+        } else if self.is_dense_array() {
+            self.enter()?;
+            self.next()?;
+            self.begin_object();
+            let len = self.read_collection_len()?;
+            out.extend(&self.data[start..self.offset]);
+
+            for index in 0..len {
+                pointer.push(index.to_string());
+                self.transcode_value(pointer, f, out)?;
+                pointer.pop();
+            }
+            let end = self.offset;
+            self.read_array_end(len)?;
+            out.extend(&self.data[end..self.offset]);
+            self.leave();
+        } else {
+            let value = self.parse()?;
+            match f(pointer, value) {
+                // Skip the version header
+                Some(value) => out.extend(&crate::ser::to_vec(&value)?[2..]),
+                None => out.extend(&self.data[start..self.offset]),
+            }
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, data: &'a [u8]) -> Result<()> {
         self.data = data;
         self.offset = 0;
+        self.read_header()
+    }
+
+    /// Resets the per-value state and skips the version header, if any.
+    fn read_header(&mut self) -> Result<()> {
+        self.depth = 0;
+        self.warnings.clear();
+        self.objects.clear();
+        self.object_nodes.clear();
+        self.nodes = 0;
 
         // Version
         if self.is_version() {
-            self.offset += 2;
+            self.next()?;
+            self.read_byte()?;
         }
-
-        // Parse Value
-        self.parse()
+        Ok(())
     }
 }
+
+/// One-byte strings are Latin-1, which maps directly onto code points. Only
+/// ASCII can be borrowed as is.
+fn latin1(bytes: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(bytes) {
+        Ok(string) if bytes.is_ascii() => Cow::Borrowed(string),
+        _ => Cow::Owned(bytes.iter().map(|&byte| byte as char).collect()),
+    }
+}
+
+fn bigint_to_i64((negative, magnitude): (bool, u64)) -> Option<i64> {
+    if !negative {
+        i64::try_from(magnitude).ok()
+    } else if magnitude <= i64::MIN.unsigned_abs() {
+        Some((magnitude as i64).wrapping_neg())
+    } else {
+        None
+    }
+}
+
+/// Decodes `data`, collecting recoverable issues instead of failing on them.
+///
+/// This is meant for importing imperfect data (e.g. archived payloads) where
+/// getting a value out matters more than strict conformance. Truncated input
+/// and unknown tags are still errors.
+pub fn from_slice_lenient(data: &[u8]) -> Result<(Value, Vec<Warning>)> {
+    let mut deserializer = Deserializer::with_options(DeserializerOptions {
+        collect_warnings: true,
+        ..Default::default()
+    });
+    let value = deserializer.deserialize_root(data)?;
+    Ok((value, deserializer.warnings))
+}
+
+/// Decodes `data` as a single value, failing if anything is left after it.
+///
+/// Malformed input of any kind is an error, never a panic, which makes this
+/// the entry point to fuzz.
+pub fn from_slice_checked(data: &[u8]) -> Result<Value> {
+    Deserializer::new().deserialize(data)
+}
+
+/// Decodes `data` with `from_slice_checked` and encodes the result again.
+pub fn roundtrip(data: &[u8]) -> Result<Vec<u8>> {
+    crate::ser::value_to_vec(&from_slice_checked(data)?)
+}
+
+/// Copies `data`, letting `f` replace values along the way.
+///
+/// Objects and dense arrays are walked into with their own bytes copied as
+/// is, `f` is called with every other value (maps and sets included, whole)
+/// and its location. Returning `Some` writes the new value in its place,
+/// `None` keeps the original bytes. Values are decoded one at a time, the
+/// tree as a whole never is.
+///
+/// Only those leaves are passed to `f`, never objects or dense arrays
+/// themselves (the root included), since that would mean decoding them
+/// whole. To replace one, replace what's in it.
+///
+/// Objects `Value` doesn't model are passed to `f` as `Value::Unsupported`.
+///
+/// V8 numbers objects in write order for `'^'` references, so replacing a
+/// value with one that contains a different number of objects (anything but
+/// primitives and strings counts) breaks references after it.
+pub fn transcode<F>(data: &[u8], mut f: F) -> Result<Vec<u8>>
+where
+    F: FnMut(&JsonPointer, Value) -> Option<Value>,
+{
+    let mut deserializer = Deserializer::with_options(DeserializerOptions {
+        allow_unsupported: true,
+        ..Default::default()
+    });
+    deserializer.start(data)?;
+    let mut out = data[..deserializer.offset].to_vec();
+    deserializer.transcode_value(&mut JsonPointer::new(), &mut f, &mut out)?;
+    Ok(out)
+}
+
+/// Decodes a `Value` from everything left in `reader`.
+///
+/// The format isn't length prefixed at the top level, so there's no telling
+/// where a value ends without decoding it and the whole payload is buffered
+/// first.
+pub fn from_reader<R: std::io::Read>(reader: &mut R) -> Result<Value> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .map_err(|err| Error::Message(err.to_string()))?;
+    Deserializer::new().deserialize(&data)
+}
+
+/// Deserializes a `T` from `data`, as written by `to_vec`.
+///
+/// Fails with `Error::TrailingBytes` if there's more after the value.
+pub fn from_slice<'a, T: de::Deserialize<'a>>(data: &'a [u8]) -> Result<T> {
+    let mut deserializer = Deserializer::new();
+    deserializer.start(data)?;
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+/// Like `from_slice_checked`, but decodes into containers from `pool` and
+/// returns them to it once the value is dropped.
+///
+/// Meant for decoding many small values one after another, where allocating
+/// and freeing every array, map and object otherwise adds up. A single large
+/// value decodes faster with `from_slice_checked`, the reused containers are
+/// scattered across memory where fresh ones would be next to each other. The
+/// pool is borrowed until the value is dropped, so each pool serves one value
+/// at a time.
+pub fn from_slice_pooled<'p>(data: &[u8], pool: &'p mut ValuePool) -> Result<Pooled<'p>> {
+    let mut deserializer = Deserializer::new();
+    deserializer.pool = std::mem::take(pool);
+    let result = deserializer
+        .deserialize_root(data)
+        .and_then(|value| deserializer.end().map(|_| value));
+    // The copies kept for references go back too
+    for value in deserializer.objects.drain(..).flatten() {
+        deserializer.pool.recycle(value);
+    }
+    *pool = deserializer.pool;
+    Ok(Pooled::new(result?, pool))
+}
+
+/// Decodes every value in `data`, as written by `to_vec_many`.
+pub fn from_slice_many(data: &[u8]) -> Result<Vec<Value>> {
+    let mut deserializer = Deserializer::new().with_input(data);
+    let mut values = Vec::new();
+    while let Some(value) = deserializer.next_value()? {
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// Like `from_slice`, but driven by `seed`, for decoding that needs state
+/// (e.g. an arena to allocate into).
+pub fn from_slice_seed<'a, S: de::DeserializeSeed<'a>>(
+    data: &'a [u8],
+    seed: S,
+) -> Result<S::Value> {
+    let mut deserializer = Deserializer::new();
+    deserializer.start(data)?;
+    let value = seed.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.skip_padding()?;
+        let at = self.offset;
+        match self.peek()? as char {
+            '_' => {
+                self.next()?;
+                visitor.visit_unit()
+            }
+            '0' => {
+                self.next()?;
+                visitor.visit_none()
+            }
+            'T' => {
+                self.next()?;
+                visitor.visit_bool(true)
+            }
+            'F' => {
+                self.next()?;
+                visitor.visit_bool(false)
+            }
+            'I' => visitor.visit_i32(self.read_int32()?),
+            'U' => visitor.visit_u32(self.read_uint32()?),
+            'N' => visitor.visit_f64(self.read_double()?),
+            'Z' => match self.read_bigint()? {
+                (false, magnitude) if magnitude > i64::MAX as u64 => visitor.visit_u64(magnitude),
+                bigint => match bigint_to_i64(bigint) {
+                    Some(value) => visitor.visit_i64(value),
+                    None => Err(Error::Message(format!(
+                        "BigInt does not fit in 64 bits at {}",
+                        at
+                    ))),
+                },
+            },
+            '"' => {
+                self.next()?;
+                match latin1(self.read_string(1)?) {
+                    Cow::Borrowed(string) => visitor.visit_borrowed_str(string),
+                    Cow::Owned(string) => visitor.visit_string(string),
+                }
+            }
+            'c' => visitor.visit_string(self.read_two_byte_string()?),
+            'S' => visitor.visit_borrowed_str(self.read_utf8_string()?),
+            'o' => {
+                self.enter()?;
+                self.next()?;
+                let mut access = Entries::new(self, b'{');
+                let value = visitor.visit_map(&mut access)?;
+                let count = access.count;
+                self.read_end(b'{', count)?;
+                self.leave();
+                Ok(value)
+            }
+            ';' => {
+                self.enter()?;
+                self.next()?;
+                let mut access = Entries::new(self, b':');
+                let value = visitor.visit_map(&mut access)?;
+                let count = access.count * 2;
+                self.read_end(b':', count)?;
+                self.leave();
+                Ok(value)
+            }
+            'A' => {
+                self.enter()?;
+                self.next()?;
+                let len = self.read_collection_len()?;
+                let mut access = Elements {
+                    de: &mut *self,
+                    len: Some(len),
+                    count: 0,
+                };
+                let value = visitor.visit_seq(&mut access)?;
+                if access.count != len {
+                    return Err(de::Error::invalid_length(len, &"fewer elements in array"));
+                }
+                self.read_array_end(len)?;
+                self.leave();
+                Ok(value)
+            }
+            '\'' => {
+                self.enter()?;
+                self.next()?;
+                let mut access = Elements {
+                    de: &mut *self,
+                    len: None,
+                    count: 0,
+                };
+                let value = visitor.visit_seq(&mut access)?;
+                let count = access.count as u32;
+                self.read_end(b',', count)?;
+                self.leave();
+                Ok(value)
+            }
+            'B' => {
+                // A view is seen as just the bytes it covers
+                let mut bytes = self.read_array_buffer()?;
+                if self.is_array_buffer_view() {
+                    let (_, byte_offset, byte_length) = self.read_array_buffer_view(bytes.len())?;
+                    let start = byte_offset as usize;
+                    bytes = &bytes[start..start + byte_length as usize];
+                }
+                visitor.visit_borrowed_bytes(bytes)
+            }
+            _ => self.invalid_type(&visitor),
+        }
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.is_undefined() || self.is_null() {
+            self.next()?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    // Unit structs are written as empty objects
+    fn deserialize_unit_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        if self.is_object() {
+            self.next()?;
+            self.read_end(b'{', 0)?;
+            visitor.visit_unit()
+        } else {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Variants are written as an object with a single property, the variant's
+    // name. Plain strings are accepted for unit variants too.
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        if self.is_one_byte_string() {
+            self.next()?;
+            let variant = latin1(self.read_string(1)?);
+            return visitor.visit_enum(variant.as_ref().into_deserializer());
+        }
+        if !self.is_object() {
+            return self.invalid_type(&visitor);
+        }
+
+        self.enter()?;
+        self.expect_next(b'o')?;
+        let value = visitor.visit_enum(&mut *self)?;
+        self.read_end(b'{', 1)?;
+        self.leave();
+        Ok(value)
+    }
+
+    // Like the `Serializer`, so that compact forms round trip
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+/// Properties of an object or entries of a map, up to the `end` tag.
+struct Entries<'b, 'de> {
+    de: &'b mut Deserializer<'de>,
+    end: u8,
+    count: u32,
+}
+
+impl<'b, 'de> Entries<'b, 'de> {
+    fn new(de: &'b mut Deserializer<'de>, end: u8) -> Self {
+        Self { de, end, count: 0 }
+    }
+}
+
+impl<'b, 'de> de::MapAccess<'de> for Entries<'b, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.de.peek()? == self.end {
+            return Ok(None);
+        }
+        self.count += 1;
+        if self.end == b'{' {
+            seed.deserialize(PropertyKey(&mut *self.de)).map(Some)
+        } else {
+            seed.deserialize(&mut *self.de).map(Some)
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+/// Object keys are strings or, for integer indices, numbers. Numbers are
+/// handed to the visitor as strings, the way JS sees them.
+struct PropertyKey<'b, 'de>(&'b mut Deserializer<'de>);
+
+impl<'b, 'de> de::Deserializer<'de> for PropertyKey<'b, 'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let de = self.0;
+        if de.is_int32() {
+            visitor.visit_string(de.read_int32()?.to_string())
+        } else if de.is_uint32() {
+            visitor.visit_string(de.read_uint32()?.to_string())
+        } else if de.is_double() {
+            visitor.visit_string(de.read_double()?.to_string())
+        } else {
+            de::Deserializer::deserialize_any(de, visitor)
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// Elements of a dense array (`len` of them) or a set (up to `','`).
+struct Elements<'b, 'de> {
+    de: &'b mut Deserializer<'de>,
+    len: Option<usize>,
+    count: usize,
+}
+
+impl<'b, 'de> de::SeqAccess<'de> for Elements<'b, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>> {
+        let done = match self.len {
+            Some(len) => self.count == len,
+            None => self.de.peek()? == b',',
+        };
+        if done {
+            return Ok(None);
+        }
+        self.count += 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.len.map(|len| len - self.count)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let variant = seed.deserialize(&mut *self)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+/// Tags are mapped to the variant `Serializer` would write them from, e.g.
+/// `'I'` to `Int32` and `'Z'` to `BigInt`.
+///
+/// Serde can't tell maps from objects or sets from arrays, so maps whose keys
+/// are all strings come back as `Value::Object` and sets as `Value::Array`.
+/// `Deserializer::deserialize` keeps them apart.
+impl<'de> de::Deserialize<'de> for Value {
+    fn deserialize<D: de::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> de::Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("any value")
+    }
+
+    fn visit_unit<E>(self) -> std::result::Result<Value, E> {
+        Ok(Value::Undefined)
+    }
+
+    fn visit_none<E>(self) -> std::result::Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Value, D::Error> {
+        de::Deserialize::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, value: bool) -> std::result::Result<Value, E> {
+        Ok(Value::Boolean(value))
+    }
+
+    fn visit_i8<E>(self, value: i8) -> std::result::Result<Value, E> {
+        Ok(Value::Int32(value.into()))
+    }
+
+    fn visit_i16<E>(self, value: i16) -> std::result::Result<Value, E> {
+        Ok(Value::Int32(value.into()))
+    }
+
+    fn visit_i32<E>(self, value: i32) -> std::result::Result<Value, E> {
+        Ok(Value::Int32(value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> std::result::Result<Value, E> {
+        Ok(Value::BigInt(value))
+    }
+
+    fn visit_u8<E>(self, value: u8) -> std::result::Result<Value, E> {
+        Ok(Value::Uint32(value.into()))
+    }
+
+    fn visit_u16<E>(self, value: u16) -> std::result::Result<Value, E> {
+        Ok(Value::Uint32(value.into()))
+    }
+
+    fn visit_u32<E>(self, value: u32) -> std::result::Result<Value, E> {
+        Ok(Value::Uint32(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> std::result::Result<Value, E> {
+        match i64::try_from(value) {
+            Ok(value) => Ok(Value::BigInt(value)),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Unsigned(value), &self)),
+        }
+    }
+
+    fn visit_f32<E>(self, value: f32) -> std::result::Result<Value, E> {
+        Ok(Value::Double(value.into()))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> std::result::Result<Value, E> {
+        Ok(Value::Double(value))
+    }
+
+    fn visit_str<E>(self, value: &str) -> std::result::Result<Value, E> {
+        Ok(Value::from(value))
+    }
+
+    fn visit_string<E>(self, value: String) -> std::result::Result<Value, E> {
+        Ok(Value::from(value))
+    }
+
+    fn visit_bytes<E>(self, value: &[u8]) -> std::result::Result<Value, E> {
+        Ok(Value::ArrayBuffer(value.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, value: Vec<u8>) -> std::result::Result<Value, E> {
+        Ok(Value::ArrayBuffer(value))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> std::result::Result<Value, A::Error> {
+        let mut entries: Vec<(Value, Value)> = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+
+        if entries.iter().all(|(key, _)| key.is_string()) {
+            Ok(entries
+                .into_iter()
+                .filter_map(|(key, value)| match key {
+                    Value::String(key, _) => Some((key, value)),
+                    _ => None,
+                })
+                .collect())
+        } else {
+            Ok(Value::Map(entries))
+        }
+    }
+}
+
+#[cfg(test)]
+fn nested_arrays(depth: usize) -> Vec<u8> {
+    let mut data = vec![0xFF, crate::ser::FORMAT_VERSION];
+    for _ in 0..depth - 1 {
+        data.extend([b'A', 1]);
+    }
+    data.extend([b'A', 0, b'$', 0, 0]);
+    for _ in 0..depth - 1 {
+        data.extend([b'$', 0, 1]);
+    }
+    data
+}
+
+#[test]
+fn test_dense_array() {
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'A',
+        2,
+        b'T',
+        b'_',
+        b'$',
+        0,
+        2,
+    ];
+    match Deserializer::new().deserialize(&data) {
+        Ok(Value::Array(elements)) => {
+            assert_eq!(elements.len(), 2);
+            assert!(elements[0].is_boolean());
+            assert!(elements[1].is_undefined());
+        }
+        _ => panic!("expected an array"),
+    }
+}
+
+#[test]
+fn test_truncated() {
+    let mut valid = vec![0xFF, crate::ser::FORMAT_VERSION, b'A', 5];
+    valid.extend([b'_', b'0', b'T', b'F', b'A', 0, b'$', 0, 0]);
+    valid.extend([b'$', 0, 5]);
+    assert!(Deserializer::new().deserialize(&valid).is_ok());
+
+    // Every strict prefix of a valid buffer is missing something
+    for len in 0..valid.len() {
+        assert!(
+            matches!(
+                Deserializer::new().deserialize(&valid[..len]),
+                Err(Error::UnexpectedEof { at }) if at == len
+            ),
+            "prefix of length {}",
+            len
+        );
+    }
+
+    // Unterminated varint
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'A', 0x80];
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::UnexpectedEof { at: 4 })
+    ));
+}
+
+#[test]
+fn test_max_depth() {
+    let data = nested_arrays(DEFAULT_MAX_DEPTH);
+    assert!(Deserializer::new().deserialize(&data).is_ok());
+
+    let data = nested_arrays(200);
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::DepthLimitExceeded { .. })
+    ));
+
+    let data = nested_arrays(3);
+    assert!(Deserializer::new()
+        .with_max_depth(3)
+        .deserialize(&data)
+        .is_ok());
+    assert!(matches!(
+        Deserializer::new().with_max_depth(2).deserialize(&data),
+        Err(Error::DepthLimitExceeded { at: 6 })
+    ));
+}
+
+#[test]
+fn test_warnings() {
+    // Trailer claims a length of 3 for a 1 element array
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'A', 1, b'T', b'$', 0, 3];
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::Malformed(Warning::LengthMismatch {
+            expected: 1,
+            actual: 3,
+            at: 5
+        }))
+    ));
+
+    let (value, warnings) = from_slice_lenient(&data).unwrap();
+    assert!(value.is_array());
+    assert_eq!(
+        warnings,
+        vec![Warning::LengthMismatch {
+            expected: 1,
+            actual: 3,
+            at: 5
+        }]
+    );
+
+    // Non-canonical length varint (0 encoded in two bytes)
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'A',
+        0x80,
+        0x00,
+        b'$',
+        0,
+        0,
+    ];
+    assert!(Deserializer::new().deserialize(&data).is_err());
+    let (_, warnings) = from_slice_lenient(&data).unwrap();
+    assert_eq!(warnings, vec![Warning::NonCanonicalVarint { at: 3 }]);
+
+    // Fatal issues still fail
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'A', 1];
+    assert!(matches!(
+        from_slice_lenient(&data),
+        Err(Error::UnexpectedEof { at: 4 })
+    ));
+}
+
+#[test]
+fn test_string() {
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'"', 3, b'c', 0xE9, b'!'];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::String(String::from("c\u{e9}!"), false))
+    );
+
+    // Truncated payload
+    assert!(matches!(
+        Deserializer::new().deserialize(&data[..5]),
+        Err(Error::UnexpectedEof { at: 5 })
+    ));
+}
+
+#[test]
+fn test_two_byte_string() {
+    let mut data = vec![0xFF, crate::ser::FORMAT_VERSION, 0, b'c', 6];
+    for unit in "h\u{e9}\u{2603}".encode_utf16() {
+        data.extend(unit.to_ne_bytes());
+    }
+    // Padding before the tag is skipped
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::String(String::from("h\u{e9}\u{2603}"), true))
+    );
+    assert_eq!(
+        from_slice::<String>(&data),
+        Ok(String::from("h\u{e9}\u{2603}"))
+    );
+
+    // A lone high surrogate
+    let mut data = vec![0xFF, crate::ser::FORMAT_VERSION, b'c', 4];
+    data.extend(0xD83Du16.to_ne_bytes());
+    data.extend(u16::from(b'!').to_ne_bytes());
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::Message(_))
+    ));
+    let lossy = DeserializerOptions {
+        lossy_strings: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        Deserializer::with_options(lossy).deserialize(&data),
+        Ok(Value::String(String::from("\u{fffd}!"), true))
+    );
+
+    // Odd number of bytes
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'c', 1, b'a'];
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::Message(_))
+    ));
+}
+
+#[test]
+fn test_max_string_len() {
+    let options = DeserializerOptions {
+        max_string_len: Some(2),
+        ..Default::default()
+    };
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'"', 2, b'o', b'k'];
+    assert!(Deserializer::with_options(options.clone())
+        .deserialize(&data)
+        .is_ok());
+
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'A',
+        1,
+        b'"',
+        3,
+        b'b',
+        b'a',
+        b'd',
+        b'$',
+        0,
+        1,
+    ];
+    assert_eq!(
+        Deserializer::with_options(options.clone()).deserialize(&data),
+        Err(Error::StringTooLong { len: 3, offset: 5 })
+    );
+
+    // Rejected from the length alone, before looking for the payload
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'"',
+        0xFF,
+        0xFF,
+        0xFF,
+        0xFF,
+        0x0F,
+    ];
+    assert_eq!(
+        Deserializer::with_options(options).deserialize(&data),
+        Err(Error::StringTooLong {
+            len: u32::MAX as usize,
+            offset: 3
+        })
+    );
+}
+
+#[test]
+fn test_length_limits() {
+    // A buffer and an array claiming u32::MAX entries, with no payload
+    let buffer = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'B',
+        0xFF,
+        0xFF,
+        0xFF,
+        0xFF,
+        0x0F,
+    ];
+    let array = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'A',
+        0xFF,
+        0xFF,
+        0xFF,
+        0xFF,
+        0x0F,
+    ];
+    let limited = || {
+        Deserializer::new()
+            .with_max_buffer_len(1024)
+            .with_max_collection_len(2)
+    };
+    let too_long = Err(Error::LimitExceeded {
+        len: u32::MAX as usize,
+        at: 3,
+    });
+    assert_eq!(limited().deserialize(&buffer), too_long);
+    assert_eq!(limited().deserialize(&array), too_long);
+    // Without limits, still an error before anything is allocated
+    assert_eq!(
+        Deserializer::new().deserialize(&buffer),
+        Err(Error::UnexpectedEof { at: 8 })
+    );
+
+    let set = crate::ser::value_to_vec(&Value::Set(vec![
+        Value::Int32(1),
+        Value::Int32(2),
+        Value::Int32(3),
+    ]))
+    .unwrap();
+    assert_eq!(
+        limited().deserialize(&set),
+        Err(Error::LimitExceeded { len: 3, at: 7 })
+    );
+    let small = crate::ser::value_to_vec(&Value::ArrayBuffer(vec![1, 2])).unwrap();
+    assert_eq!(
+        limited().deserialize(&small),
+        Ok(Value::ArrayBuffer(vec![1, 2]))
+    );
+}
+
+#[test]
+fn test_utf8_string() {
+    let data = [
+        0xFF, 0x0F, b'A', 2, b'S', 2, 0xC3, 0xA9, b'"', 1, 0xE9, b'$', 0, 2,
+    ];
+    // The same character, as UTF-8 and as Latin-1
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Array(vec![Value::from("é"), Value::from("é")]))
+    );
+
+    let data = [0xFF, 0x0F, b'S', 1, 0xC3];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::Message(String::from("Invalid UTF-8 string at 3")))
+    );
+}
+
+#[test]
+fn test_pathological_lengths() {
+    let max = [0xFF, 0xFF, 0xFF, 0xFF, 0x0F];
+    for tag in [b'"', b'c', b'S', b'B'] {
+        let mut data = vec![0xFF, 0x0F, tag];
+        data.extend(max);
+        data.extend(b"abc");
+        assert_eq!(
+            Deserializer::new().deserialize(&data),
+            Err(Error::UnexpectedEof { at: 11 })
+        );
+    }
+
+    // One byte past the end
+    let data = [0xFF, 0x0F, b'B', 2, 0];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::UnexpectedEof { at: 5 })
+    );
+
+    let data = [0xFF, 0x0F, b'_', b'_'];
+    let mut deserializer = Deserializer::new().with_input(&data);
+    deserializer.advance(2).unwrap();
+    assert_eq!(
+        deserializer.advance(usize::MAX),
+        Err(Error::UnexpectedEof { at: 4 })
+    );
+    assert_eq!(deserializer.offset, 2);
+    assert!(deserializer.advance(2).is_ok());
+    assert!(deserializer.next().is_err());
+}
+
+#[test]
+fn test_parse_dispatch() {
+    let values = vec![
+        Value::Undefined,
+        Value::Null,
+        Value::Boolean(true),
+        Value::Boolean(false),
+        Value::Int32(-1),
+        Value::Uint32(u32::MAX),
+        Value::Double(0.5),
+        Value::BigInt(-7),
+        Value::from("one byte"),
+        Value::String(String::from("two bytes €"), true),
+        vec![(String::from("a"), Value::Null)].into_iter().collect(),
+        Value::Array(vec![Value::Null]),
+        Value::Date(1.0),
+        Value::NumberObject(2.0),
+        Value::StringObject(String::from("boxed")),
+        Value::RegExp {
+            expr: String::from("x"),
+            flags: 1,
+        },
+        Value::Error {
+            ty: ErrorType::TypeError,
+            message: Some(String::from("bad")),
+            stack: None,
+        },
+        Value::Map(vec![(Value::Int32(1), Value::Int32(2))]),
+        Value::Set(vec![Value::Int32(3)]),
+        Value::ArrayBuffer(vec![1, 2]),
+        Value::ArrayBufferTransfer { transfer_id: 4 },
+        Value::SharedArrayBuffer { transfer_id: 5 },
+        Value::WasmModule(vec![0, b'a', b's', b'm']),
+        Value::WasmMemory {
+            maximum_pages: -1,
+            transfer_id: 6,
+        },
+    ];
+    for value in values {
+        let data = crate::ser::value_to_vec(&value).unwrap();
+        assert_eq!(Deserializer::new().deserialize(&data), Ok(value));
+    }
+
+    // Tags that are only read
+    let data = [0xFF, 0x0F, b'S', 1, b'a'];
+    assert_eq!(Deserializer::new().deserialize(&data), Ok(Value::from("a")));
+    let data = [0xFF, 0x0F, b'A', 2, b'o', b'{', 0, b'^', 1, b'$', 0, 2];
+    let object = Value::Object(crate::ObjectMap::new());
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Array(vec![object.clone(), object]))
+    );
+    let data = [0xFF, 0x0F, b'\\', 1];
+    assert_eq!(
+        Deserializer::new()
+            .on_host_object(|_| Some(1))
+            .deserialize(&data),
+        Ok(Value::HostObject(vec![1]))
+    );
+    let options = DeserializerOptions {
+        allow_unsupported: true,
+        ..Default::default()
+    };
+    let data = [0xFF, 0x0F, b'y'];
+    assert!(Deserializer::with_options(options)
+        .deserialize(&data)
+        .unwrap()
+        .is_unsupported());
+
+    // Anything else, or those without the options they need, is unexpected
+    for tag in [b'\\', b'y', b'?', b'{'] {
+        assert_eq!(
+            Deserializer::new().deserialize(&[0xFF, 0x0F, tag]),
+            Err(Error::Unexpected { byte: tag, at: 2 })
+        );
+    }
+}
+
+#[test]
+fn test_parse() {
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'I', 0x03];
+    assert_eq!(Deserializer::new().deserialize(&data), Ok(Value::Int32(-2)));
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'U', 0xAC, 0x02];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Uint32(300))
+    );
+
+    let mut data = vec![0xFF, crate::ser::FORMAT_VERSION, b'N'];
+    data.extend(1.5f64.to_ne_bytes());
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Double(1.5))
+    );
+
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'Z',
+        17,
+        5,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::BigInt(-5))
+    );
+
+    // { 1: 'a' } with an integer key
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'o',
+        b'I',
+        2,
+        b'"',
+        1,
+        b'a',
+        b'{',
+        1,
+    ];
+    let value = Deserializer::new().deserialize(&data).unwrap();
+    assert_eq!(value.as_object().unwrap()["1"], Value::from("a"));
+
+    // new Map([[1, true]]) and new Set([null])
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b';',
+        b'I',
+        2,
+        b'T',
+        b':',
+        2,
+    ];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Map(vec![(Value::Int32(1), Value::Boolean(true))]))
+    );
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'\'', b'0', b',', 1];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Set(vec![Value::Null]))
+    );
+}
+
+#[test]
+fn test_from_slice() {
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(u32),
+        Line(i32, i32),
+        Rect { w: u32, h: u32 },
+    }
+
+    let point = Point { x: 1, y: -2 };
+    let data = crate::ser::to_vec(&point).unwrap();
+    assert_eq!(from_slice::<Point>(&data), Ok(point));
+
+    for shape in [
+        Shape::Empty,
+        Shape::Circle(3),
+        Shape::Line(1, 2),
+        Shape::Rect { w: 4, h: 5 },
+    ] {
+        let data = crate::ser::to_vec(&shape).unwrap();
+        assert_eq!(from_slice::<Shape>(&data), Ok(shape));
+    }
+
+    let data = crate::ser::to_vec((Some(true), None::<bool>, String::from("hi"))).unwrap();
+    assert_eq!(
+        from_slice(&data),
+        Ok((Some(true), None::<bool>, String::from("hi")))
+    );
+
+    let mut map = BTreeMap::new();
+    map.insert(String::from("a"), 1u8);
+    map.insert(String::from("b"), 2u8);
+    let data = crate::ser::to_vec(&map).unwrap();
+    assert_eq!(from_slice(&data), Ok(map));
+}
+
+#[test]
+fn test_tagged_enums() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    enum Internal {
+        Empty,
+        At(Point),
+        Rect { w: u32, h: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type", content = "content")]
+    enum Adjacent {
+        Empty,
+        Circle(u32),
+        Line(i32, i32),
+        Rect { w: u32, h: u32 },
+    }
+
+    let object = |properties: Vec<(&str, Value)>| -> Value {
+        properties
+            .into_iter()
+            .map(|(key, value)| (String::from(key), value))
+            .collect()
+    };
+
+    let cases = [
+        (
+            Internal::Empty,
+            object(vec![("type", Value::from("Empty"))]),
+        ),
+        (
+            Internal::At(Point { x: 1, y: 2 }),
+            object(vec![
+                ("type", Value::from("At")),
+                ("x", Value::Int32(1)),
+                ("y", Value::Int32(2)),
+            ]),
+        ),
+        (
+            Internal::Rect { w: 3, h: 4 },
+            object(vec![
+                ("type", Value::from("Rect")),
+                ("w", Value::Uint32(3)),
+                ("h", Value::Uint32(4)),
+            ]),
+        ),
+    ];
+    for (internal, expected) in cases {
+        let data = crate::ser::to_vec(&internal).unwrap();
+        assert_eq!(from_slice_checked(&data), Ok(expected));
+        assert_eq!(from_slice::<Internal>(&data), Ok(internal));
+    }
+
+    let cases = [
+        (
+            Adjacent::Empty,
+            object(vec![("type", Value::from("Empty"))]),
+        ),
+        (
+            Adjacent::Circle(5),
+            object(vec![
+                ("type", Value::from("Circle")),
+                ("content", Value::Uint32(5)),
+            ]),
+        ),
+        (
+            Adjacent::Line(-1, 1),
+            object(vec![
+                ("type", Value::from("Line")),
+                (
+                    "content",
+                    Value::Array(vec![Value::Int32(-1), Value::Int32(1)]),
+                ),
+            ]),
+        ),
+        (
+            Adjacent::Rect { w: 6, h: 7 },
+            object(vec![
+                ("type", Value::from("Rect")),
+                (
+                    "content",
+                    object(vec![("w", Value::Uint32(6)), ("h", Value::Uint32(7))]),
+                ),
+            ]),
+        ),
+    ];
+    // Their tag is written as a unit variant, which has to be a bare string
+    let mut serializer = crate::SerializerBuilder::new()
+        .unit_variants_as_strings(true)
+        .build();
+    for (adjacent, expected) in cases {
+        let data = serializer.serialize_to_vec(&adjacent).unwrap();
+        assert_eq!(from_slice_checked(&data), Ok(expected));
+        assert_eq!(from_slice::<Adjacent>(&data), Ok(adjacent));
+    }
+}
+
+#[test]
+fn test_type_mismatch() {
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Kind {
+        A,
+    }
+
+    // Values serde has no equivalent of, or that aren't what was asked for,
+    // are named like `Value::type_name` does
+    let message = |error: Error| error.to_string();
+    let date = crate::value_to_vec(&Value::Date(0.0)).unwrap();
+    assert_eq!(
+        from_slice::<u32>(&date).map_err(message),
+        Err(String::from("invalid type: date, expected u32"))
+    );
+    let regexp = crate::value_to_vec(&Value::RegExp {
+        expr: String::from("a+"),
+        flags: 0,
+    })
+    .unwrap();
+    assert_eq!(
+        from_slice::<Vec<u8>>(&regexp).map_err(message),
+        Err(String::from("invalid type: regexp, expected a sequence"))
+    );
+    let array = crate::to_vec(vec![1]).unwrap();
+    assert_eq!(
+        from_slice::<Kind>(&array).map_err(message),
+        Err(String::from("invalid type: array, expected enum Kind"))
+    );
+
+    // Unknown tags are still just unexpected
+    assert_eq!(
+        from_slice::<u32>(&[0xFF, 0x0F, b'?']),
+        Err(Error::Unexpected { byte: b'?', at: 2 })
+    );
+}
+
+#[test]
+fn test_from_slice_checked() {
+    let value = Value::Array(vec![Value::from("a"), Value::Double(1.5)]);
+    let data = crate::ser::value_to_vec(&value).unwrap();
+    assert_eq!(from_slice_checked(&data), Ok(value));
+    assert_eq!(roundtrip(&data), Ok(data.clone()));
+
+    let mut trailing = data.clone();
+    trailing.push(b'_');
+    assert_eq!(
+        from_slice_checked(&trailing),
+        Err(Error::TrailingBytes { remaining: 1 })
+    );
+}
+
+#[test]
+fn test_trailing_bytes() {
+    let mut data = crate::to_vec((1, "a")).unwrap();
+    data.push(0);
+    assert_eq!(
+        from_slice::<(i32, String)>(&data),
+        Err(Error::TrailingBytes { remaining: 1 })
+    );
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::TrailingBytes { remaining: 1 })
+    );
+
+    let deserializer = Deserializer::with_options(DeserializerOptions {
+        allow_trailing: true,
+        ..Default::default()
+    });
+    let value = deserializer.deserialize(&data).unwrap();
+    assert_eq!(value[1].as_str(), Some("a"));
+
+    // Back to back values aren't trailing bytes to `next_value`
+    let data = [crate::to_vec(1).unwrap(), crate::to_vec(2).unwrap()].concat();
+    assert!(from_slice::<i32>(&data).is_err());
+    let mut deserializer = Deserializer::new().with_input(&data);
+    assert_eq!(deserializer.next_value(), Ok(Some(Value::Int32(1))));
+    assert_eq!(deserializer.next_value(), Ok(Some(Value::Int32(2))));
+}
+
+#[test]
+fn test_random_input() {
+    // xorshift, good enough to not need a dependency
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let valid = crate::ser::value_to_vec(&Value::Array(vec![
+        Value::from("key"),
+        Value::Date(0.0),
+        Value::Map(vec![(Value::from(1), Value::BigInt(-1))]),
+        Value::ArrayBuffer(vec![1, 2, 3, 4]),
+    ]))
+    .unwrap();
+    for i in 0..500 {
+        let data: Vec<u8> = if i % 2 == 0 {
+            let len = (next() % 64) as usize;
+            (0..len).map(|_| next() as u8).collect()
+        } else {
+            // Mostly well-formed input reaches further into the decoder
+            let mut data = valid.clone();
+            for _ in 0..1 + next() % 3 {
+                let at = (next() as usize) % data.len();
+                data[at] = next() as u8;
+            }
+            data.truncate(data.len() - (next() % 4) as usize);
+            data
+        };
+        let _ = from_slice_checked(&data);
+        let _ = roundtrip(&data);
+        let _ = from_slice::<Value>(&data);
+    }
+}
+
+#[test]
+fn test_from_reader() {
+    let value = Value::Array(vec![Value::Date(0.0), Value::from("a")]);
+    let mut cursor = std::io::Cursor::new(crate::ser::value_to_vec(&value).unwrap());
+    assert_eq!(from_reader(&mut cursor), Ok(value));
+
+    let mut cursor = std::io::Cursor::new(vec![0xFF, crate::ser::FORMAT_VERSION, b'A', 1]);
+    assert!(matches!(
+        from_reader(&mut cursor),
+        Err(Error::UnexpectedEof { .. })
+    ));
+}
+
+#[test]
+fn test_deserialize_value() {
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    let data = crate::ser::to_vec(Point { x: 1, y: 2 }).unwrap();
+    let value = from_slice::<Value>(&data).unwrap();
+    let properties = value.as_object().unwrap();
+    assert_eq!(properties.len(), 2);
+    assert_eq!(properties["x"], Value::Int32(1));
+    assert_eq!(properties["y"], Value::Int32(2));
+
+    let data = crate::ser::to_vec((1u32, -1i64, 0.5, "s", ())).unwrap();
+    assert_eq!(
+        from_slice::<Value>(&data),
+        Ok(Value::Array(vec![
+            Value::Uint32(1),
+            Value::BigInt(-1),
+            Value::Double(0.5),
+            Value::from("s"),
+            Value::Undefined,
+        ]))
+    );
+
+    // Non-string keys stay a map
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b';',
+        b'I',
+        2,
+        b'T',
+        b':',
+        2,
+    ];
+    assert_eq!(
+        from_slice::<Value>(&data),
+        Ok(Value::Map(vec![(Value::Int32(1), Value::Boolean(true))]))
+    );
+}
+
+#[test]
+fn test_transcode() {
+    let user: Value = vec![
+        (String::from("name"), Value::from("ann")),
+        (String::from("password"), Value::from("hunter2")),
+    ]
+    .into_iter()
+    .collect();
+    let message: Value = vec![
+        (String::from("user"), user),
+        (
+            String::from("ids"),
+            Value::Array(vec![Value::Int32(1), Value::Int32(2)]),
+        ),
+    ]
+    .into_iter()
+    .collect();
+    let data = crate::ser::to_vec(&message).unwrap();
+
+    // Nothing replaced, nothing changed
+    let mut pointers = Vec::new();
+    let copy = transcode(&data, |pointer, _| {
+        pointers.push(pointer.to_string());
+        None
+    })
+    .unwrap();
+    assert_eq!(copy, data);
+    pointers.sort();
+    assert_eq!(
+        pointers,
+        ["/ids/0", "/ids/1", "/user/name", "/user/password"]
+    );
+
+    let redacted = transcode(&data, |pointer, value| match pointer.tokens() {
+        [user, password] if user == "user" && password == "password" => {
+            assert_eq!(value, Value::from("hunter2"));
+            Some(Value::from("***"))
+        }
+        _ => None,
+    })
+    .unwrap();
+    assert_eq!(redacted.len(), data.len() - 4);
+
+    let value = Deserializer::new().deserialize(&redacted).unwrap();
+    let user = value.as_object().unwrap()["user"].as_object().unwrap();
+    assert_eq!(user["password"], Value::from("***"));
+    assert_eq!(user["name"], Value::from("ann"));
+    assert_eq!(
+        value.as_object().unwrap()["ids"],
+        message.as_object().unwrap()["ids"]
+    );
+
+    // Objects and arrays aren't passed to `f`, even empty ones, maps are
+    let message: Value = vec![
+        (
+            String::from("object"),
+            Value::Object(crate::ObjectMap::new()),
+        ),
+        (String::from("array"), Value::Array(Vec::new())),
+        (
+            String::from("map"),
+            Value::Map(vec![(Value::from(1), Value::Array(Vec::new()))]),
+        ),
+    ]
+    .into_iter()
+    .collect();
+    let data = crate::ser::to_vec(&message).unwrap();
+    let mut pointers = Vec::new();
+    transcode(&data, |pointer, _| {
+        pointers.push(pointer.to_string());
+        None
+    })
+    .unwrap();
+    assert_eq!(pointers, ["/map"]);
+}
+
+#[cfg(feature = "preserve_order")]
+#[test]
+fn test_object_key_order() {
+    // { b: 1, a: 2, c: 3 }
+    let mut data = vec![0xFF, crate::ser::FORMAT_VERSION, b'o'];
+    for (key, value) in [(b'b', 1), (b'a', 2), (b'c', 3)] {
+        data.extend([b'"', 1, key, b'I', value * 2]);
+    }
+    data.extend([b'{', 3]);
+
+    let value = Deserializer::new().deserialize(&data).unwrap();
+    let keys: Vec<&str> = value
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(|key| key.as_str())
+        .collect();
+    assert_eq!(keys, ["b", "a", "c"]);
+    assert_eq!(crate::ser::to_vec(&value).unwrap(), data);
+}
+
+#[test]
+fn test_unsupported() {
+    // [Object(true), Object(1n), Object(false)]
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'A',
+        3,
+        b'y',
+        b'z',
+        16,
+        1,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        b'x',
+        b'$',
+        0,
+        3,
+    ];
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::Unexpected { byte: b'y', at: 4 })
+    ));
+
+    let options = DeserializerOptions {
+        allow_unsupported: true,
+        ..Default::default()
+    };
+    let value = Deserializer::with_options(options)
+        .deserialize(&data)
+        .unwrap();
+    assert_eq!(
+        value,
+        Value::Array(vec![
+            Value::Unsupported {
+                tag: b'y',
+                data: Vec::new()
+            },
+            Value::Unsupported {
+                tag: b'z',
+                data: vec![16, 1, 0, 0, 0, 0, 0, 0, 0]
+            },
+            Value::Unsupported {
+                tag: b'x',
+                data: Vec::new()
+            },
+        ])
+    );
+    assert_eq!(crate::ser::to_vec(&value).unwrap(), data);
+}
+
+#[test]
+fn test_number_tags() {
+    let mut cases = vec![
+        vec![b'I', 0],
+        vec![b'I', 1],
+        vec![b'I', 0xFF, 0xFF, 0xFF, 0xFF, 0x0F],
+        vec![b'I', 0xFE, 0xFF, 0xFF, 0xFF, 0x0F],
+        vec![b'U', 0],
+        vec![b'U', 0xFF, 0xFF, 0xFF, 0xFF, 0x0F],
+        vec![b'Z', 0],
+        vec![b'Z', 17, 1, 0, 0, 0, 0, 0, 0, 0],
+        vec![b'Z', 16, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F],
+        vec![b'Z', 17, 0, 0, 0, 0, 0, 0, 0, 0x80],
+    ];
+    // Doubles that could pass for integers keep their tag too
+    for double in [0.0, -0.0, 1.0, 0.5, f64::NAN, f64::INFINITY, 4294967296.0] {
+        let mut bytes = vec![b'N'];
+        bytes.extend(f64::to_ne_bytes(double));
+        cases.push(bytes);
+    }
+
+    for bytes in cases {
+        let mut data = vec![0xFF, crate::ser::FORMAT_VERSION];
+        data.extend(&bytes);
+        let value = Deserializer::new().deserialize(&data).unwrap();
+        let is_variant = match bytes[0] {
+            b'I' => value.is_int32(),
+            b'U' => value.is_uint32(),
+            b'N' => value.is_double(),
+            _ => value.is_bigint(),
+        };
+        assert!(is_variant, "{:?} from {:?}", value, bytes);
+        assert_eq!(crate::ser::value_to_vec(&value).unwrap(), data);
+    }
+}
+
+#[test]
+fn test_date() {
+    for millis in [0.0, 1_609_459_200_000.0, -1500.0, f64::NAN] {
+        let data = crate::ser::value_to_vec(&Value::Date(millis)).unwrap();
+        assert_eq!(data[2], b'D');
+        assert_eq!(data[3..], millis.to_ne_bytes());
+
+        match Deserializer::new().deserialize(&data) {
+            Ok(Value::Date(value)) => assert_eq!(value.to_bits(), millis.to_bits()),
+            value => panic!("expected a date, got {:?}", value),
+        }
+    }
+
+    let data = crate::ser::value_to_vec(&Value::Date(0.0)).unwrap();
+    assert!(matches!(
+        Deserializer::new().deserialize(&data[..7]),
+        Err(Error::UnexpectedEof { at: 7 })
+    ));
+}
+
+#[test]
+fn test_primitive_objects() {
+    let number = Value::NumberObject(-1.5);
+    let data = crate::ser::value_to_vec(&number).unwrap();
+    assert_eq!(data[2], b'n');
+    assert_eq!(data.len(), 2 + 1 + 8);
+    assert_eq!(Deserializer::new().deserialize(&data), Ok(number));
+
+    let string = Value::StringObject(String::from("abc"));
+    let data = crate::ser::value_to_vec(&string).unwrap();
+    assert_eq!(
+        data,
+        [
+            0xFF,
+            crate::ser::FORMAT_VERSION,
+            b's',
+            b'"',
+            3,
+            b'a',
+            b'b',
+            b'c'
+        ]
+    );
+    assert_eq!(Deserializer::new().deserialize(&data), Ok(string));
+
+    // Both are objects and can be referred back to
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'A',
+        2,
+        b's',
+        b'"',
+        1,
+        b'x',
+        b'^',
+        1,
+        b'$',
+        0,
+        2,
+    ];
+    let x = Value::StringObject(String::from("x"));
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Array(vec![x.clone(), x]))
+    );
+}
+
+#[test]
+fn test_regexp() {
+    // /ab+c/gi, global is bit 0 and ignoreCase bit 1
+    let regexp = Value::RegExp {
+        expr: String::from("ab+c"),
+        flags: 0b11,
+    };
+    let data = crate::ser::value_to_vec(&regexp).unwrap();
+    assert_eq!(
+        data,
+        [
+            0xFF,
+            crate::ser::FORMAT_VERSION,
+            b'R',
+            b'"',
+            4,
+            b'a',
+            b'b',
+            b'+',
+            b'c',
+            3
+        ]
+    );
+    assert_eq!(Deserializer::new().deserialize(&data), Ok(regexp));
+
+    // Source longer than the buffer
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'R', b'"', 9, b'a', 3];
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::UnexpectedEof { .. })
+    ));
+
+    // Source that isn't a string
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'R', b'I', 2, 0];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::Unexpected { byte: b'I', at: 3 })
+    );
+}
+
+#[test]
+fn test_object_reference() {
+    // [{a: 1}, ^1, ^1], the array is object 0 and the object 1
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'A',
+        3,
+        b'o',
+        b'"',
+        1,
+        b'a',
+        b'I',
+        2,
+        b'{',
+        1,
+        b'^',
+        1,
+        b'^',
+        1,
+        b'$',
+        0,
+        3,
+    ];
+    let object: Value = vec![(String::from("a"), Value::Int32(1))]
+        .into_iter()
+        .collect();
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Array(vec![object.clone(), object.clone(), object]))
+    );
+
+    // A cycle, the array contains itself
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'A',
+        1,
+        b'^',
+        0,
+        b'$',
+        0,
+        1,
+    ];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::Array(vec![Value::ObjectReference { id: 0 }]))
+    );
+
+    // Nothing to refer to
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'^', 0x81, 0x01];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::Message(String::from(
+            "Invalid object reference 129 at 2"
+        )))
+    );
+
+    // Each array refers to the one before it twice, doubling the size of
+    // the decoded value with every two references
+    let chain = |len: u8| {
+        let mut data = vec![0xFF, crate::ser::FORMAT_VERSION, b'A', len];
+        data.extend([b'A', 0, b'$', 0, 0]);
+        for id in 1..len {
+            data.extend([b'A', 2, b'^', id, b'^', id, b'$', 0, 2]);
+        }
+        data.extend([b'$', 0, len]);
+        data
+    };
+    // The outer array and ones of 1, 3, 7 and 15 values
+    assert!(Deserializer::new()
+        .with_max_nodes(27)
+        .deserialize(&chain(4))
+        .is_ok());
+    assert_eq!(
+        Deserializer::new()
+            .with_max_nodes(26)
+            .deserialize(&chain(4)),
+        Err(Error::LimitExceeded { len: 27, at: 31 })
+    );
+    assert!(matches!(
+        Deserializer::new().deserialize(&chain(64)),
+        Err(Error::LimitExceeded { .. })
+    ));
+}
+
+#[test]
+fn test_error() {
+    let header = [0xFF, crate::ser::FORMAT_VERSION];
+    let parse = |body: &[u8]| {
+        let data: Vec<u8> = header.iter().chain(body).copied().collect();
+        Deserializer::new().deserialize(&data)
+    };
+
+    let type_only = Value::Error {
+        ty: ErrorType::TypeError,
+        message: None,
+        stack: None,
+    };
+    assert_eq!(parse(b"rT."), Ok(type_only.clone()));
+    assert_eq!(
+        Deserializer::new().deserialize(&crate::ser::value_to_vec(&type_only).unwrap()),
+        Ok(type_only)
+    );
+
+    assert_eq!(
+        parse(b"rm\"\x04oops."),
+        Ok(Value::Error {
+            ty: ErrorType::Unknown,
+            message: Some(String::from("oops")),
+            stack: None,
+        })
+    );
+
+    let full = Value::Error {
+        ty: ErrorType::SyntaxError,
+        message: Some(String::from("bad")),
+        stack: Some(String::from("at <anonymous>")),
+    };
+    assert_eq!(
+        Deserializer::new().deserialize(&crate::ser::value_to_vec(&full).unwrap()),
+        Ok(full.clone())
+    );
+    // Stack before message
+    assert_eq!(parse(b"rCs\"\x0eat <anonymous>m\"\x03bad."), Ok(full));
+
+    // Missing terminator and unknown sub-section
+    assert!(matches!(parse(b"rT"), Err(Error::UnexpectedEof { .. })));
+    assert_eq!(parse(b"rx."), Err(Error::Unexpected { byte: b'x', at: 3 }));
+}
+
+#[test]
+fn test_array_buffer() {
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'B', 3, 1, 2, 3];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Ok(Value::ArrayBuffer(vec![1, 2, 3]))
+    );
+    assert_eq!(
+        crate::ser::value_to_vec(&Value::ArrayBuffer(vec![1, 2, 3])).unwrap(),
+        data
+    );
+
+    // Claims more bytes than there are
+    let data = [0xFF, crate::ser::FORMAT_VERSION, b'B', 4, 1, 2, 3];
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::UnexpectedEof { at: 7 })
+    ));
+}
+
+#[test]
+fn test_array_buffer_view() {
+    let view = Value::ArrayBufferView {
+        ty: ArrayBufferViewType::Uint8Array,
+        byte_offset: 1,
+        byte_length: 2,
+        buffer: vec![1, 2, 3, 4],
+    };
+    let data = crate::ser::value_to_vec(&view).unwrap();
+    assert_eq!(
+        data,
+        [
+            0xFF,
+            crate::ser::FORMAT_VERSION,
+            b'B',
+            4,
+            1,
+            2,
+            3,
+            4,
+            b'V',
+            b'B',
+            1,
+            2
+        ]
+    );
+    assert_eq!(Deserializer::new().deserialize(&data), Ok(view));
+
+    let bytes: serde_bytes::ByteBuf = from_slice(&data).unwrap();
+    assert_eq!(bytes.as_ref(), [2, 3]);
+
+    // Unknown type
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'B',
+        1,
+        0,
+        b'V',
+        b'x',
+        0,
+        1,
+    ];
+    assert_eq!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::UnknownViewType { byte: b'x', at: 6 })
+    );
+
+    // Past the end of the buffer
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'B',
+        1,
+        0,
+        b'V',
+        b'B',
+        1,
+        1,
+    ];
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::InvalidView { .. })
+    ));
+
+    // Misaligned, a Uint16Array of 3 bytes
+    let data = [
+        0xFF,
+        crate::ser::FORMAT_VERSION,
+        b'B',
+        4,
+        0,
+        0,
+        0,
+        0,
+        b'V',
+        b'W',
+        0,
+        3,
+    ];
+    assert!(matches!(
+        Deserializer::new().deserialize(&data),
+        Err(Error::InvalidView { .. })
+    ));
+}
+
+#[test]
+fn test_next_value() {
+    let mut data = crate::to_vec(vec![1, 2]).unwrap();
+    data.extend(crate::to_vec("two").unwrap());
+    // Without a header
+    data.extend(&crate::to_vec(true).unwrap()[2..]);
+
+    let mut deserializer = Deserializer::new().with_input(&data);
+    assert_eq!(
+        deserializer.next_value(),
+        Ok(Some(Value::Array(vec![Value::Int32(1), Value::Int32(2)])))
+    );
+    assert_eq!(deserializer.next_value(), Ok(Some(Value::from("two"))));
+    assert_eq!(deserializer.next_value(), Ok(Some(Value::Boolean(true))));
+    assert_eq!(deserializer.next_value(), Ok(None));
+    assert_eq!(deserializer.next_value(), Ok(None));
+
+    let mut truncated = Deserializer::new().with_input(&data[..data.len() - 3]);
+    assert!(truncated.next_value().is_ok());
+    assert!(truncated.next_value().is_err());
+}
+
+#[test]
+fn test_position() {
+    // A payload embedded in a message, followed by a trailer of our own
+    let first = crate::to_vec(vec![1, 2]).unwrap();
+    let second = crate::to_vec("two").unwrap();
+    let mut data = [first.clone(), second.clone()].concat();
+    data.extend(b"END");
+
+    let mut deserializer = Deserializer::new().with_input(&data);
+    assert_eq!(deserializer.position(), 0);
+    assert_eq!(deserializer.remaining(), &data[..]);
+
+    deserializer.next_value().unwrap();
+    assert_eq!(deserializer.position(), first.len());
+    assert_eq!(deserializer.remaining()[..second.len()], second[..]);
+
+    deserializer.next_value().unwrap();
+    assert_eq!(deserializer.position(), first.len() + second.len());
+    assert_eq!(deserializer.remaining(), b"END");
+}
+
+#[test]
+fn test_many() {
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Message {
+        id: u32,
+        body: &'static str,
+    }
+
+    let messages = [
+        Message { id: 1, body: "a" },
+        Message {
+            id: 2,
+            body: "\u{20ac}",
+        },
+        Message { id: 3, body: "" },
+    ];
+    let data = crate::to_vec_many(&messages).unwrap();
+    let expected: Vec<u8> = messages
+        .iter()
+        .flat_map(|message| crate::to_vec(message).unwrap())
+        .collect();
+    assert_eq!(data, expected);
+
+    let values = from_slice_many(&data).unwrap();
+    assert_eq!(values.len(), 3);
+    for (value, message) in values.iter().zip(&messages) {
+        assert_eq!(value["id"], Value::Uint32(message.id));
+        assert_eq!(value["body"].as_str(), Some(message.body));
+    }
+
+    assert_eq!(from_slice_many(&[]), Ok(Vec::new()));
+    assert_eq!(crate::to_vec_many::<u8>(&[]), Ok(Vec::new()));
+    assert!(from_slice_many(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn test_wasm() {
+    // An empty module: magic and version
+    let wire_bytes = b"\0asm\x01\0\0\0".to_vec();
+    let module = Value::WasmModule(wire_bytes.clone());
+    let encoded = crate::value_to_vec(&module).unwrap();
+    assert_eq!(encoded[2..5], [b'W', b'y', 8]);
+    assert_eq!(
+        Deserializer::new().deserialize(&encoded),
+        Ok(module.clone())
+    );
+
+    // Compiled code is skipped
+    let mut data = vec![0xFF, 0x0F, b'W', b'y', 8];
+    data.extend(&wire_bytes);
+    data.extend(&[3, 1, 2, 3]);
+    assert_eq!(from_slice_checked(&data), Ok(module));
+
+    let memory = Value::WasmMemory {
+        maximum_pages: -1,
+        transfer_id: 2,
+    };
+    let encoded = crate::value_to_vec(&memory).unwrap();
+    assert_eq!(encoded[2..], [b'm', 1, b'u', 2]);
+    assert_eq!(
+        Deserializer::new().deserialize(&encoded),
+        Ok(memory.clone())
+    );
+
+    // The memory and its buffer are both objects
+    let value = Value::Array(vec![memory.clone(), Value::ObjectReference { id: 2 }]);
+    assert_eq!(
+        Deserializer::new().deserialize(&crate::value_to_vec(&value).unwrap()),
+        Ok(Value::Array(vec![
+            memory,
+            Value::SharedArrayBuffer { transfer_id: 2 }
+        ]))
+    );
+}
+
+#[test]
+fn test_host_object() {
+    // `v8.serialize(new Uint8Array([1, 2, 3]))` in Node.js, which writes
+    // typed arrays as host objects: a type index, the length and the bytes
+    let data = [0xFF, 0x0F, b'\\', 1, 3, 1, 2, 3];
+    assert!(from_slice_checked(&data).is_err());
+
+    let deserializer = Deserializer::new().on_host_object(|data| {
+        let len = *data.get(1)? as usize;
+        Some(2 + len)
+    });
+    let value = deserializer.deserialize(&data).unwrap();
+    assert_eq!(value, Value::HostObject(vec![1, 3, 1, 2, 3]));
+    assert_eq!(crate::value_to_vec(&value).unwrap()[2..], data[2..]);
+
+    let deserializer = Deserializer::new().on_host_object(|_| None);
+    assert_eq!(
+        deserializer.deserialize(&data),
+        Err(Error::Message(String::from(
+            "Unrecognized host object at 2"
+        )))
+    );
+}
+
+#[test]
+fn test_data_view() {
+    // Byte granular, where a typed array would be misaligned
+    let view = Value::data_view((0..10).collect(), 3, 5);
+    assert_eq!(view.as_data_view(), Some(&[3, 4, 5, 6, 7][..]));
+
+    let encoded = crate::value_to_vec(&view).unwrap();
+    assert_eq!(encoded[encoded.len() - 4..], [b'V', b'?', 3, 5]);
+    let decoded = Deserializer::new().deserialize(&encoded).unwrap();
+    assert_eq!(decoded, view);
+    assert_eq!(decoded.as_data_view(), Some(&[3, 4, 5, 6, 7][..]));
+
+    assert!(crate::value_to_vec(&Value::data_view(vec![0; 10], 6, 5)).is_err());
+    assert_eq!(Value::ArrayBuffer(vec![0; 10]).as_data_view(), None);
+}
+
+#[test]
+fn test_transferred_buffers() {
+    let values = Value::Array(vec![
+        Value::ArrayBufferTransfer { transfer_id: 0 },
+        Value::SharedArrayBuffer { transfer_id: 300 },
+        Value::ObjectReference { id: 1 },
+    ]);
+    let encoded = crate::value_to_vec(&values).unwrap();
+    assert_eq!(
+        encoded[2..],
+        [b'A', 3, b't', 0, b'u', 0xAC, 2, b'^', 1, b'$', 0, 3]
+    );
+    assert_eq!(
+        Deserializer::new().deserialize(&encoded),
+        Ok(Value::Array(vec![
+            Value::ArrayBufferTransfer { transfer_id: 0 },
+            Value::SharedArrayBuffer { transfer_id: 300 },
+            Value::ArrayBufferTransfer { transfer_id: 0 },
+        ]))
+    );
+}
+
+#[test]
+fn test_from_slice_seed() {
+    use std::fmt;
+
+    // Sums elements, counting them on the side
+    struct Sum<'a>(&'a mut usize);
+
+    impl<'de, 'a> de::DeserializeSeed<'de> for Sum<'a> {
+        type Value = i64;
+
+        fn deserialize<D: de::Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> std::result::Result<i64, D::Error> {
+            deserializer.deserialize_seq(self)
+        }
+    }
+
+    impl<'de, 'a> de::Visitor<'de> for Sum<'a> {
+        type Value = i64;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a sequence of integers")
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> std::result::Result<i64, A::Error> {
+            let mut sum = 0;
+            while let Some(value) = seq.next_element::<i64>()? {
+                sum += value;
+                *self.0 += 1;
+            }
+            Ok(sum)
+        }
+    }
+
+    let data = crate::to_vec(vec![1, 2, 3, 4]).unwrap();
+    let mut count = 0;
+    assert_eq!(from_slice_seed(&data, Sum(&mut count)), Ok(10));
+    assert_eq!(count, 4);
+    assert!(from_slice_seed(&data[..5], Sum(&mut count)).is_err());
+}
+
+#[test]
+fn test_borrowed() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message<'a> {
+        name: &'a str,
+        #[serde(with = "serde_bytes")]
+        data: &'a [u8],
+    }
+
+    let message = Message {
+        name: "ping",
+        data: &[1, 2, 3],
+    };
+    let encoded = crate::to_vec(&message).unwrap();
+    // Both point into `encoded`, nothing was copied
+    let decoded: Message = from_slice(&encoded).unwrap();
+    assert_eq!(decoded, message);
+    let range = encoded.as_ptr_range();
+    assert!(range.contains(&decoded.name.as_ptr()));
+    assert!(range.contains(&decoded.data.as_ptr()));
+
+    // Legacy UTF-8 strings can be borrowed whatever they contain
+    let data = [0xFF, 0x0F, b'S', 3, b'a', 0xC3, 0xA9];
+    let string: &str = from_slice(&data).unwrap();
+    assert_eq!(string, "aé");
+    assert_eq!(from_slice_checked(&data), Ok(Value::from("aé")));
+    assert!(from_slice::<&str>(&[0xFF, 0x0F, b'S', 1, 0xC3]).is_err());
+}
+
+#[test]
+fn test_cow() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Frame<'a> {
+        #[serde(borrow)]
+        name: Cow<'a, str>,
+        #[serde(borrow, with = "serde_bytes")]
+        payload: Cow<'a, [u8]>,
+    }
+
+    let borrowed = Frame {
+        name: Cow::Borrowed("ping"),
+        payload: Cow::Borrowed(&[1, 2, 3]),
+    };
+    let owned = Frame {
+        name: Cow::Owned(String::from("ping")),
+        payload: Cow::Owned(vec![1, 2, 3]),
+    };
+    // Either way the contents are copied straight into the output once
+    let encoded = crate::to_vec(&borrowed).unwrap();
+    assert_eq!(crate::to_vec(&owned).unwrap(), encoded);
+    assert_eq!(encoded[encoded.len() - 7..], [b'B', 3, 1, 2, 3, b'{', 2]);
+
+    let decoded: Frame = from_slice(&encoded).unwrap();
+    assert_eq!(decoded, owned);
+    assert!(matches!(decoded.name, Cow::Borrowed(_)));
+    assert!(matches!(decoded.payload, Cow::Borrowed(_)));
+
+    // Strings that aren't stored as UTF-8 have to be converted
+    for name in ["caf\u{e9}", "\u{20ac}"] {
+        let frame = Frame {
+            name: Cow::Borrowed(name),
+            payload: Cow::Borrowed(&[]),
+        };
+        let encoded = crate::to_vec(&frame).unwrap();
+        let decoded: Frame = from_slice(&encoded).unwrap();
+        assert_eq!(decoded, frame);
+        assert!(matches!(decoded.name, Cow::Owned(_)));
+    }
+
+    // Without `serde_bytes`, serde writes `[u8]` as an array of numbers
+    let bytes: Cow<[u8]> = Cow::Borrowed(&[1, 2]);
+    assert_eq!(
+        crate::to_vec(&bytes).unwrap(),
+        crate::to_vec(vec![1u8, 2]).unwrap()
+    );
+}
+
+#[test]
+fn test_byte_buf() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Upload {
+        name: String,
+        #[serde(with = "serde_bytes")]
+        body: Vec<u8>,
+    }
+
+    let upload = Upload {
+        name: String::from("a.bin"),
+        body: (0..=255).collect(),
+    };
+    let encoded = crate::to_vec(&upload).unwrap();
+    // Written as an `ArrayBuffer`, not an array of numbers
+    let at = encoded.len() - 256 - 5;
+    assert_eq!(encoded[at..at + 3], [b'B', 0x80, 0x02]);
+    assert_eq!(from_slice::<Upload>(&encoded), Ok(upload));
+
+    // Only takes the bytes whole, an element at a time would be an error
+    struct Whole(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for Whole {
+        fn deserialize<D: de::Deserializer<'de>>(
+            deserializer: D,
+        ) -> std::result::Result<Self, D::Error> {
+            struct Visitor;
+
+            impl<'de> de::Visitor<'de> for Visitor {
+                type Value = Whole;
+
+                fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    formatter.write_str("bytes")
+                }
+
+                fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Whole, E> {
+                    Ok(Whole(v.to_vec()))
+                }
+            }
+
+            deserializer.deserialize_byte_buf(Visitor)
+        }
+    }
+
+    let data = [0xFF, 0x0F, b'B', 3, 1, 2, 3];
+    assert_eq!(from_slice::<Whole>(&data).unwrap().0, [1, 2, 3]);
+    assert!(from_slice::<Whole>(&[0xFF, 0x0F, b'A', 1, b'I', 2, b'$', 0, 1]).is_err());
+}
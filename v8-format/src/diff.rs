@@ -0,0 +1,220 @@
+use crate::common::Value;
+use crate::pointer::JsonPointer;
+
+/// A difference between two values, found by `Value::diff`.
+///
+/// Paths point into both values, and `from`/`to` are the sides of
+/// `self.diff(other)` respectively.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueDiff {
+    /// A property that's only in `other`.
+    Added { path: JsonPointer, value: Value },
+    /// A property that's only in `self`.
+    Removed { path: JsonPointer, value: Value },
+    /// Values of the same type that differ.
+    Changed {
+        path: JsonPointer,
+        from: Value,
+        to: Value,
+    },
+    /// Values of different types, see `Value::type_name`, e.g. an int32
+    /// that came back as a double.
+    TypeMismatch {
+        path: JsonPointer,
+        from: Value,
+        to: Value,
+    },
+    /// Arrays of different lengths. Their common elements are still
+    /// compared.
+    LengthMismatch {
+        path: JsonPointer,
+        from: usize,
+        to: usize,
+    },
+}
+
+impl ValueDiff {
+    pub fn path(&self) -> &JsonPointer {
+        match self {
+            ValueDiff::Added { path, .. }
+            | ValueDiff::Removed { path, .. }
+            | ValueDiff::Changed { path, .. }
+            | ValueDiff::TypeMismatch { path, .. }
+            | ValueDiff::LengthMismatch { path, .. } => path,
+        }
+    }
+}
+
+impl Value {
+    /// Lists where `other` differs from this value, e.g. to find where a
+    /// round trip went wrong. Empty if they're the same.
+    ///
+    /// Objects are compared property by property and arrays element by
+    /// element, anything else as a whole. Unlike `==`, NaN is the same as
+    /// NaN, and `0` and `-0` are told apart, like `Object.is`.
+    pub fn diff(&self, other: &Value) -> Vec<ValueDiff> {
+        let mut diffs = Vec::new();
+        diff(&mut JsonPointer::new(), self, other, &mut diffs);
+        diffs
+    }
+}
+
+fn diff(path: &mut JsonPointer, from: &Value, to: &Value, diffs: &mut Vec<ValueDiff>) {
+    match (from, to) {
+        (Value::Object(from), Value::Object(to)) => {
+            for (key, value) in from {
+                path.push(key.as_str());
+                match to.get(key.as_str()) {
+                    Some(to) => diff(path, value, to, diffs),
+                    None => diffs.push(ValueDiff::Removed {
+                        path: path.clone(),
+                        value: value.clone(),
+                    }),
+                }
+                path.pop();
+            }
+            for (key, value) in to {
+                if !from.contains_key(key.as_str()) {
+                    path.push(key.as_str());
+                    diffs.push(ValueDiff::Added {
+                        path: path.clone(),
+                        value: value.clone(),
+                    });
+                    path.pop();
+                }
+            }
+        }
+        (Value::Array(from), Value::Array(to)) => {
+            if from.len() != to.len() {
+                diffs.push(ValueDiff::LengthMismatch {
+                    path: path.clone(),
+                    from: from.len(),
+                    to: to.len(),
+                });
+            }
+            for (index, (from, to)) in from.iter().zip(to).enumerate() {
+                path.push(index.to_string());
+                diff(path, from, to, diffs);
+                path.pop();
+            }
+        }
+        (from, to) if same(from, to) => {}
+        (from, to) if from.type_name() != to.type_name() => diffs.push(ValueDiff::TypeMismatch {
+            path: path.clone(),
+            from: from.clone(),
+            to: to.clone(),
+        }),
+        (from, to) => diffs.push(ValueDiff::Changed {
+            path: path.clone(),
+            from: from.clone(),
+            to: to.clone(),
+        }),
+    }
+}
+
+/// `==`, except that numbers compare like `Object.is`.
+fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Double(a), Value::Double(b)) | (Value::Date(a), Value::Date(b)) => {
+            a.to_bits() == b.to_bits() || a.is_nan() && b.is_nan()
+        }
+        _ => a == b,
+    }
+}
+
+#[test]
+fn test_diff() {
+    let object = |properties: Vec<(&str, Value)>| -> Value {
+        properties
+            .into_iter()
+            .map(|(key, value)| (String::from(key), value))
+            .collect()
+    };
+    let user = |city: &str| {
+        object(vec![
+            ("name", Value::from("ann")),
+            (
+                "address",
+                object(vec![
+                    ("city", Value::from(city)),
+                    ("zip", Value::from(1000)),
+                ]),
+            ),
+            ("scores", Value::Array(vec![Value::Double(f64::NAN)])),
+        ])
+    };
+
+    assert_eq!(user("Oslo").diff(&user("Oslo")), Vec::new());
+    let diffs = user("Oslo").diff(&user("Bergen"));
+    assert_eq!(
+        diffs,
+        [ValueDiff::Changed {
+            path: "/address/city".parse().unwrap(),
+            from: Value::from("Oslo"),
+            to: Value::from("Bergen"),
+        }]
+    );
+    assert_eq!(diffs[0].path().to_string(), "/address/city");
+
+    let from = object(vec![
+        ("id", Value::Int32(1)),
+        ("old", Value::Null),
+        ("list", Value::Array(vec![Value::Int32(1), Value::Int32(2)])),
+        ("zero", Value::Double(0.0)),
+    ]);
+    let to = object(vec![
+        ("id", Value::Double(1.0)),
+        ("list", Value::Array(vec![Value::Int32(3)])),
+        ("zero", Value::Double(-0.0)),
+        ("new", Value::Boolean(true)),
+    ]);
+    let diffs = from.diff(&to);
+    let at = |path: &str| path.parse::<JsonPointer>().unwrap();
+    for expected in [
+        ValueDiff::TypeMismatch {
+            path: at("/id"),
+            from: Value::Int32(1),
+            to: Value::Double(1.0),
+        },
+        ValueDiff::Removed {
+            path: at("/old"),
+            value: Value::Null,
+        },
+        ValueDiff::Added {
+            path: at("/new"),
+            value: Value::Boolean(true),
+        },
+        ValueDiff::LengthMismatch {
+            path: at("/list"),
+            from: 2,
+            to: 1,
+        },
+        ValueDiff::Changed {
+            path: at("/list/0"),
+            from: Value::Int32(1),
+            to: Value::Int32(3),
+        },
+        ValueDiff::Changed {
+            path: at("/zero"),
+            from: Value::Double(0.0),
+            to: Value::Double(-0.0),
+        },
+    ] {
+        assert!(
+            diffs.contains(&expected),
+            "{:?} not in {:?}",
+            expected,
+            diffs
+        );
+    }
+    assert_eq!(diffs.len(), 6);
+
+    assert_eq!(
+        Value::from(1).diff(&Value::from("1")),
+        [ValueDiff::TypeMismatch {
+            path: JsonPointer::new(),
+            from: Value::from(1),
+            to: Value::from("1"),
+        }]
+    );
+}
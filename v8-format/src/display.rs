@@ -0,0 +1,208 @@
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Write;
+
+use crate::common::Value;
+
+/// Renders values like JS source or Node's `util.inspect`, e.g.
+/// `{ id: 1, tags: ["a", "b"], big: 10n }`, for debugging. Objects with
+/// no JS literal (buffers, transferred objects, etc.) get a short summary
+/// instead of their contents.
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Undefined => f.write_str("undefined"),
+            Value::Null => f.write_str("null"),
+            Value::Boolean(value) => write!(f, "{}", value),
+            Value::Int32(value) => write!(f, "{}", value),
+            Value::Uint32(value) => write!(f, "{}", value),
+            Value::Double(value) => write_number(f, *value),
+            Value::BigInt(value) => write!(f, "{}n", value),
+            Value::String(value, _) => write!(f, "{:?}", value),
+            Value::ObjectReference { id } => write!(f, "[Reference #{}]", id),
+            Value::Object(properties) => {
+                if properties.is_empty() {
+                    return f.write_str("{}");
+                }
+                f.write_str("{ ")?;
+                for (i, (key, value)) in properties.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_key(f, key)?;
+                    write!(f, ": {}", value)?;
+                }
+                f.write_str(" }")
+            }
+            Value::Array(values) => {
+                f.write_char('[')?;
+                write_list(f, values)?;
+                f.write_char(']')
+            }
+            Value::Date(time) => {
+                f.write_str("new Date(")?;
+                write_number(f, *time)?;
+                f.write_char(')')
+            }
+            Value::NumberObject(value) => {
+                f.write_str("[Number: ")?;
+                write_number(f, *value)?;
+                f.write_char(']')
+            }
+            Value::BigIntObject() => f.write_str("[BigInt]"),
+            Value::StringObject(value) => write!(f, "[String: {:?}]", value),
+            Value::RegExp { expr, flags } => write!(f, "/{}/{}", expr, regexp_flags(*flags)),
+            Value::Map(entries) => {
+                write!(f, "Map({}) {{", entries.len())?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    f.write_str(if i > 0 { ", " } else { " " })?;
+                    write!(f, "{} => {}", key, value)?;
+                }
+                f.write_str(if entries.is_empty() { "}" } else { " }" })
+            }
+            Value::Set(values) => {
+                write!(f, "Set({}) {{", values.len())?;
+                if !values.is_empty() {
+                    f.write_char(' ')?;
+                    write_list(f, values)?;
+                    f.write_char(' ')?;
+                }
+                f.write_char('}')
+            }
+            Value::ArrayBuffer(data) => write!(f, "ArrayBuffer {{ byteLength: {} }}", data.len()),
+            Value::ArrayBufferTransfer { transfer_id } => {
+                write!(f, "ArrayBuffer {{ transferId: {} }}", transfer_id)
+            }
+            Value::ArrayBufferView {
+                ty,
+                byte_offset,
+                byte_length,
+                ..
+            } => write!(
+                f,
+                "{:?} {{ byteOffset: {}, byteLength: {} }}",
+                ty, byte_offset, byte_length
+            ),
+            Value::SharedArrayBuffer { transfer_id } => {
+                write!(f, "SharedArrayBuffer {{ transferId: {} }}", transfer_id)
+            }
+            Value::Error { ty, message, .. } => {
+                write!(f, "{:?}", ty)?;
+                match message {
+                    Some(message) => write!(f, ": {}", message),
+                    None => Ok(()),
+                }
+            }
+            Value::WasmModule(bytes) => {
+                write!(f, "WebAssembly.Module {{ byteLength: {} }}", bytes.len())
+            }
+            Value::WasmMemory {
+                maximum_pages,
+                transfer_id,
+            } => write!(
+                f,
+                "WebAssembly.Memory {{ maximum: {}, transferId: {} }}",
+                maximum_pages, transfer_id
+            ),
+            Value::HostObject(data) => write!(f, "[HostObject: {} bytes]", data.len()),
+            Value::Unsupported { tag, .. } => write!(f, "[Unsupported: {:?}]", *tag as char),
+        }
+    }
+}
+
+/// Like JS's `Number.prototype.toString` for the common cases, which
+/// differs from Rust's for non-finite numbers and negative zero.
+fn write_number(f: &mut fmt::Formatter, value: f64) -> fmt::Result {
+    if value.is_nan() {
+        f.write_str("NaN")
+    } else if value.is_infinite() {
+        f.write_str(if value > 0.0 { "Infinity" } else { "-Infinity" })
+    } else if value == 0.0 && value.is_sign_negative() {
+        f.write_str("-0")
+    } else {
+        write!(f, "{}", value)
+    }
+}
+
+/// Keys that are identifiers are written bare, the rest quoted.
+fn write_key(f: &mut fmt::Formatter, key: &str) -> fmt::Result {
+    let mut chars = key.chars();
+    let identifier = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if identifier {
+        f.write_str(key)
+    } else {
+        write!(f, "{:?}", key)
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter, values: &[Value]) -> fmt::Result {
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", value)?;
+    }
+    Ok(())
+}
+
+/// V8's flag bits, in the order `RegExp.prototype.flags` lists them.
+fn regexp_flags(flags: u32) -> String {
+    const FLAGS: [(u32, char); 8] = [
+        (1 << 7, 'd'),
+        (1 << 0, 'g'),
+        (1 << 1, 'i'),
+        (1 << 2, 'm'),
+        (1 << 5, 's'),
+        (1 << 4, 'u'),
+        (1 << 8, 'v'),
+        (1 << 3, 'y'),
+    ];
+    FLAGS
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, flag)| flag)
+        .collect()
+}
+
+#[test]
+fn test_display() {
+    let value: Value = vec![
+        (String::from("id"), Value::Int32(1)),
+        (String::from("name"), Value::from("a \"b\"")),
+        (
+            String::from("tags"),
+            Value::Array(vec![Value::Undefined, Value::Null, Value::Double(-0.0)]),
+        ),
+        (
+            String::from("content-type"),
+            Value::Map(vec![(Value::BigInt(10), Value::Boolean(true))]),
+        ),
+        (
+            String::from("pattern"),
+            Value::RegExp {
+                expr: String::from("a+"),
+                flags: 0b11,
+            },
+        ),
+        (String::from("empty"), Value::Set(Vec::new())),
+    ]
+    .into_iter()
+    .collect();
+
+    #[cfg(not(feature = "preserve_order"))]
+    let expected = concat!(
+        r#"{ "content-type": Map(1) { 10n => true }, empty: Set(0) {}, id: 1, "#,
+        r#"name: "a \"b\"", pattern: /a+/gi, tags: [undefined, null, -0] }"#
+    );
+    #[cfg(feature = "preserve_order")]
+    let expected = concat!(
+        r#"{ id: 1, name: "a \"b\"", tags: [undefined, null, -0], "#,
+        r#""content-type": Map(1) { 10n => true }, pattern: /a+/gi, empty: Set(0) {} }"#
+    );
+    assert_eq!(value.to_string(), expected);
+    assert_eq!(Value::Object(Default::default()).to_string(), "{}");
+    assert_eq!(Value::Double(f64::NEG_INFINITY).to_string(), "-Infinity");
+}
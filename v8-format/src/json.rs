@@ -0,0 +1,167 @@
+use std::convert::TryFrom;
+
+use crate::common::Error;
+use crate::common::Value;
+
+/// Numbers that fit become `Int32` (or `Uint32`), like V8's small integers,
+/// the rest `Double`.
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(value) => Value::Boolean(value),
+            serde_json::Value::Number(number) => {
+                if let Some(value) = number.as_i64().and_then(|n| i32::try_from(n).ok()) {
+                    Value::Int32(value)
+                } else if let Some(value) = number.as_u64().and_then(|n| u32::try_from(n).ok()) {
+                    Value::Uint32(value)
+                } else {
+                    // Always a number without arbitrary precision
+                    Value::Double(number.as_f64().unwrap_or(f64::NAN))
+                }
+            }
+            serde_json::Value::String(value) => Value::from(value),
+            serde_json::Value::Array(values) => {
+                Value::Array(values.into_iter().map(Value::from).collect())
+            }
+            serde_json::Value::Object(properties) => properties
+                .into_iter()
+                .map(|(key, value)| (key, Value::from(value)))
+                .collect(),
+        }
+    }
+}
+
+/// The inverse of `From<serde_json::Value>`, for values JSON can hold.
+///
+/// Some conversions lose information: `NaN` and infinities become `null`
+/// like in `JSON.stringify`, number and string objects are unboxed, maps
+/// with only string keys become objects and sets become arrays. Anything
+/// else (`undefined`, BigInts, dates, regexps, buffers, errors, WebAssembly
+/// objects, references and maps with other keys) is an error.
+impl TryFrom<Value> for serde_json::Value {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self, Error> {
+        Ok(match value {
+            Value::Null => serde_json::Value::Null,
+            Value::Boolean(value) => serde_json::Value::Bool(value),
+            Value::Int32(value) => serde_json::Value::from(value),
+            Value::Uint32(value) => serde_json::Value::from(value),
+            Value::Double(value) | Value::NumberObject(value) => {
+                serde_json::Number::from_f64(value).map_or(serde_json::Value::Null, Into::into)
+            }
+            Value::String(value, _) | Value::StringObject(value) => {
+                serde_json::Value::String(value)
+            }
+            Value::Array(values) | Value::Set(values) => serde_json::Value::Array(
+                values
+                    .into_iter()
+                    .map(serde_json::Value::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(properties) => serde_json::Value::Object(
+                properties
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, serde_json::Value::try_from(value)?)))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            Value::Map(entries) => serde_json::Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| match key {
+                        Value::String(key, _) => Ok((key, serde_json::Value::try_from(value)?)),
+                        _ => Err(unrepresentable("a Map with keys other than strings")),
+                    })
+                    .collect::<Result<_, Error>>()?,
+            ),
+            Value::Undefined => return Err(unrepresentable("undefined")),
+            Value::BigInt(_) | Value::BigIntObject() => return Err(unrepresentable("a BigInt")),
+            Value::Date(_) => return Err(unrepresentable("a Date")),
+            Value::RegExp { .. } => return Err(unrepresentable("a RegExp")),
+            Value::ArrayBuffer(_)
+            | Value::ArrayBufferTransfer { .. }
+            | Value::ArrayBufferView { .. }
+            | Value::SharedArrayBuffer { .. } => return Err(unrepresentable("a buffer")),
+            Value::Error { .. } => return Err(unrepresentable("an Error")),
+            Value::WasmModule(_) | Value::WasmMemory { .. } => {
+                return Err(unrepresentable("a WebAssembly object"))
+            }
+            Value::HostObject(_) => return Err(unrepresentable("a host object")),
+            Value::ObjectReference { .. } => return Err(unrepresentable("an object reference")),
+            Value::Unsupported { .. } => return Err(unrepresentable("an unsupported object")),
+        })
+    }
+}
+
+fn unrepresentable(what: &str) -> Error {
+    Error::Message(format!("Can't represent {} in JSON", what))
+}
+
+#[test]
+fn test_from_json() {
+    let json = serde_json::json!({
+        "null": null,
+        "bool": true,
+        "small": -5,
+        "unsigned": 3_000_000_000u32,
+        "large": 1u64 << 40,
+        "fraction": 0.25,
+        "string": "text",
+        "array": [1, "two"],
+    });
+
+    let value = Value::from(json);
+    let object = value.as_object().unwrap();
+    assert_eq!(object["null"], Value::Null);
+    assert_eq!(object["bool"], Value::Boolean(true));
+    assert_eq!(object["small"], Value::Int32(-5));
+    assert_eq!(object["unsigned"], Value::Uint32(3_000_000_000));
+    assert_eq!(object["large"], Value::Double((1u64 << 40) as f64));
+    assert_eq!(object["fraction"], Value::Double(0.25));
+    assert_eq!(object["string"], Value::from("text"));
+    assert_eq!(
+        object["array"],
+        Value::Array(vec![Value::Int32(1), Value::from("two")])
+    );
+}
+
+#[test]
+fn test_to_json() {
+    let value: Value = vec![
+        (String::from("id"), Value::Int32(7)),
+        (String::from("ratio"), Value::Double(f64::NAN)),
+        (
+            String::from("tags"),
+            Value::Set(vec![
+                Value::from("a"),
+                Value::StringObject(String::from("b")),
+            ]),
+        ),
+        (
+            String::from("env"),
+            Value::Map(vec![(Value::from("HOME"), Value::from("/root"))]),
+        ),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        serde_json::Value::try_from(value),
+        Ok(serde_json::json!({
+            "id": 7,
+            "ratio": null,
+            "tags": ["a", "b"],
+            "env": { "HOME": "/root" },
+        }))
+    );
+
+    let nested = Value::Array(vec![Value::Null, Value::BigInt(1)]);
+    assert_eq!(
+        serde_json::Value::try_from(nested),
+        Err(Error::Message(String::from(
+            "Can't represent a BigInt in JSON"
+        )))
+    );
+    let map = Value::Map(vec![(Value::Int32(1), Value::Null)]);
+    assert!(serde_json::Value::try_from(map).is_err());
+}
@@ -1,10 +1,36 @@
 mod common;
 mod de;
+mod diff;
+mod display;
+#[cfg(feature = "json")]
+mod json;
+mod patch;
+mod pointer;
+mod pool;
+mod schema;
 mod ser;
+mod size;
+mod visit;
 
 pub use serde;
-pub use common::{ArrayBufferViewType, ErrorType, Value};
-pub use de::Deserializer;
-pub use ser::Serializer;
+pub use common::{ArrayBufferViewType, Error, ErrorType, ObjectMap, Value, Warning};
+pub use de::{
+    from_reader, from_slice, from_slice_checked, from_slice_lenient, from_slice_many,
+    from_slice_pooled, from_slice_seed, roundtrip, transcode, Deserializer, DeserializerOptions,
+};
+pub use diff::ValueDiff;
+pub use pointer::JsonPointer;
+pub use pool::{Pooled, ValuePool};
+pub use schema::Schema;
+pub use ser::{
+    serialize_as_js_set, serialize_as_object, serialize_hooked, serialize_shared, serialized_size,
+    to_slice, to_vec_many, to_vec_with_capacity, to_vec_with_options, AsBinaryIp, AsJsSet,
+    AsObject, BigInt, CharString, Hooked, SerializeDateExt, SerializeShared, Serializer,
+    SerializerBuilder, SerializerOptions, Shared, TypedBytes,
+};
+pub use visit::ValueVisitor;
 pub use crate::ser::to_vec;
+pub use crate::ser::value_to_vec;
 pub use crate::ser::FORMAT_VERSION;
+#[cfg(feature = "smallvec")]
+pub use crate::ser::INLINE_CAPACITY;
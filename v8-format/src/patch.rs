@@ -0,0 +1,246 @@
+use crate::common::{Error, Result, Value};
+use crate::diff::ValueDiff;
+use crate::pointer::JsonPointer;
+
+impl Value {
+    /// Computes a patch that turns `old` into `new` when passed to
+    /// `apply_patch`.
+    ///
+    /// The patch is a `Value` itself, so it can be sent with the same
+    /// serialization as the values. It's an array of operations modelled on
+    /// JSON Patch (RFC 6902), each an object with:
+    ///
+    /// - `op`: `"add"`, `"remove"` or `"replace"`.
+    /// - `path`: JSON Pointer to the object property or array element the
+    ///   operation applies to, `""` for the whole value.
+    /// - `value`: the value to add or replace with, missing for `"remove"`.
+    ///
+    /// The operations follow `old.diff(new)`: objects and arrays are
+    /// compared element by element (arrays by index, so inserting at the front
+    /// replaces every element after it), anything else that differs is
+    /// replaced as a whole.
+    pub fn diff_patch(old: &Value, new: &Value) -> Value {
+        let mut operations = Vec::new();
+        for diff in old.diff(new) {
+            match diff {
+                ValueDiff::Added { path, value } => {
+                    operations.push(operation("add", &path, Some(&value)))
+                }
+                ValueDiff::Removed { path, .. } => {
+                    operations.push(operation("remove", &path, None))
+                }
+                ValueDiff::Changed { path, to, .. } | ValueDiff::TypeMismatch { path, to, .. } => {
+                    operations.push(operation("replace", &path, Some(&to)))
+                }
+                // The common elements get their own diffs
+                ValueDiff::LengthMismatch { mut path, from, to } => {
+                    let elements = lookup(new, &path).and_then(Value::as_array);
+                    for (index, value) in elements.into_iter().flatten().enumerate().skip(from) {
+                        path.push(index.to_string());
+                        operations.push(operation("add", &path, Some(value)));
+                        path.pop();
+                    }
+                    // From the end, so the remaining indices stay valid
+                    for index in (to..from).rev() {
+                        path.push(index.to_string());
+                        operations.push(operation("remove", &path, None));
+                        path.pop();
+                    }
+                }
+            }
+        }
+        Value::Array(operations)
+    }
+
+    /// Applies a patch made by `diff_patch`, stopping at the first operation
+    /// that doesn't fit this value.
+    pub fn apply_patch(&mut self, patch: &Value) -> Result<()> {
+        let operations = match patch.as_array() {
+            Some(operations) => operations,
+            None => return Err(invalid("a patch must be an array")),
+        };
+        for operation in operations {
+            self.apply_operation(operation)?;
+        }
+        Ok(())
+    }
+
+    fn apply_operation(&mut self, operation: &Value) -> Result<()> {
+        let fields = match operation.as_object() {
+            Some(fields) => fields,
+            None => return Err(invalid("an operation must be an object")),
+        };
+        let op = fields.get("op").and_then(Value::as_str);
+        let path = match fields.get("path").and_then(Value::as_str) {
+            Some(path) => path.parse::<JsonPointer>()?,
+            None => return Err(invalid("an operation must have a path")),
+        };
+        let value = fields.get("value").cloned();
+
+        let (last, parents) = match path.tokens().split_last() {
+            Some(split) => split,
+            None => {
+                return match (op, value) {
+                    (Some("replace"), Some(value)) => {
+                        *self = value;
+                        Ok(())
+                    }
+                    _ => Err(invalid("only replace applies to the whole value")),
+                }
+            }
+        };
+        let mut target = self;
+        for token in parents {
+            target = match target {
+                Value::Object(properties) => properties.get_mut(token.as_str()),
+                Value::Array(elements) => index(token, elements.len())
+                    .ok()
+                    .and_then(move |index| elements.get_mut(index)),
+                _ => None,
+            }
+            .ok_or_else(|| invalid(&format!("nothing at {}", path)))?;
+        }
+
+        match (target, op, value) {
+            (Value::Object(properties), Some("add"), Some(value))
+            | (Value::Object(properties), Some("replace"), Some(value)) => {
+                properties.insert(last.clone(), value);
+            }
+            (Value::Object(properties), Some("remove"), None) => {
+                if !properties.contains_key(last.as_str()) {
+                    return Err(invalid(&format!("nothing at {}", path)));
+                }
+                properties.retain(|key, _| key != last);
+            }
+            // "-" appends, as in JSON Patch
+            (Value::Array(elements), Some("add"), Some(value)) => {
+                let index = match last.as_str() {
+                    "-" => elements.len(),
+                    last => index(last, elements.len() + 1)?,
+                };
+                elements.insert(index, value);
+            }
+            (Value::Array(elements), Some("replace"), Some(value)) => {
+                let index = index(last, elements.len())?;
+                elements[index] = value;
+            }
+            (Value::Array(elements), Some("remove"), None) => {
+                elements.remove(index(last, elements.len())?);
+            }
+            _ => return Err(invalid(&format!("can't apply operation at {}", path))),
+        }
+        Ok(())
+    }
+}
+
+/// Looks up the value at `path`, which `Value::diff` found in `value`.
+fn lookup<'a>(value: &'a Value, path: &JsonPointer) -> Option<&'a Value> {
+    path.tokens().iter().try_fold(value, |value, token| {
+        value
+            .get(token)
+            .or_else(|| token.parse().ok().and_then(|index| value.get_index(index)))
+    })
+}
+
+fn operation(op: &str, path: &JsonPointer, value: Option<&Value>) -> Value {
+    let mut fields = vec![
+        (String::from("op"), Value::from(op)),
+        (String::from("path"), Value::from(path.to_string())),
+    ];
+    if let Some(value) = value {
+        fields.push((String::from("value"), value.clone()));
+    }
+    fields.into_iter().collect()
+}
+
+/// Parses an array index that must be below `len`.
+fn index(token: &str, len: usize) -> Result<usize> {
+    match token.parse::<usize>() {
+        Ok(index) if index < len => Ok(index),
+        _ => Err(invalid(&format!("invalid index {}", token))),
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::Message(format!("Invalid patch: {}", reason))
+}
+
+#[test]
+fn test_diff_patch() {
+    let old: Value = vec![
+        (String::from("name"), Value::from("ann")),
+        (String::from("age"), Value::from(30)),
+        (
+            String::from("tags"),
+            Value::Array(vec![Value::from("a"), Value::from("b"), Value::from("c")]),
+        ),
+        (String::from("a/b"), Value::Null),
+    ]
+    .into_iter()
+    .collect();
+    let new: Value = vec![
+        (String::from("name"), Value::from("ann")),
+        (String::from("age"), Value::from(31)),
+        (String::from("tags"), Value::Array(vec![Value::from("a")])),
+        (String::from("email"), Value::from("ann@example.com")),
+    ]
+    .into_iter()
+    .collect();
+
+    let patch = Value::diff_patch(&old, &new);
+    let operations = patch.as_array().unwrap();
+    assert_eq!(operations.len(), 5);
+    assert!(operations.contains(
+        &vec![
+            (String::from("op"), Value::from("remove")),
+            (String::from("path"), Value::from("/a~1b")),
+        ]
+        .into_iter()
+        .collect()
+    ));
+
+    // Patches go over the wire like any other value
+    let data = crate::ser::value_to_vec(&patch).unwrap();
+    let patch = crate::de::Deserializer::new().deserialize(&data).unwrap();
+
+    let mut value = old.clone();
+    value.apply_patch(&patch).unwrap();
+    assert_eq!(value, new);
+
+    let mut value = new.clone();
+    value.apply_patch(&Value::diff_patch(&new, &old)).unwrap();
+    assert_eq!(value, old);
+
+    assert_eq!(Value::diff_patch(&old, &old), Value::Array(Vec::new()));
+    // Values compare like in `Value::diff`, so a zero's sign isn't lost
+    let mut value = Value::Double(0.0);
+    value
+        .apply_patch(&Value::diff_patch(&value, &Value::Double(-0.0)))
+        .unwrap();
+    assert!(value.as_f64().unwrap().is_sign_negative());
+    let mut value = Value::from(1);
+    value.apply_patch(&Value::diff_patch(&value, &old)).unwrap();
+    assert_eq!(value, old);
+}
+
+#[test]
+fn test_apply_patch_errors() {
+    let remove = |path: &str| {
+        Value::Array(vec![vec![
+            (String::from("op"), Value::from("remove")),
+            (String::from("path"), Value::from(path)),
+        ]
+        .into_iter()
+        .collect()])
+    };
+    let mut value = Value::Array(vec![Value::Null]);
+    assert!(value.apply_patch(&remove("/1")).is_err());
+    assert!(value.apply_patch(&remove("/0/x")).is_err());
+    assert!(value.apply_patch(&remove("")).is_err());
+    assert!(value.apply_patch(&remove("0")).is_err());
+    assert!(value.apply_patch(&Value::Null).is_err());
+    assert_eq!(value, Value::Array(vec![Value::Null]));
+
+    value.apply_patch(&remove("/0")).unwrap();
+    assert_eq!(value, Value::Array(Vec::new()));
+}
@@ -0,0 +1,81 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::common::Error;
+
+/// Location of a value as the object keys and array indices leading to it.
+///
+/// Displayed as a JSON Pointer (RFC 6901), e.g. `/users/0/name`. The root is
+/// the empty pointer.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct JsonPointer {
+    tokens: Vec<String>,
+}
+
+impl JsonPointer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    pub fn push<T: Into<String>>(&mut self, token: T) {
+        self.tokens.push(token.into());
+    }
+
+    pub fn pop(&mut self) -> Option<String> {
+        self.tokens.pop()
+    }
+}
+
+impl Display for JsonPointer {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        for token in &self.tokens {
+            formatter.write_str("/")?;
+            formatter.write_str(&token.replace('~', "~0").replace('/', "~1"))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for JsonPointer {
+    type Err = Error;
+
+    fn from_str(pointer: &str) -> Result<Self, Error> {
+        if pointer.is_empty() {
+            return Ok(Self::new());
+        }
+        match pointer.strip_prefix('/') {
+            Some(tokens) => Ok(Self {
+                tokens: tokens
+                    .split('/')
+                    .map(|token| token.replace("~1", "/").replace("~0", "~"))
+                    .collect(),
+            }),
+            None => Err(Error::Message(format!(
+                "JSON Pointer {:?} doesn't start with /",
+                pointer
+            ))),
+        }
+    }
+}
+
+#[test]
+fn test_json_pointer() {
+    let mut pointer = JsonPointer::new();
+    assert_eq!(pointer.to_string(), "");
+
+    pointer.push("users");
+    pointer.push("0");
+    pointer.push("a/b~c");
+    assert_eq!(pointer.to_string(), "/users/0/a~1b~0c");
+
+    assert_eq!("/users/0/a~1b~0c".parse(), Ok(pointer.clone()));
+    assert_eq!("".parse(), Ok(JsonPointer::new()));
+    assert!("users".parse::<JsonPointer>().is_err());
+
+    assert_eq!(pointer.pop(), Some(String::from("a/b~c")));
+    assert_eq!(pointer.tokens(), ["users", "0"]);
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use crate::common::ObjectMap;
+use crate::common::Value;
+
+/// Containers kept from dropped `Value`s for `from_slice_pooled` to decode
+/// into again, so that decoding one tree after another stops allocating
+/// once the pool has warmed up.
+///
+/// Arrays, sets, maps and (with `preserve_order`) objects are pooled, along
+/// with the copies the deserializer keeps of every object for `'^'`
+/// references. Strings, buffers and object keys are still allocated. Without
+/// `preserve_order` objects are `BTreeMap`s, which have no capacity to
+/// keep, so only their contents are pooled.
+#[derive(Default)]
+pub struct ValuePool {
+    arrays: Vec<Vec<Value>>,
+    entries: Vec<Vec<(Value, Value)>>,
+    #[cfg(feature = "preserve_order")]
+    objects: Vec<ObjectMap>,
+}
+
+impl ValuePool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a pool with `count` containers of each kind, each with room
+    /// for `capacity` elements.
+    pub fn with_capacity(count: usize, capacity: usize) -> Self {
+        Self {
+            arrays: (0..count).map(|_| Vec::with_capacity(capacity)).collect(),
+            entries: (0..count).map(|_| Vec::with_capacity(capacity)).collect(),
+            #[cfg(feature = "preserve_order")]
+            objects: (0..count)
+                .map(|_| ObjectMap::with_capacity(capacity))
+                .collect(),
+        }
+    }
+
+    /// How many containers are waiting to be reused.
+    pub fn len(&self) -> usize {
+        let len = self.arrays.len() + self.entries.len();
+        #[cfg(feature = "preserve_order")]
+        let len = len + self.objects.len();
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Empties `value`'s containers, and those in them, into the pool.
+    pub fn recycle(&mut self, value: Value) {
+        match value {
+            Value::Array(values) | Value::Set(values) => self.recycle_array(values),
+            Value::Map(entries) => self.recycle_entries(entries),
+            Value::Object(properties) => self.recycle_object(properties),
+            _ => {}
+        }
+    }
+
+    fn recycle_array(&mut self, mut values: Vec<Value>) {
+        for value in values.drain(..) {
+            self.recycle(value);
+        }
+        if values.capacity() > 0 {
+            self.arrays.push(values);
+        }
+    }
+
+    fn recycle_entries(&mut self, mut entries: Vec<(Value, Value)>) {
+        for (key, value) in entries.drain(..) {
+            self.recycle(key);
+            self.recycle(value);
+        }
+        if entries.capacity() > 0 {
+            self.entries.push(entries);
+        }
+    }
+
+    #[cfg(feature = "preserve_order")]
+    fn recycle_object(&mut self, mut properties: ObjectMap) {
+        for (_, value) in properties.drain(..) {
+            self.recycle(value);
+        }
+        if properties.capacity() > 0 {
+            self.objects.push(properties);
+        }
+    }
+
+    #[cfg(not(feature = "preserve_order"))]
+    fn recycle_object(&mut self, properties: ObjectMap) {
+        for (_, value) in properties {
+            self.recycle(value);
+        }
+    }
+
+    pub(crate) fn take_array(&mut self) -> Vec<Value> {
+        self.arrays.pop().unwrap_or_default()
+    }
+
+    pub(crate) fn take_entries(&mut self) -> Vec<(Value, Value)> {
+        self.entries.pop().unwrap_or_default()
+    }
+
+    #[cfg(feature = "preserve_order")]
+    pub(crate) fn take_object(&mut self) -> ObjectMap {
+        self.objects.pop().unwrap_or_default()
+    }
+
+    #[cfg(not(feature = "preserve_order"))]
+    pub(crate) fn take_object(&mut self) -> ObjectMap {
+        ObjectMap::new()
+    }
+
+    /// Like `value.clone()`, but with containers taken from the pool.
+    pub(crate) fn clone_value(&mut self, value: &Value) -> Value {
+        if self.is_empty() {
+            // Cloning allocates every container at its final size at once
+            return value.clone();
+        }
+        match value {
+            Value::Array(values) => Value::Array(self.clone_array(values)),
+            Value::Set(values) => Value::Set(self.clone_array(values)),
+            Value::Map(entries) => {
+                let mut copy = self.take_entries();
+                copy.reserve(entries.len());
+                for (key, value) in entries {
+                    let key = self.clone_value(key);
+                    let value = self.clone_value(value);
+                    copy.push((key, value));
+                }
+                Value::Map(copy)
+            }
+            Value::Object(properties) => {
+                let mut copy = self.take_object();
+                for (key, value) in properties {
+                    let value = self.clone_value(value);
+                    copy.insert(key.clone(), value);
+                }
+                Value::Object(copy)
+            }
+            _ => value.clone(),
+        }
+    }
+
+    fn clone_array(&mut self, values: &[Value]) -> Vec<Value> {
+        let mut copy = self.take_array();
+        copy.reserve(values.len());
+        for value in values {
+            let value = self.clone_value(value);
+            copy.push(value);
+        }
+        copy
+    }
+}
+
+impl fmt::Debug for ValuePool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValuePool")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// A `Value` decoded by `from_slice_pooled`, whose containers go back to the
+/// pool when it's dropped. Use `into_inner` to keep the value instead.
+pub struct Pooled<'p> {
+    value: Value,
+    pool: &'p mut ValuePool,
+}
+
+impl<'p> Pooled<'p> {
+    pub(crate) fn new(value: Value, pool: &'p mut ValuePool) -> Self {
+        Self { value, pool }
+    }
+
+    /// Takes the value out, its containers don't go back to the pool.
+    pub fn into_inner(mut self) -> Value {
+        std::mem::replace(&mut self.value, Value::Undefined)
+    }
+}
+
+impl Deref for Pooled<'_> {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        &self.value
+    }
+}
+
+impl DerefMut for Pooled<'_> {
+    fn deref_mut(&mut self) -> &mut Value {
+        &mut self.value
+    }
+}
+
+impl Drop for Pooled<'_> {
+    fn drop(&mut self) {
+        let value = std::mem::replace(&mut self.value, Value::Undefined);
+        self.pool.recycle(value);
+    }
+}
+
+impl fmt::Debug for Pooled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+#[test]
+fn test_from_slice_pooled() {
+    let value = Value::Array(vec![
+        Value::Set(vec![Value::from(1)]),
+        Value::Map(vec![(Value::from("a"), Value::Array(vec![Value::Null]))]),
+        Value::Object(
+            vec![(String::from("b"), Value::from(2))]
+                .into_iter()
+                .collect(),
+        ),
+    ]);
+    let data = crate::value_to_vec(&value).unwrap();
+
+    let mut pool = ValuePool::new();
+    let decoded = crate::from_slice_pooled(&data, &mut pool).unwrap();
+    assert_eq!(*decoded, value);
+    drop(decoded);
+    // The tree's containers and the deserializer's copies of them
+    let warm = pool.len();
+    assert!(warm > 0);
+
+    // Decoding again reuses them rather than growing the pool
+    let decoded = crate::from_slice_pooled(&data, &mut pool).unwrap();
+    assert_eq!(*decoded, value);
+    drop(decoded);
+    assert_eq!(pool.len(), warm);
+
+    // Values that are kept don't come back
+    let kept = crate::from_slice_pooled(&data, &mut pool)
+        .unwrap()
+        .into_inner();
+    assert_eq!(kept, value);
+    assert!(pool.len() < warm);
+
+    assert!(crate::from_slice_pooled(&data[..data.len() - 1], &mut pool).is_err());
+}
+
+#[test]
+fn test_value_pool_with_capacity() {
+    let mut pool = ValuePool::with_capacity(2, 8);
+    let len = pool.len();
+    assert!(len >= 4);
+    let array = pool.take_array();
+    assert!(array.capacity() >= 8);
+    assert!(pool.take_entries().capacity() >= 8);
+    assert_eq!(pool.len(), len - 2);
+
+    // Only containers with something to reuse are kept
+    pool.recycle(Value::Array(Vec::new()));
+    assert_eq!(pool.len(), len - 2);
+    pool.recycle(Value::Set(array));
+    assert_eq!(pool.len(), len - 1);
+}
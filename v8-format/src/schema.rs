@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+
+use crate::common::Value;
+
+/// Structural description of a `Value`, see `Value::infer_schema`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schema {
+    /// Nothing was seen to infer from (e.g. elements of an empty array),
+    /// matches any value.
+    Any,
+    Undefined,
+    Null,
+    Boolean,
+    /// `Int32`, `Uint32` and `Double`, which are all a JS number.
+    Number,
+    BigInt,
+    String,
+    Date,
+    RegExp,
+    ArrayBuffer,
+    ArrayBufferView,
+    Error,
+    /// Values without a useful structure: object references, boxed
+    /// primitives, transferred or shared buffers, WebAssembly objects and
+    /// unsupported objects.
+    Other,
+    Array(Box<Schema>),
+    /// Properties by name. Optional properties are a union with `Undefined`.
+    Object(BTreeMap<String, Schema>),
+    Map {
+        key: Box<Schema>,
+        value: Box<Schema>,
+    },
+    Set(Box<Schema>),
+    /// One of several schemas, never nested and never empty.
+    Union(Vec<Schema>),
+}
+
+impl Schema {
+    /// Combines two schemas into one that matches values of either.
+    pub fn merge(self, other: Schema) -> Schema {
+        match (self, other) {
+            (Schema::Any, other) | (other, Schema::Any) => other,
+            (a, b) if a == b => a,
+            (Schema::Array(a), Schema::Array(b)) => Schema::Array(Box::new(a.merge(*b))),
+            (Schema::Set(a), Schema::Set(b)) => Schema::Set(Box::new(a.merge(*b))),
+            (
+                Schema::Map { key, value },
+                Schema::Map {
+                    key: other_key,
+                    value: other_value,
+                },
+            ) => Schema::Map {
+                key: Box::new(key.merge(*other_key)),
+                value: Box::new(value.merge(*other_value)),
+            },
+            (Schema::Object(a), Schema::Object(mut b)) => {
+                let mut properties = BTreeMap::new();
+                for (key, schema) in a {
+                    let other = b.remove(&key).unwrap_or(Schema::Undefined);
+                    properties.insert(key, schema.merge(other));
+                }
+                for (key, schema) in b {
+                    properties.insert(key, schema.merge(Schema::Undefined));
+                }
+                Schema::Object(properties)
+            }
+            (a, b) => {
+                let mut variants = Vec::new();
+                for schema in a.into_variants().into_iter().chain(b.into_variants()) {
+                    Self::add_variant(&mut variants, schema);
+                }
+                if variants.len() == 1 {
+                    variants.pop().unwrap()
+                } else {
+                    Schema::Union(variants)
+                }
+            }
+        }
+    }
+
+    fn into_variants(self) -> Vec<Schema> {
+        match self {
+            Schema::Union(variants) => variants,
+            schema => vec![schema],
+        }
+    }
+
+    /// Adds `schema` to a union, merging it into a variant of the same kind.
+    fn add_variant(variants: &mut Vec<Schema>, schema: Schema) {
+        let kind = std::mem::discriminant(&schema);
+        match variants
+            .iter()
+            .position(|variant| std::mem::discriminant(variant) == kind)
+        {
+            Some(index) => {
+                let variant = std::mem::replace(&mut variants[index], Schema::Any);
+                variants[index] = variant.merge(schema);
+            }
+            None => variants.push(schema),
+        }
+    }
+
+    /// Checks whether `value` has the structure described by this schema.
+    ///
+    /// Objects must not have properties the schema doesn't know about, and
+    /// missing properties are treated as `undefined`.
+    pub fn validate(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Schema::Any, _) => true,
+            (Schema::Union(variants), value) => {
+                variants.iter().any(|variant| variant.validate(value))
+            }
+            (Schema::Array(elements), Value::Array(values)) => {
+                values.iter().all(|value| elements.validate(value))
+            }
+            (Schema::Set(elements), Value::Set(values)) => {
+                values.iter().all(|value| elements.validate(value))
+            }
+            (Schema::Map { key, value }, Value::Map(entries)) => entries
+                .iter()
+                .all(|(k, v)| key.validate(k) && value.validate(v)),
+            (Schema::Object(schemas), Value::Object(properties)) => {
+                properties.keys().all(|key| schemas.contains_key(key))
+                    && schemas.iter().all(|(key, schema)| {
+                        schema.validate(properties.get(key).unwrap_or(&Value::Undefined))
+                    })
+            }
+            (schema, value) => *schema == Schema::leaf(value),
+        }
+    }
+
+    fn leaf(value: &Value) -> Schema {
+        match value {
+            Value::Undefined => Schema::Undefined,
+            Value::Null => Schema::Null,
+            Value::Boolean(_) => Schema::Boolean,
+            Value::Int32(_) | Value::Uint32(_) | Value::Double(_) => Schema::Number,
+            Value::BigInt(_) => Schema::BigInt,
+            Value::String(_, _) => Schema::String,
+            Value::Date(_) => Schema::Date,
+            Value::RegExp { .. } => Schema::RegExp,
+            Value::ArrayBuffer(_) => Schema::ArrayBuffer,
+            Value::ArrayBufferView { .. } => Schema::ArrayBufferView,
+            Value::Error { .. } => Schema::Error,
+            Value::ObjectReference { .. }
+            | Value::NumberObject(_)
+            | Value::BigIntObject()
+            | Value::StringObject(_)
+            | Value::ArrayBufferTransfer { .. }
+            | Value::SharedArrayBuffer { .. }
+            | Value::WasmModule(_)
+            | Value::WasmMemory { .. }
+            | Value::HostObject(_)
+            | Value::Unsupported { .. } => Schema::Other,
+            // Containers are never leaves
+            Value::Object(_) | Value::Array(_) | Value::Map(_) | Value::Set(_) => Schema::Any,
+        }
+    }
+}
+
+impl Value {
+    /// Infers a `Schema` describing this value's structure.
+    ///
+    /// Array and set elements, and map keys and values, are merged into a
+    /// single schema (a union if they differ). Use `Schema::merge` to combine
+    /// schemas inferred from several samples.
+    pub fn infer_schema(&self) -> Schema {
+        match self {
+            Value::Array(values) => Schema::Array(Box::new(Self::infer_all(values))),
+            Value::Set(values) => Schema::Set(Box::new(Self::infer_all(values))),
+            Value::Map(entries) => Schema::Map {
+                key: Box::new(Self::infer_all(entries.iter().map(|(k, _)| k))),
+                value: Box::new(Self::infer_all(entries.iter().map(|(_, v)| v))),
+            },
+            Value::Object(properties) => Schema::Object(
+                properties
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.infer_schema()))
+                    .collect(),
+            ),
+            value => Schema::leaf(value),
+        }
+    }
+
+    fn infer_all<'a>(values: impl IntoIterator<Item = &'a Value>) -> Schema {
+        values.into_iter().fold(Schema::Any, |schema, value| {
+            schema.merge(value.infer_schema())
+        })
+    }
+}
+
+#[test]
+fn test_infer_schema() {
+    let sample: Value = vec![
+        (String::from("id"), Value::from(1)),
+        (String::from("name"), Value::from("one")),
+        (
+            String::from("tags"),
+            Value::Array(vec![Value::from("a"), Value::Null]),
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let schema = sample.infer_schema();
+    let mut properties = BTreeMap::new();
+    properties.insert(String::from("id"), Schema::Number);
+    properties.insert(String::from("name"), Schema::String);
+    properties.insert(
+        String::from("tags"),
+        Schema::Array(Box::new(Schema::Union(vec![Schema::String, Schema::Null]))),
+    );
+    assert_eq!(schema, Schema::Object(properties));
+    assert!(schema.validate(&sample));
+
+    let other: Value = vec![
+        (String::from("id"), Value::Double(2.5)),
+        (String::from("name"), Value::from("two")),
+        (String::from("tags"), Value::Array(Vec::new())),
+    ]
+    .into_iter()
+    .collect();
+    assert!(schema.validate(&other));
+
+    let wrong_type: Value = vec![
+        (String::from("id"), Value::from("3")),
+        (String::from("name"), Value::from("three")),
+        (String::from("tags"), Value::Array(Vec::new())),
+    ]
+    .into_iter()
+    .collect();
+    assert!(!schema.validate(&wrong_type));
+
+    let unknown_property: Value = vec![
+        (String::from("id"), Value::from(4)),
+        (String::from("name"), Value::from("four")),
+        (String::from("tags"), Value::Array(Vec::new())),
+        (String::from("extra"), Value::Null),
+    ]
+    .into_iter()
+    .collect();
+    assert!(!schema.validate(&unknown_property));
+}
+
+#[test]
+fn test_merge_schema() {
+    let a: Value = vec![(String::from("x"), Value::from(1))]
+        .into_iter()
+        .collect();
+    let b: Value = vec![(String::from("y"), Value::from(true))]
+        .into_iter()
+        .collect();
+
+    // Properties only seen in some samples become optional
+    let schema = Value::Array(vec![a.clone(), b.clone()]).infer_schema();
+    let mut properties = BTreeMap::new();
+    properties.insert(
+        String::from("x"),
+        Schema::Union(vec![Schema::Number, Schema::Undefined]),
+    );
+    properties.insert(
+        String::from("y"),
+        Schema::Union(vec![Schema::Boolean, Schema::Undefined]),
+    );
+    assert_eq!(schema, Schema::Array(Box::new(Schema::Object(properties))));
+    assert!(schema.validate(&Value::Array(vec![b, a, Value::Object(Default::default())])));
+    assert!(!schema.validate(&Value::Array(vec![Value::Null])));
+
+    assert_eq!(Schema::Number.merge(Schema::Number), Schema::Number);
+    assert_eq!(
+        Schema::Union(vec![Schema::Number, Schema::Null]).merge(Schema::String),
+        Schema::Union(vec![Schema::Number, Schema::Null, Schema::String])
+    );
+}
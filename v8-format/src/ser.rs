@@ -2,757 +2,2718 @@
 #![allow(unused_variables)]
 
 use crate::common::Error;
+use serde::de;
 use serde::ser;
+use serde::Deserialize;
 use serde::Serialize;
+use std::any::{Any, TypeId};
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Arc;
 
 use integer_encoding::VarInt;
 
 use crate::common::ArrayBufferViewType;
 use crate::common::ErrorType;
+use crate::common::ObjectMap;
 use crate::common::Value;
 
-pub const FORMAT_VERSION: u8 = 0xD0;
+/// The version written in the header, the one current V8 (and Node.js since
+/// v20) writes. It's read as a varint, so it has to stay below 0x80.
+pub const FORMAT_VERSION: u8 = 15;
 
-pub fn to_vec<T: Serialize>(value: T) -> Result<Vec<u8>, Error> {
-    let mut serializer = Serializer {
-        data: vec![0xFF, FORMAT_VERSION],
-        start_pos: 0,
-        current_len: None,
-        lazy_len: 0,
-    };
-    value.serialize(&mut serializer)?;
-    Ok(serializer.data)
+/// Tuple struct name used by `serialize_shared` to write a pointer's target,
+/// its only field. The `Serializer` tells pointers apart by the field's
+/// address.
+const SHARED_TOKEN: &str = "$__v8_format_shared";
+
+/// Tuple struct name used by `Value`'s `Serialize` impl for the variants
+/// serde has no equivalent of. The fields are written without tags of their
+/// own: a `char` is an object's tag, a `u8` any other byte, `u32`s and
+/// `i32`s are varints and `f64`s bare doubles. Strings and bytes are written
+/// as usual.
+const VALUE_TOKEN: &str = "$__v8_format_value";
+
+/// Unit struct name used by `Value::Null`'s `Serialize` impl.
+const NULL_TOKEN: &str = "$__v8_format_null";
+
+/// Newtype struct name used to write a string as a two-byte one, whether
+/// it's given to `serialize_str` or `collect_str`.
+const TWO_BYTE_TOKEN: &str = "$__v8_format_two_byte";
+
+/// Newtype struct names used by `TypedBytes`, one per view type. The bytes
+/// inside are written as a view of that type over its own buffer.
+const VIEW_TOKENS: [(ArrayBufferViewType, &str); 13] = [
+    (
+        ArrayBufferViewType::Int8Array,
+        "$__v8_format_view_Int8Array",
+    ),
+    (
+        ArrayBufferViewType::Uint8Array,
+        "$__v8_format_view_Uint8Array",
+    ),
+    (
+        ArrayBufferViewType::Uint8ClampedArray,
+        "$__v8_format_view_Uint8ClampedArray",
+    ),
+    (
+        ArrayBufferViewType::Int16Array,
+        "$__v8_format_view_Int16Array",
+    ),
+    (
+        ArrayBufferViewType::Uint16Array,
+        "$__v8_format_view_Uint16Array",
+    ),
+    (
+        ArrayBufferViewType::Int32Array,
+        "$__v8_format_view_Int32Array",
+    ),
+    (
+        ArrayBufferViewType::Uint32Array,
+        "$__v8_format_view_Uint32Array",
+    ),
+    (
+        ArrayBufferViewType::Float16Array,
+        "$__v8_format_view_Float16Array",
+    ),
+    (
+        ArrayBufferViewType::Float32Array,
+        "$__v8_format_view_Float32Array",
+    ),
+    (
+        ArrayBufferViewType::Float64Array,
+        "$__v8_format_view_Float64Array",
+    ),
+    (
+        ArrayBufferViewType::BigInt64Array,
+        "$__v8_format_view_BigInt64Array",
+    ),
+    (
+        ArrayBufferViewType::BigUint64Array,
+        "$__v8_format_view_BigUint64Array",
+    ),
+    (ArrayBufferViewType::DataView, "$__v8_format_view_DataView"),
+];
+
+/// Newtype struct name used by `Hooked`, whose contents are also in `HOOKED`.
+const HOOK_TOKEN: &str = "$__v8_format_hook";
+
+/// Newtype struct name used by `AsObject` to write the map in it as a plain
+/// object.
+const OBJECT_TOKEN: &str = "$__v8_format_object";
+
+/// Newtype struct name used by `AsJsSet` to write the sequence in it as a JS
+/// `Set`.
+const SET_TOKEN: &str = "$__v8_format_set";
+
+/// How many bytes a `Serializer` holds before its output spills to the
+/// heap, `V8_FORMAT_INLINE_CAPACITY` when the crate was built or 64.
+#[cfg(feature = "smallvec")]
+pub const INLINE_CAPACITY: usize = match option_env!("V8_FORMAT_INLINE_CAPACITY") {
+    Some(capacity) => parse_capacity(capacity),
+    None => 64,
+};
+
+#[cfg(feature = "smallvec")]
+const fn parse_capacity(capacity: &str) -> usize {
+    let bytes = capacity.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i].is_ascii_digit(),
+            "V8_FORMAT_INLINE_CAPACITY must be a number"
+        );
+        value = value * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    value
 }
 
-pub struct Serializer {
-    data: Vec<u8>,
-    start_pos: usize,
-    current_len: Option<usize>,
-    lazy_len: usize,
+/// Output of a `Serializer`.
+#[cfg(feature = "smallvec")]
+type Buffer = smallvec::SmallVec<[u8; INLINE_CAPACITY]>;
+#[cfg(not(feature = "smallvec"))]
+type Buffer = Vec<u8>;
+
+#[cfg(feature = "smallvec")]
+fn into_vec(data: Buffer) -> Vec<u8> {
+    data.into_vec()
 }
 
-// Old impl, non-serde one.
-impl Serializer {
-    fn write_undefined(&mut self) {
-        self.data.push('_' as u8)
+#[cfg(not(feature = "smallvec"))]
+fn into_vec(data: Buffer) -> Vec<u8> {
+    data
+}
+
+/// Encodes `value` into `buf`, which fits any varint up to 64 bits.
+fn encode_varint<V: VarInt>(value: V, buf: &mut [u8; 10]) -> &[u8] {
+    let len = value.encode_var(buf);
+    &buf[..len]
+}
+
+/// Inserts `bytes` at `at`, moving everything after it along.
+#[cfg(feature = "smallvec")]
+fn insert_bytes(data: &mut Buffer, at: usize, bytes: &[u8]) {
+    data.insert_from_slice(at, bytes);
+}
+
+#[cfg(not(feature = "smallvec"))]
+fn insert_bytes(data: &mut Buffer, at: usize, bytes: &[u8]) {
+    data.splice(at..at, bytes.iter().copied());
+}
+
+/// Where a `Serializer` writes its output, a `Buffer` unless it's only
+/// measuring.
+pub trait Output {
+    fn len(&self) -> usize;
+
+    fn push(&mut self, byte: u8);
+
+    fn extend_from_slice(&mut self, bytes: &[u8]);
+
+    /// Inserts `bytes` at `at`, moving everything after it along.
+    fn insert_from_slice(&mut self, at: usize, bytes: &[u8]);
+
+    fn truncate(&mut self, len: usize);
+
+    /// Overwrites the byte at `at`.
+    fn set(&mut self, at: usize, byte: u8);
+
+    fn reserve(&mut self, additional: usize) {}
+
+    /// Everything written so far, if it's kept. Canonical map entries can
+    /// only be sorted if it is, outputs that aren't only serve the default
+    /// options.
+    fn written(&self) -> Option<&[u8]>;
+
+    /// The serializer itself if it writes to a `Buffer`, the only kind type
+    /// hooks are given.
+    fn buffered(serializer: &mut Serializer<Self>) -> Option<&mut Serializer>
+    where
+        Self: Sized,
+    {
+        None
     }
+}
 
-    fn write_null(&mut self) {
-        self.data.push('0' as u8)
+impl Output for Buffer {
+    fn len(&self) -> usize {
+        Buffer::len(self)
     }
 
-    fn write_boolean(&mut self, value: bool) {
-        match value {
-            true => self.data.push('T' as u8),
-            false => self.data.push('F' as u8),
-        }
+    fn push(&mut self, byte: u8) {
+        Buffer::push(self, byte);
     }
 
-    fn write_int32(&mut self, value: i32) {
-        self.data.push('I' as u8);
-        self.data.extend(value.encode_var_vec());
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        Buffer::extend_from_slice(self, bytes);
     }
 
-    fn write_uint32(&mut self, value: u32) {
-        self.data.push('U' as u8);
-        self.data.extend(value.encode_var_vec());
+    fn insert_from_slice(&mut self, at: usize, bytes: &[u8]) {
+        insert_bytes(self, at, bytes);
     }
 
-    fn write_double(&mut self, value: f64) {
-        self.data.push('N' as u8);
-        self.data.extend(value.to_ne_bytes());
+    fn truncate(&mut self, len: usize) {
+        Buffer::truncate(self, len);
     }
 
-    // todo: support i128 too
-    fn write_bigint(&mut self, value: i64) {
-        self.data.push('Z' as u8);
+    fn set(&mut self, at: usize, byte: u8) {
+        self[at] = byte;
+    }
 
-        let mut flags = 0u32;
-        if value < 0 {
-            flags |= 1 << 0; // signed
-        }
+    fn reserve(&mut self, additional: usize) {
+        Buffer::reserve(self, additional);
+    }
 
-        flags |= 8 * 2; // bits
+    fn written(&self) -> Option<&[u8]> {
+        Some(self)
+    }
 
-        self.data.extend(flags.encode_var_vec());
-        self.data.extend((value as u64).to_le_bytes()); // is this right
+    fn buffered(serializer: &mut Serializer) -> Option<&mut Serializer> {
+        Some(serializer)
     }
+}
 
-    fn write_string(&mut self, value: String, utf16: bool) {
-        self.data.push(if utf16 { 'c' } else { '"' } as u8);
-        self.data.extend(value.len().encode_var_vec());
-        self.data.extend(value.as_bytes());
+/// Counts the bytes written without keeping them, for `serialized_size`.
+#[derive(Default)]
+struct Counter {
+    len: usize,
+}
+
+impl Output for Counter {
+    fn len(&self) -> usize {
+        self.len
     }
 
-    fn write_object_reference(&mut self, id: u32) {
-        self.data.push('^' as u8);
-        self.data.extend(id.encode_var_vec());
+    fn push(&mut self, byte: u8) {
+        self.len += 1;
     }
 
-    fn write_object(&mut self, value: HashMap<String, Value>) {
-        self.data.push('o' as u8);
-        let size = value.len();
-        for (k, v) in value {
-            self.write_string(k, false);
-            self.write_value(v);
-        }
-        self.data.push('{' as u8);
-        self.data.extend((size as u32).encode_var_vec());
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.len += bytes.len();
     }
 
-    fn write_array(&mut self, value: Vec<Value>) {
-        self.data.push('A' as u8);
-        let len = value.len();
-        let enc = len.encode_var_vec();
-        self.data.extend(&enc);
-        for val in value {
-            self.write_value(val);
-        }
-        self.data.push(36);
-        self.data.push(0);
-        self.data.extend(enc);
+    fn insert_from_slice(&mut self, at: usize, bytes: &[u8]) {
+        self.len += bytes.len();
     }
 
-    fn write_date(&mut self, value: f64) {
-        self.data.push('D' as u8);
-        self.data.extend(value.to_ne_bytes()); // ne or le?
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
     }
 
-    fn write_number_object(&mut self, value: f64) {
-        self.data.push('n' as u8);
-        self.data.extend(value.to_ne_bytes()); // ne or le?
+    fn set(&mut self, at: usize, byte: u8) {}
+
+    fn written(&self) -> Option<&[u8]> {
+        None
     }
+}
 
-    fn write_bigint_object(&mut self) {
-        self.data.push('z' as u8); // todo
+/// Writes into a slice while the output fits, then only counts it, for
+/// `to_slice`.
+struct SliceOutput<'a> {
+    out: &'a mut [u8],
+    len: usize,
+    /// Set once the output didn't fit, `out` no longer holds it from then on.
+    overflowed: bool,
+}
+
+impl<'a> Output for SliceOutput<'a> {
+    fn len(&self) -> usize {
+        self.len
     }
 
-    fn write_string_object(&mut self, value: String) {
-        self.data.push('s' as u8);
-        self.data.extend((value.len() as u32).encode_var_vec());
-        self.data.extend(value.as_bytes());
+    fn push(&mut self, byte: u8) {
+        self.extend_from_slice(&[byte]);
     }
 
-    fn write_regexp(&mut self, expr: String, flags: u32) {
-        self.data.push('R' as u8);
-        self.data.extend((expr.len() as u32).encode_var_vec());
-        self.data.extend(expr.as_bytes());
-        self.data.extend(flags.encode_var_vec());
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        let end = self.len + bytes.len();
+        if !self.overflowed && end <= self.out.len() {
+            self.out[self.len..end].copy_from_slice(bytes);
+        } else {
+            self.overflowed = true;
+        }
+        self.len = end;
     }
 
-    fn write_map(&mut self, value: HashMap<Value, Value>) {
-        self.data.push(';' as u8);
-        let size = value.len();
-        for (k, v) in value {
-            self.write_value(k);
-            self.write_value(v);
+    fn insert_from_slice(&mut self, at: usize, bytes: &[u8]) {
+        let end = self.len + bytes.len();
+        if !self.overflowed && end <= self.out.len() {
+            self.out.copy_within(at..self.len, at + bytes.len());
+            self.out[at..at + bytes.len()].copy_from_slice(bytes);
+        } else {
+            self.overflowed = true;
         }
-        self.data.push(':' as u8);
-        self.data.extend((size as u32).encode_var_vec());
+        self.len = end;
     }
 
-    fn write_set(&mut self, value: HashSet<Value>) {
-        self.data.push('\'' as u8);
-        let size = value.len();
-        for v in value {
-            self.write_value(v);
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    fn set(&mut self, at: usize, byte: u8) {
+        if !self.overflowed {
+            self.out[at] = byte;
         }
-        self.data.push(',' as u8);
-        self.data.extend((size as u32).encode_var_vec());
     }
 
-    fn write_array_buffer(&mut self, value: Vec<u8>) {
-        self.data.push('B' as u8);
-        self.data.extend((value.len() as u32).encode_var_vec());
-        self.data.extend(value);
+    fn written(&self) -> Option<&[u8]> {
+        match self.overflowed {
+            true => None,
+            false => Some(&self.out[..self.len]),
+        }
     }
+}
 
-    fn write_array_buffer_transfer(&mut self, transfer_id: u32) {
-        self.data.push('t' as u8);
-        self.data.extend(transfer_id.encode_var_vec());
+/// Formats into an `Output` as UTF-16.
+struct Utf16Writer<'a, W> {
+    output: &'a mut W,
+    little_endian: bool,
+}
+
+impl<'a, W: Output> fmt::Write for Utf16Writer<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for unit in s.encode_utf16() {
+            if self.little_endian {
+                self.output.extend_from_slice(&unit.to_le_bytes());
+            } else {
+                self.output.extend_from_slice(&unit.to_be_bytes());
+            }
+        }
+        Ok(())
     }
+}
 
-    fn write_array_buffer_view(
-        &mut self,
-        ty: ArrayBufferViewType,
-        byte_offset: u32,
-        byte_length: u32,
-        buffer: Vec<u8>,
-    ) {
-        self.write_array_buffer(buffer);
-        self.data.push('V' as u8);
-        self.data.push(match ty {
-            ArrayBufferViewType::Int8Array => 'b',
-            ArrayBufferViewType::Uint8Array => 'B',
-            ArrayBufferViewType::Uint8ClampedArray => 'C',
-            ArrayBufferViewType::Int16Array => 'w',
-            ArrayBufferViewType::Uint16Array => 'W',
-            ArrayBufferViewType::Int32Array => 'd',
-            ArrayBufferViewType::Uint32Array => 'D',
-            ArrayBufferViewType::Float32Array => 'f',
-            ArrayBufferViewType::Float64Array => 'F',
-            ArrayBufferViewType::BigInt64Array => 'q',
-            ArrayBufferViewType::BigUint64Array => 'Q',
-            ArrayBufferViewType::DataView => '?',
-        } as u8);
-        self.data.extend(byte_offset.encode_var_vec());
-        self.data.extend(byte_length.encode_var_vec());
+/// Formats into an `Output`, noting whether it was all ASCII.
+struct AsciiWriter<'a, W> {
+    output: &'a mut W,
+    ascii: bool,
+}
+
+impl<'a, W: Output> fmt::Write for AsciiWriter<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.ascii &= s.is_ascii();
+        self.output.extend_from_slice(s.as_bytes());
+        Ok(())
     }
+}
 
-    fn write_shared_array_buffer(&mut self, transfer_id: u32) {
-        self.data.push('u' as u8);
-        self.data.extend(transfer_id.encode_var_vec());
+pub fn to_vec<T: Serialize>(value: T) -> Result<Vec<u8>, Error> {
+    to_vec_with_options(value, SerializerOptions::default())
+}
+
+pub fn to_vec_with_options<T: Serialize>(
+    value: T,
+    options: SerializerOptions,
+) -> Result<Vec<u8>, Error> {
+    Serializer {
+        options,
+        ..Serializer::new()
     }
+    .serialize_to_vec(value)
+}
 
-    fn write_error(&mut self, ty: ErrorType, message: Option<String>, stack: Option<String>) {
-        self.data.push('r' as u8);
-        if let Some(ch) = match ty {
-            ErrorType::EvalError => Some('E'),
-            ErrorType::RangeError => Some('R'),
-            ErrorType::ReferenceError => Some('F'),
-            ErrorType::SyntaxError => Some('C'),
-            ErrorType::TypeError => Some('T'),
-            ErrorType::UriError => Some('U'),
-            ErrorType::Unknown => None,
-        } {
-            self.data.push(ch as u8);
-        }
+/// Like `to_vec`, but allocates room for `capacity` bytes upfront. Large
+/// values that would otherwise be reallocated several times as they grow can
+/// be written into a single allocation if their size is roughly known.
+pub fn to_vec_with_capacity<T: Serialize>(value: T, capacity: usize) -> Result<Vec<u8>, Error> {
+    Serializer::with_capacity(capacity).serialize_to_vec(value)
+}
 
-        if let Some(message) = message {
-            self.data.push('m' as u8);
-            self.write_string(message, false);
-        }
+/// Encodes `values` one after another, each with its own header as if
+/// written by `to_vec`, for `from_slice_many` to read back.
+///
+/// Nothing separates them, a value ends where its encoding does. The next
+/// header can't be found without decoding what comes before it, so one
+/// malformed value loses the rest. Ids, and so references, start over with
+/// each value.
+pub fn to_vec_many<T: Serialize>(values: &[T]) -> Result<Vec<u8>, Error> {
+    let mut serializer = Serializer::new();
+    for value in values {
+        serializer.start_value();
+        value.serialize(&mut serializer)?;
+    }
+    Ok(into_vec(std::mem::take(&mut serializer.data)))
+}
 
-        if let Some(stack) = stack {
-            self.data.push('s' as u8);
-            self.write_string(stack, false);
-        }
+/// The length of `to_vec(value)`, header included.
+///
+/// Some of the encoding is only known once a value is written (lengths of
+/// sequences without a size hint and which pointers are shared), so rather
+/// than duplicate the `Serializer` this runs it over an output that only
+/// counts bytes. Nothing is allocated for the output, only for the
+/// `Serializer`'s bookkeeping.
+pub fn serialized_size<T: Serialize>(value: &T) -> Result<usize, Error> {
+    let mut serializer = Serializer::with_output(Counter::default());
+    serializer.write_header();
+    value.serialize(&mut serializer)?;
+    Ok(serializer.data.len())
+}
 
-        self.data.push('.' as u8);
+/// Writes `to_vec(value)` into `out` and returns its length, or fails with
+/// `Error::BufferTooSmall` if it doesn't fit. What's in `out` then is
+/// unspecified.
+///
+/// Nothing is allocated for the output, it's written into `out` directly
+/// (lengths only known at the end are inserted there too) and once it's full
+/// only counted, for the error to say how much room was needed. The crate
+/// still needs
+/// `std`, for the `Serializer`'s bookkeeping as much as for the buffer.
+///
+/// ```
+/// let mut out = [0; 16];
+/// let len = v8_format::to_slice((1, "a"), &mut out).unwrap();
+/// assert_eq!(out[..len], v8_format::to_vec((1, "a")).unwrap());
+/// ```
+pub fn to_slice<T: Serialize>(value: T, out: &mut [u8]) -> Result<usize, Error> {
+    let capacity = out.len();
+    let mut serializer = Serializer::with_output(SliceOutput {
+        out,
+        len: 0,
+        overflowed: false,
+    });
+    serializer.write_header();
+    value.serialize(&mut serializer)?;
+    match serializer.data {
+        SliceOutput {
+            len,
+            overflowed: true,
+            ..
+        } => Err(Error::BufferTooSmall { len, capacity }),
+        SliceOutput { len, .. } => Ok(len),
     }
+}
 
-    fn write_value(&mut self, value: Value) {
-        match value {
-            Value::Undefined => self.write_undefined(),
-            Value::Null => self.write_null(),
-            Value::Boolean(value) => self.write_boolean(value),
-            Value::Int32(value) => self.write_int32(value),
-            Value::Uint32(value) => self.write_uint32(value),
-            Value::Double(value) => self.write_double(value),
-            Value::BigInt(value) => self.write_bigint(value),
-            Value::String(value, utf16) => self.write_string(value, utf16),
-            Value::ObjectReference { id } => self.write_object_reference(id),
-            Value::Object(value) => self.write_object(value),
-            Value::Array(value) => self.write_array(value),
-            Value::Date(value) => self.write_date(value),
-            Value::NumberObject(value) => self.write_number_object(value),
-            Value::BigIntObject() => self.write_bigint_object(),
-            Value::StringObject(value) => self.write_string_object(value),
-            Value::RegExp { expr, flags } => self.write_regexp(expr, flags),
-            Value::Map(value) => self.write_map(value),
-            Value::Set(value) => self.write_set(value),
-            Value::ArrayBuffer(value) => self.write_array_buffer(value),
-            Value::ArrayBufferTransfer { transfer_id } => {
-                self.write_array_buffer_transfer(transfer_id)
-            }
-            Value::ArrayBufferView {
-                ty,
-                byte_offset,
-                byte_length,
-                buffer,
-            } => self.write_array_buffer_view(ty, byte_offset, byte_length, buffer),
-            Value::SharedArrayBuffer { transfer_id } => self.write_shared_array_buffer(transfer_id),
-            Value::Error { ty, message, stack } => self.write_error(ty, message, stack),
+/// Encodes a `Value` exactly as it is, with the tag each variant stands for.
+///
+/// This writes the tree directly instead of going through serde, which is
+/// all there is to it when the data is already a `Value`. `ObjectReference`
+/// ids are written as given, counting objects in the order they're written.
+/// Fails with `Error::InvalidView` on views V8 wouldn't accept.
+pub fn value_to_vec(value: &Value) -> Result<Vec<u8>, Error> {
+    let mut serializer = Serializer::new();
+    serializer.write_header();
+    serializer.serialize(value)
+}
+
+/// Called with a value of the hooked type, see `SerializerOptions::with_type_hook`.
+type TypeHook = dyn Fn(&dyn Any, &mut Serializer) -> Result<(), Error>;
+
+pub struct SerializerOptions {
+    /// Names to write struct fields under instead of their own, e.g. to turn
+    /// `snake_case` fields into `camelCase` properties from configuration
+    /// rather than `#[serde(rename)]`. Only struct (and struct variant)
+    /// fields are renamed, map keys and enum variant names aren't.
+    pub key_map: HashMap<String, String>,
+    /// Byte order doubles and two-byte strings are written in. V8 uses the
+    /// host's, which is the default.
+    pub little_endian: bool,
+    /// Write `Shared` pointers seen before as `'^'` references. Without it
+    /// they're written out in full every time and cycles are an error.
+    pub dedup_references: bool,
+    /// Write map entries (and `AsObject` properties) sorted by their encoded
+    /// keys, so equal maps always give the same bytes whatever order they
+    /// iterate in. Struct fields keep their declaration order, which is
+    /// already stable. Maps that would need reordering can't contain `Shared`
+    /// pointers, moving them would break the references.
+    pub canonical: bool,
+    /// Pad like V8 does, for output that matches its byte for byte. V8 writes
+    /// a `'\0'` before two-byte strings whose contents would otherwise start
+    /// at an odd offset. Readers skip padding wherever a tag is expected,
+    /// whatever the format version, so it's never needed to be understood.
+    /// `ArrayBuffer` contents aren't aligned.
+    pub v8_compat: bool,
+    /// Write `None` as `null` instead of `undefined`. Either reads back as
+    /// `None`, but JS code (and `JSON.stringify`) tells them apart. `()`
+    /// stays `undefined`.
+    pub none_as_null: bool,
+    /// Fail with `Error::NonFiniteFloat` on NaN and infinities instead of
+    /// writing them, for data headed somewhere that can't represent them
+    /// (e.g. JSON). Dates and `Number` objects are written regardless.
+    pub reject_nonfinite: bool,
+    /// Write `i64`s and `u64`s that fit in 32 bits as numbers rather than
+    /// BigInts. They're smaller and usually what JS code expects, but the JS
+    /// type then depends on the value: `5i64` is `5` while
+    /// `5_000_000_000i64` is still `5000000000n`. Either reads back as the
+    /// same integer.
+    pub compact_int64: bool,
+    /// Write unit variants as just their name, like JSON does, instead of
+    /// `{ Variant: undefined }`. Adjacently tagged enums
+    /// (`#[serde(tag = "type", content = "content")]`) write their tag as a
+    /// unit variant, so they need this to become `{ type: "Variant",
+    /// content: payload }` and read back. Internally tagged ones are written
+    /// as structs either way.
+    pub unit_variants_as_strings: bool,
+    type_hooks: HashMap<TypeId, Rc<TypeHook>>,
+}
+
+impl Default for SerializerOptions {
+    fn default() -> Self {
+        Self {
+            key_map: HashMap::new(),
+            little_endian: cfg!(target_endian = "little"),
+            dedup_references: true,
+            canonical: false,
+            v8_compat: false,
+            none_as_null: false,
+            reject_nonfinite: false,
+            compact_int64: false,
+            unit_variants_as_strings: false,
+            type_hooks: HashMap::new(),
         }
     }
+}
 
-    fn serialize(mut self, value: Value) -> Vec<u8> {
-        self.write_value(value);
-        self.data
+impl SerializerOptions {
+    /// Serializes `T`s wrapped in `Hooked` with `hook` instead of their
+    /// `Serialize` impl, e.g. to write a domain type as a typed array.
+    ///
+    /// Hooks are looked up by the value's `TypeId` and handed it as `&dyn
+    /// Any`, which comes with some limits:
+    ///
+    /// - Serde neither requires values to be `'static` nor tells the
+    ///   `Serializer` what type they are, so it only sees values marked with
+    ///   `Hooked` (or `serialize_hooked` on a field). Anything else is
+    ///   written with its `Serialize` impl, hook or not.
+    /// - `T` has to be `'static`, a type with lifetime parameters can't be
+    ///   hooked.
+    /// - Types must match exactly, a hook for `Uuid` doesn't apply to a
+    ///   `Hooked<&Uuid>` or `Hooked<Box<Uuid>>`.
+    /// - Values serde buffers before writing them, like the fields of a
+    ///   tuple variant in an internally tagged enum, are written without
+    ///   their hook.
+    pub fn with_type_hook<T, F>(mut self, hook: F) -> Self
+    where
+        T: Any,
+        F: Fn(&T, &mut Serializer) -> Result<(), Error> + 'static,
+    {
+        let hook = move |value: &dyn Any, serializer: &mut Serializer| match value.downcast_ref() {
+            Some(value) => hook(value, serializer),
+            None => Err(Error::Message(format!(
+                "Hook for {} called with another type",
+                std::any::type_name::<T>()
+            ))),
+        };
+        self.type_hooks.insert(TypeId::of::<T>(), Rc::new(hook));
+        self
     }
 }
 
-impl<'a> ser::Serializer for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+/// Configures a `Serializer`, for when the defaults `to_vec` uses don't fit.
+///
+/// ```
+/// use v8_format::SerializerBuilder;
+///
+/// let mut serializer = SerializerBuilder::new().little_endian(false).build();
+/// let data = serializer.serialize_to_vec(1.5).unwrap();
+/// assert_eq!(data[3..], 1.5f64.to_be_bytes());
+/// ```
+#[derive(Default)]
+pub struct SerializerBuilder {
+    options: SerializerOptions,
+}
 
-    type SerializeSeq = Self;
-    type SerializeTuple = Self;
-    type SerializeTupleStruct = Self;
-    type SerializeTupleVariant = Self;
-    type SerializeMap = Self;
-    type SerializeStruct = Self;
-    type SerializeStructVariant = Self;
+impl SerializerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
-        match v {
-            true => self.data.push('T' as u8),
-            false => self.data.push('F' as u8),
-        }
-        Ok(())
+    /// Starts from `options` instead of the defaults.
+    pub fn options(mut self, options: SerializerOptions) -> Self {
+        self.options = options;
+        self
     }
 
-    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
-        self.data.push('I' as u8);
-        self.data.extend(v.encode_var_vec());
-        Ok(())
+    /// See `SerializerOptions::little_endian`.
+    pub fn little_endian(mut self, little_endian: bool) -> Self {
+        self.options.little_endian = little_endian;
+        self
     }
 
-    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
-        self.data.push('I' as u8);
-        self.data.extend(v.encode_var_vec());
-        Ok(())
+    /// See `SerializerOptions::dedup_references`.
+    pub fn dedup_references(mut self, dedup_references: bool) -> Self {
+        self.options.dedup_references = dedup_references;
+        self
     }
 
-    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
-        self.data.push('I' as u8);
-        self.data.extend(v.encode_var_vec());
-        Ok(())
+    /// See `SerializerOptions::canonical`.
+    pub fn canonical(mut self, canonical: bool) -> Self {
+        self.options.canonical = canonical;
+        self
     }
 
-    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
-        self.data.push('Z' as u8);
+    /// See `SerializerOptions::v8_compat`.
+    pub fn v8_compat(mut self, v8_compat: bool) -> Self {
+        self.options.v8_compat = v8_compat;
+        self
+    }
 
-        let mut flags = 0u32;
-        flags |= 1 << 0; // signed
-        flags |= 8 * 2; // bits
+    /// See `SerializerOptions::none_as_null`.
+    pub fn none_as_null(mut self, none_as_null: bool) -> Self {
+        self.options.none_as_null = none_as_null;
+        self
+    }
 
-        self.data.extend(flags.encode_var_vec());
-        self.data.extend((v as u64).to_le_bytes());
-        Ok(())
+    /// See `SerializerOptions::reject_nonfinite`.
+    pub fn reject_nonfinite(mut self, reject_nonfinite: bool) -> Self {
+        self.options.reject_nonfinite = reject_nonfinite;
+        self
     }
 
-    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
-        self.data.push('U' as u8);
-        self.data.extend(v.encode_var_vec());
-        Ok(())
+    /// See `SerializerOptions::compact_int64`.
+    pub fn compact_int64(mut self, compact_int64: bool) -> Self {
+        self.options.compact_int64 = compact_int64;
+        self
     }
 
-    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
-        self.data.push('U' as u8);
-        self.data.extend(v.encode_var_vec());
-        Ok(())
+    /// See `SerializerOptions::unit_variants_as_strings`.
+    pub fn unit_variants_as_strings(mut self, unit_variants_as_strings: bool) -> Self {
+        self.options.unit_variants_as_strings = unit_variants_as_strings;
+        self
     }
 
-    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
-        self.data.push('U' as u8);
-        self.data.extend(v.encode_var_vec());
-        Ok(())
+    pub fn build(self) -> Serializer {
+        Serializer {
+            options: self.options,
+            ..Serializer::new()
+        }
     }
+}
 
-    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
-        self.data.push('Z' as u8);
+pub struct Serializer<W = Buffer> {
+    data: W,
+    /// Tuples and structs being serialized, innermost last.
+    lens: Vec<LenFrame>,
+    /// Sequences being serialized, innermost last.
+    seqs: Vec<SeqFrame>,
+    /// Maps being serialized, innermost last.
+    maps: Vec<MapFrame>,
+    /// Id the next object written will get, V8 numbers every object in
+    /// write order and `'^'` references point back to these ids.
+    next_id: u32,
+    /// Ids of objects written through `serialize_shared`, by address.
+    ids: HashMap<usize, u32>,
+    tuple_structs: Vec<TupleStructFrame>,
+    /// Set while the fields of a `VALUE_TOKEN` tuple struct are written.
+    raw: bool,
+    /// Set while the contents of a `TWO_BYTE_TOKEN` newtype are written.
+    two_byte: bool,
+    /// Set while the contents of a `VIEW_TOKENS` newtype are written.
+    view: Option<ArrayBufferViewType>,
+    /// Where the contents of an `OBJECT_TOKEN` newtype start, a map written
+    /// there is a plain object.
+    object_at: Option<usize>,
+    /// Where the contents of a `SET_TOKEN` newtype start, a sequence written
+    /// there is a JS `Set`.
+    set_at: Option<usize>,
+    options: SerializerOptions,
+}
 
-        let mut flags = 0u32;
-        flags |= 8 * 2; // bits
+struct MapFrame {
+    /// Entries written so far.
+    len: usize,
+    /// Where each entry starts and its key ends, only kept in canonical mode.
+    entries: Vec<(usize, usize)>,
+    /// Shared pointers registered before the map began.
+    shared: usize,
+    /// Where its tag is, if it's only known once the map ends, see
+    /// `serialize_map`.
+    tag_at: Option<usize>,
+    /// Whether it's written as a plain object.
+    object: bool,
+}
 
-        self.data.extend(flags.encode_var_vec());
-        self.data.extend(v.to_le_bytes());
-        Ok(())
+struct SeqFrame {
+    /// Where the length goes once it's known, if it wasn't upfront.
+    start_pos: Option<usize>,
+    /// Elements written so far.
+    len: usize,
+    /// Whether it's written as a JS `Set`.
+    set: bool,
+}
+
+struct LenFrame {
+    /// The length given upfront.
+    len: usize,
+    /// Elements or fields written so far.
+    written: usize,
+}
+
+enum TupleStructFrame {
+    /// A regular tuple struct, written as an array.
+    Array,
+    /// A shared pointer whose target hasn't been seen yet.
+    Pointer,
+    /// A shared pointer seen for the first time, its target is written inline.
+    Shared { address: usize, id: u32 },
+    /// A shared pointer written as a `'^'` reference, its target is skipped.
+    Reference,
+    /// The parts of a `Value`, see `VALUE_TOKEN`.
+    Value,
+    /// A shared pointer written in full because references are disabled.
+    Inline { address: usize },
+}
+
+impl Serializer {
+    fn new() -> Self {
+        Self::with_capacity(0)
     }
 
-    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
-        self.data.push('N' as u8);
-        self.data.extend((v as f64).to_ne_bytes());
-        Ok(())
+    /// A serializer with the default options and room for `capacity` bytes.
+    ///
+    /// To encode many values without allocating for each, reuse one with
+    /// `reset`:
+    ///
+    /// ```
+    /// use serde::Serialize;
+    /// use v8_format::Serializer;
+    ///
+    /// let mut serializer = Serializer::with_capacity(64);
+    /// for tick in 0..3 {
+    ///     serializer.reset();
+    ///     tick.serialize(&mut serializer).unwrap();
+    ///     assert_eq!(serializer.as_bytes()[2], b'I');
+    /// }
+    /// ```
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_output(Buffer::with_capacity(capacity))
     }
 
-    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
-        self.data.push('N' as u8);
-        self.data.extend(v.to_ne_bytes());
-        Ok(())
+    /// Encodes `value` with the version header. The serializer can be reused,
+    /// ids and references don't carry over from one call to the next.
+    pub fn serialize_to_vec<T: Serialize>(&mut self, value: T) -> Result<Vec<u8>, Error> {
+        self.reset();
+        let res = value.serialize(&mut *self);
+        // Don't leave half a value behind for the next call either
+        let data = std::mem::take(&mut self.data);
+        res.map(|_| into_vec(data))
     }
 
-    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
-        self.data.push('"' as u8);
-        self.data.push(1);
-        self.data.push(v as u8);
-        Ok(())
+    /// Forgets everything written so far, keeping the options and the
+    /// buffer's capacity, and writes the header for the next value.
+    pub fn reset(&mut self) {
+        self.data.clear();
+        self.start_value();
     }
 
-    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
-        self.data.push('"' as u8);
-        self.data.extend(v.len().encode_var_vec());
-        self.data.extend(v.as_bytes());
-        Ok(())
+    /// Everything written since the last `reset`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
     }
 
-    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
-        self.data.push('B' as u8);
-        if v.len() > u32::MAX as usize {
-            return Err(Error::Message(String::from(
-                "Bytes cannot be larger than u32::MAX",
-            )));
+    fn serialize(mut self, value: &Value) -> Result<Vec<u8>, Error> {
+        self.write_value(value)?;
+        Ok(into_vec(self.data))
+    }
+}
+
+impl<W: Output> Serializer<W> {
+    fn with_output(data: W) -> Self {
+        Self {
+            data,
+            lens: Vec::new(),
+            seqs: Vec::new(),
+            maps: Vec::new(),
+            next_id: 0,
+            ids: HashMap::new(),
+            tuple_structs: Vec::new(),
+            raw: false,
+            two_byte: false,
+            view: None,
+            object_at: None,
+            set_at: None,
+            options: SerializerOptions::default(),
         }
-        self.data.extend((v.len() as u32).encode_var_vec());
-        self.data.extend(v);
-        Ok(())
     }
 
-    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-        self.data.push('_' as u8);
-        Ok(())
+    /// Clears the per-value state and writes the header for the next value,
+    /// after whatever was written before it.
+    fn start_value(&mut self) {
+        self.lens.clear();
+        self.seqs.clear();
+        self.maps.clear();
+        self.next_id = 0;
+        self.ids.clear();
+        self.tuple_structs.clear();
+        self.raw = false;
+        self.two_byte = false;
+        self.view = None;
+        self.object_at = None;
+        self.set_at = None;
+        self.write_header();
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        value.serialize(self)
+    /// Writes a varint without allocating for it like `encode_var_vec`.
+    fn write_varint<V: VarInt>(&mut self, value: V) {
+        self.data
+            .extend_from_slice(encode_varint(value, &mut [0; 10]));
     }
 
-    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
-        self.serialize_none()
+    /// Starts a top-level value, each is preceded by its own header.
+    fn write_header(&mut self) {
+        self.data.push(0xFF);
+        self.data.push(FORMAT_VERSION);
     }
 
-    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
-        // empty object
-        self.data.push('o' as u8);
-        self.data.push('{' as u8);
-        self.data.push(0);
-        Ok(())
+    /// Writes a struct field's name, see `SerializerOptions::key_map`.
+    fn serialize_field_key(&mut self, key: &'static str) -> Result<(), Error> {
+        use serde::Serializer;
+        match self.options.key_map.get(key).cloned() {
+            Some(key) => self.serialize_str(&key),
+            None => self.serialize_str(key),
+        }
     }
 
-    fn serialize_unit_variant(
-        self,
-        _name: &'static str,
-        _variant_index: u32,
-        variant: &'static str,
-    ) -> Result<Self::Ok, Self::Error> {
-        self.data.push('o' as u8);
-        self.serialize_str(variant)?;
-        self.serialize_none()?;
-        self.data.push('{' as u8);
-        self.data.push(1);
-        Ok(())
+    /// Counts an element of the innermost tuple, or a field of the innermost
+    /// struct.
+    fn count_element(&mut self) {
+        if let Some(frame) = self.lens.last_mut() {
+            frame.written += 1;
+        }
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(
-        self,
-        _name: &'static str,
-        value: &T,
-    ) -> Result<Self::Ok, Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        value.serialize(self)
+    /// Ends a tuple, whose length was written upfront. A `Serialize` impl
+    /// writing a different number of elements would leave the length
+    /// wrong, so that's an error.
+    fn end_array(&mut self) -> Result<(), Error> {
+        let LenFrame { len, written } = self.lens.pop().unwrap();
+        if written != len {
+            return Err(Error::LengthMismatch {
+                expected: len,
+                actual: written,
+            });
+        }
+        self.data.push(b'$');
+        self.data.push(0);
+        self.write_varint(len as u32);
+        Ok(())
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
-        self,
-        _name: &'static str,
-        _variant_index: u32,
-        variant: &'static str,
-        value: &T,
-    ) -> Result<Self::Ok, Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        self.data.push('o' as u8);
-        self.serialize_str(variant)?;
-        value.serialize(&mut *self)?;
-        self.data.push('{' as u8);
-        self.data.push(1);
+    /// Reorders the entries of a map or `AsObject` object by their encoded
+    /// keys, see `SerializerOptions::canonical`. `shared` is the number of
+    /// shared pointers registered before the entries were written.
+    fn sort_entries(&mut self, entries: &[(usize, usize)], shared: usize) -> Result<(), Error> {
+        let start = match entries.first() {
+            Some(&(start, _)) => start,
+            None => return Ok(()),
+        };
+        let end = self.data.len();
+        let mut ranges: Vec<_> = entries
+            .iter()
+            .enumerate()
+            .map(|(i, &(from, key_end))| (from, key_end, entries.get(i + 1).map_or(end, |e| e.0)))
+            .collect();
+
+        let data = match self.data.written() {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        let key = |&(from, key_end, _): &(usize, usize, usize)| &data[from..key_end];
+        if ranges.windows(2).all(|pair| key(&pair[0]) <= key(&pair[1])) {
+            return Ok(());
+        }
+        // Objects are numbered in write order, references to shared ones
+        // written in these entries would point elsewhere once they move.
+        if self.ids.len() > shared {
+            return Err(Error::Message(String::from(
+                "Can't sort map entries containing shared pointers",
+            )));
+        }
+        ranges.sort_by(|a, b| key(a).cmp(key(b)));
+
+        let mut sorted = Vec::with_capacity(end - start);
+        for (from, _, to) in ranges {
+            sorted.extend_from_slice(&data[from..to]);
+        }
+        self.data.truncate(start);
+        self.data.extend_from_slice(&sorted);
         Ok(())
     }
 
-    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
-        self.data.push('A' as u8);
-        self.current_len = len;
-        self.lazy_len = 0;
-        if let Some(len) = len {
-            self.data.extend((len as u32).encode_var_vec());
+    /// Writes the target of a shared pointer to `address`, or a reference to
+    /// it if it was written before.
+    fn serialize_pointee<T: ?Sized + Serialize>(
+        &mut self,
+        address: usize,
+        value: &T,
+    ) -> Result<(), Error> {
+        if !self.options.dedup_references {
+            // `ids` only holds the pointers being written here, seeing one
+            // again means it contains itself.
+            if self.ids.insert(address, 0).is_some() {
+                return Err(Error::Message(String::from(
+                    "Cycle through a shared pointer with references disabled",
+                )));
+            }
+            *self.tuple_structs.last_mut().unwrap() = TupleStructFrame::Inline { address };
+        } else if let Some(&id) = self.ids.get(&address) {
+            self.data.push(b'^');
+            self.write_varint(id);
+            *self.tuple_structs.last_mut().unwrap() = TupleStructFrame::Reference;
+            return Ok(());
         } else {
-            self.start_pos = self.data.len();
+            // Registered before the target is written so cycles back to it
+            // become references, the target's own tag takes this id.
+            let id = self.next_id;
+            self.ids.insert(address, id);
+            *self.tuple_structs.last_mut().unwrap() = TupleStructFrame::Shared { address, id };
         }
-        Ok(self)
+        value.serialize(self)
     }
 
-    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
-        self.data.push('A' as u8);
-        self.data.extend((len as u32).encode_var_vec());
-        self.current_len = Some(len);
-        Ok(self)
+    fn assign_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
     }
+}
 
-    fn serialize_tuple_struct(
-        self,
-        name: &'static str,
-        len: usize,
-    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
-        self.data.push('A' as u8);
-        self.data.extend((len as u32).encode_var_vec());
-        self.current_len = Some(len);
-        Ok(self)
+// Old impl, non-serde one.
+impl<W: Output> Serializer<W> {
+    fn write_undefined(&mut self) {
+        self.data.push(b'_')
     }
 
-    fn serialize_tuple_variant(
-        self,
-        _name: &'static str,
-        _variant_index: u32,
-        variant: &'static str,
-        len: usize,
-    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
-        self.data.push('o' as u8);
-        self.serialize_str(variant)?;
-        self.data.push('A' as u8);
-        self.data.extend((len as u32).encode_var_vec());
-        self.current_len = Some(len);
-        Ok(self)
+    fn write_null(&mut self) {
+        self.data.push(b'0')
     }
 
-    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
-        self.data.push(';' as u8);
-        self.current_len = len;
-        self.lazy_len = 0;
-        Ok(self)
+    fn write_boolean(&mut self, value: bool) {
+        match value {
+            true => self.data.push(b'T'),
+            false => self.data.push(b'F'),
+        }
     }
 
-    fn serialize_struct(
-        self,
-        name: &'static str,
-        len: usize,
-    ) -> Result<Self::SerializeStruct, Self::Error> {
-        self.data.push('o' as u8);
-        self.current_len = Some(len);
-        self.lazy_len = 0;
-        Ok(self)
+    fn write_int32(&mut self, value: i32) {
+        self.data.push(b'I');
+        self.write_varint(value);
     }
 
-    fn serialize_struct_variant(
-        self,
-        _name: &'static str,
-        _variant_index: u32,
-        variant: &'static str,
-        len: usize,
-    ) -> Result<Self::SerializeStructVariant, Self::Error> {
-        self.data.push('o' as u8);
-        self.serialize_str(variant)?;
-        self.data.push('o' as u8);
-        self.current_len = Some(len);
-        self.lazy_len = 0;
-        Ok(self)
+    fn write_uint32(&mut self, value: u32) {
+        self.data.push(b'U');
+        self.write_varint(value);
     }
-}
 
-impl<'a> ser::SerializeSeq for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
-
-    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        if self.current_len.is_none() {
-            self.lazy_len += 1;
+    // The bits are copied as they are, so `-0.0` and NaN payloads survive.
+    fn write_double(&mut self, value: f64) -> Result<(), Error> {
+        if self.options.reject_nonfinite && !value.is_finite() {
+            return Err(Error::NonFiniteFloat);
         }
-        value.serialize(&mut **self)
+        self.data.push(b'N');
+        self.write_f64(value);
+        Ok(())
     }
 
-    fn end(self) -> Result<(), Error> {
-        // It was a lazy one, so insert len at start_pos
-        if self.current_len.is_none() {
-            let mut pos = self.start_pos;
-            for byte in (self.lazy_len as u32).encode_var_vec() {
-                self.data.insert(pos, byte);
-                pos += 1;
-            }
+    // V8 copies doubles as they are in memory, so the byte order is the
+    // host's unless configured otherwise. Every platform V8 runs on in
+    // practice is little endian.
+    fn write_f64(&mut self, value: f64) {
+        if self.options.little_endian {
+            self.data.extend_from_slice(&value.to_le_bytes());
+        } else {
+            self.data.extend_from_slice(&value.to_be_bytes());
         }
-        self.data.push('$' as u8);
-        self.data.push(0);
+    }
+
+    fn write_bigint(&mut self, value: i64) {
+        self.write_bigint_digits(value < 0, value.unsigned_abs().into());
+    }
+
+    // The flags are followed by the magnitude as little endian 64-bit digits.
+    // Zero has no digits at all.
+    fn write_bigint_digits(&mut self, negative: bool, magnitude: u128) {
+        self.data.push(b'Z');
+
+        let digits = (128 - magnitude.leading_zeros()).div_ceil(64);
+        let byte_length = digits * 8;
+        debug_assert!(byte_length <= 16);
+
+        self.write_varint(bigint_flags(negative, byte_length));
         self.data
-            .extend((self.current_len.unwrap_or(self.lazy_len) as u32).encode_var_vec());
-        Ok(())
+            .extend_from_slice(&magnitude.to_le_bytes()[..byte_length as usize]);
     }
-}
 
-impl<'a> ser::SerializeTuple for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+    /// Like V8, strings are one-byte (Latin-1) when every character fits
+    /// and two-byte (UTF-16) otherwise, or when `utf16` asks for it.
+    fn write_string(&mut self, value: &str, utf16: bool) {
+        if !utf16 && value.is_ascii() {
+            self.data.push(b'"');
+            self.write_varint(value.len());
+            self.data.extend_from_slice(value.as_bytes());
+            return;
+        }
+        if !utf16 && value.chars().all(|c| c <= '\u{FF}') {
+            self.data.push(b'"');
+            self.write_varint(value.chars().count());
+            for c in value.chars() {
+                self.data.push(c as u8);
+            }
+            return;
+        }
 
-    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        value.serialize(&mut **self)
+        // The length is in bytes, two per UTF-16 code unit
+        let mut buf = [0; 10];
+        let len = encode_varint(2 * value.encode_utf16().count(), &mut buf);
+        if self.options.v8_compat && (self.data.len() + 1 + len.len()) % 2 == 1 {
+            self.data.push(0);
+        }
+        self.data.push(b'c');
+        self.data.extend_from_slice(len);
+        let mut writer = Utf16Writer {
+            output: &mut self.data,
+            little_endian: self.options.little_endian,
+        };
+        // Writing to an `Output` doesn't fail
+        let _ = fmt::Write::write_str(&mut writer, value);
     }
 
-    fn end(self) -> Result<(), Error> {
-        self.data.push('$' as u8);
-        self.data.push(0);
-        self.data
-            .extend((self.current_len.unwrap() as u32).encode_var_vec());
+    /// Like `write_string` with `utf16`, but for a string formatted straight
+    /// into the output. Its length is inserted in front once it's known.
+    fn collect_two_byte_str<T: ?Sized + fmt::Display>(&mut self, value: &T) -> Result<(), Error> {
+        use std::fmt::Write;
+
+        let tag_at = self.data.len();
+        self.data.push(b'c');
+        let start = self.data.len();
+        let mut writer = Utf16Writer {
+            output: &mut self.data,
+            little_endian: self.options.little_endian,
+        };
+        write!(writer, "{}", value).map_err(ser::Error::custom)?;
+        let mut buf = [0; 10];
+        let len = encode_varint(self.data.len() - start, &mut buf);
+        self.data.insert_from_slice(start, len);
+        if self.options.v8_compat && (start + len.len()) % 2 == 1 {
+            self.data.insert_from_slice(tag_at, &[0]);
+        }
         Ok(())
     }
-}
 
-impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+    fn write_object_reference(&mut self, id: u32) {
+        self.data.push(b'^');
+        self.write_varint(id);
+    }
 
-    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        value.serialize(&mut **self)
+    fn write_object(&mut self, value: &ObjectMap) -> Result<(), Error> {
+        self.assign_id();
+        self.data.push(b'o');
+        let size = value.len();
+        for (k, v) in value {
+            self.write_string(k, false);
+            self.write_value(v)?;
+        }
+        self.data.push(b'{');
+        self.write_varint(size as u32);
+        Ok(())
     }
 
-    fn end(self) -> Result<(), Error> {
-        self.data.push('$' as u8);
+    fn write_array(&mut self, value: &[Value]) -> Result<(), Error> {
+        self.assign_id();
+        self.data.push(b'A');
+        self.write_varint(value.len() as u32);
+        for val in value {
+            self.write_value(val)?;
+        }
+        // Same trailer as `SerializeSeq::end`: no extra properties, then the
+        // length again.
+        self.data.push(b'$');
         self.data.push(0);
-        self.data
-            .extend((self.current_len.unwrap() as u32).encode_var_vec());
+        self.write_varint(value.len() as u32);
         Ok(())
     }
-}
 
-impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+    fn write_date(&mut self, value: f64) {
+        self.assign_id();
+        self.data.push(b'D');
+        self.write_f64(value);
+    }
 
-    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        value.serialize(&mut **self)
+    fn write_number_object(&mut self, value: f64) {
+        self.assign_id();
+        self.data.push(b'n');
+        self.write_f64(value);
     }
 
-    fn end(self) -> Result<(), Error> {
-        // End the tuple
-        self.data.push('$' as u8);
-        self.data.push(0);
-        self.data
-            .extend((self.current_len.unwrap() as u32).encode_var_vec());
-        // End the enum variant object
-        self.data.push('{' as u8);
-        self.data.push(1);
-        Ok(())
+    fn write_bigint_object(&mut self) {
+        self.assign_id();
+        self.data.push(b'z'); // todo
     }
-}
 
-impl<'a> ser::SerializeMap for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+    // Wraps a tagged string, the same as a RegExp's source
+    fn write_string_object(&mut self, value: &str) {
+        self.assign_id();
+        self.data.push(b's');
+        self.write_string(value, false);
+    }
 
-    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        key.serialize(&mut **self)
+    // The source is a string with its own tag, like anywhere else
+    fn write_regexp(&mut self, expr: &str, flags: u32) {
+        self.assign_id();
+        self.data.push(b'R');
+        self.write_string(expr, false);
+        self.write_varint(flags);
     }
 
-    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        if self.current_len.is_none() {
-            self.lazy_len += 1;
+    fn write_map(&mut self, value: &[(Value, Value)]) -> Result<(), Error> {
+        self.assign_id();
+        self.data.push(b';');
+        let size = value.len();
+        for (k, v) in value {
+            self.write_value(k)?;
+            self.write_value(v)?;
         }
-        value.serialize(&mut **self)
+        // Keys and values are counted separately
+        self.data.push(b':');
+        self.write_varint(size as u32 * 2);
+        Ok(())
     }
 
-    fn end(self) -> Result<(), Error> {
-        self.data.push(':' as u8);
-        // Actually * 2 length is used here because its two values per entry.
-        self.data
-            .extend(((self.current_len.unwrap_or(self.lazy_len) * 2) as u32).encode_var_vec());
+    fn write_set(&mut self, value: &[Value]) -> Result<(), Error> {
+        self.assign_id();
+        self.data.push(b'\'');
+        let size = value.len();
+        for v in value {
+            self.write_value(v)?;
+        }
+        self.data.push(b',');
+        self.write_varint(size as u32);
         Ok(())
     }
-}
 
-impl<'a> ser::SerializeStruct for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+    fn write_array_buffer(&mut self, value: &[u8]) {
+        self.assign_id();
+        self.data.push(b'B');
+        self.write_varint(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
 
-    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        use serde::Serializer;
-        self.serialize_str(key)?;
-        value.serialize(&mut **self)
+    fn write_array_buffer_transfer(&mut self, transfer_id: u32) {
+        self.assign_id();
+        self.data.push(b't');
+        self.write_varint(transfer_id);
     }
 
-    fn end(self) -> Result<(), Error> {
-        self.data.push('{' as u8);
-        self.data
-            .extend((self.current_len.unwrap() as u32).encode_var_vec());
+    fn write_array_buffer_view(
+        &mut self,
+        ty: ArrayBufferViewType,
+        byte_offset: u32,
+        byte_length: u32,
+        buffer: &[u8],
+    ) -> Result<(), Error> {
+        if !ty.is_valid_view(byte_offset, byte_length, buffer.len()) {
+            let at = self.data.len();
+            return Err(Error::InvalidView { ty, at });
+        }
+        self.write_array_buffer(buffer);
+        self.assign_id();
+        self.data.push(b'V');
+        self.data.push(view_tag(ty));
+        self.write_varint(byte_offset);
+        self.write_varint(byte_length);
         Ok(())
     }
-}
 
-impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+    fn write_shared_array_buffer(&mut self, transfer_id: u32) {
+        self.assign_id();
+        self.data.push(b'u');
+        self.write_varint(transfer_id);
+    }
 
-    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        use serde::Serializer;
-        self.serialize_str(key)?;
-        value.serialize(&mut **self)
+    fn write_error(&mut self, ty: ErrorType, message: Option<&str>, stack: Option<&str>) {
+        self.assign_id();
+        self.data.push(b'r');
+        if let Some(tag) = error_tag(ty) {
+            self.data.push(tag);
+        }
+
+        if let Some(message) = message {
+            self.data.push(b'm');
+            self.write_string(message, false);
+        }
+
+        if let Some(stack) = stack {
+            self.data.push(b's');
+            self.write_string(stack, false);
+        }
+
+        self.data.push(b'.');
     }
 
-    fn end(self) -> Result<(), Error> {
-        // End inner object (variant's value)
-        self.data.push('{' as u8);
-        self.data
-            .extend((self.current_len.unwrap() as u32).encode_var_vec());
-        // End outer object (variant)
-        self.data.push('{' as u8);
-        self.data.push(1);
+    fn write_value(&mut self, value: &Value) -> Result<(), Error> {
+        match value {
+            Value::Undefined => self.write_undefined(),
+            Value::Null => self.write_null(),
+            Value::Boolean(value) => self.write_boolean(*value),
+            Value::Int32(value) => self.write_int32(*value),
+            Value::Uint32(value) => self.write_uint32(*value),
+            Value::Double(value) => self.write_double(*value)?,
+            Value::BigInt(value) => self.write_bigint(*value),
+            Value::String(value, utf16) => self.write_string(value, *utf16),
+            Value::ObjectReference { id } => self.write_object_reference(*id),
+            Value::Object(value) => self.write_object(value)?,
+            Value::Array(value) => self.write_array(value)?,
+            Value::Date(value) => self.write_date(*value),
+            Value::NumberObject(value) => self.write_number_object(*value),
+            Value::BigIntObject() => self.write_bigint_object(),
+            Value::StringObject(value) => self.write_string_object(value),
+            Value::RegExp { expr, flags } => self.write_regexp(expr, *flags),
+            Value::Map(value) => self.write_map(value)?,
+            Value::Set(value) => self.write_set(value)?,
+            Value::ArrayBuffer(value) => self.write_array_buffer(value),
+            Value::ArrayBufferTransfer { transfer_id } => {
+                self.write_array_buffer_transfer(*transfer_id)
+            }
+            Value::ArrayBufferView {
+                ty,
+                byte_offset,
+                byte_length,
+                buffer,
+            } => self.write_array_buffer_view(*ty, *byte_offset, *byte_length, buffer)?,
+            Value::SharedArrayBuffer { transfer_id } => {
+                self.write_shared_array_buffer(*transfer_id)
+            }
+            Value::Error { ty, message, stack } => {
+                self.write_error(*ty, message.as_deref(), stack.as_deref())
+            }
+            Value::WasmModule(wire_bytes) => self.write_wasm_module(wire_bytes),
+            Value::WasmMemory {
+                maximum_pages,
+                transfer_id,
+            } => self.write_wasm_memory(*maximum_pages, *transfer_id),
+            Value::HostObject(data) => self.write_host_object(data),
+            Value::Unsupported { tag, data } => self.write_unsupported(*tag, data),
+        }
         Ok(())
     }
-}
 
-pub trait SerializeDateExt {
-    type Ok;
-    type Error: std::error::Error;
+    // Wire bytes without a compiled module, which V8 compiles on reading
+    fn write_wasm_module(&mut self, wire_bytes: &[u8]) {
+        self.assign_id();
+        self.data.push(b'W');
+        self.data.push(b'y');
+        self.write_varint(wire_bytes.len() as u32);
+        self.data.extend_from_slice(wire_bytes);
+        self.data.push(0);
+    }
 
-    fn serialize_date<T: ?Sized>(&mut self, date: std::time::Instant) -> Result<(), Self::Error>
-    where
-        T: Serialize;
+    fn write_wasm_memory(&mut self, maximum_pages: i32, transfer_id: u32) {
+        self.assign_id();
+        self.data.push(b'm');
+        self.write_varint(maximum_pages);
+        self.write_shared_array_buffer(transfer_id);
+    }
+
+    fn write_host_object(&mut self, data: &[u8]) {
+        self.assign_id();
+        self.data.push(b'\\');
+        self.data.extend_from_slice(data);
+    }
+
+    fn write_unsupported(&mut self, tag: u8, data: &[u8]) {
+        self.assign_id();
+        self.data.push(tag);
+        self.data.extend_from_slice(data);
+    }
 }
 
-impl<'a> SerializeDateExt for &'a mut Serializer {
+impl<W: Output> ser::Serializer for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_date<T: ?Sized>(&mut self, date: std::time::Instant) -> Result<(), Self::Error>
-    where
-        T: Serialize,
-    {
-        self.data.push('D' as u8);
-        self.data
-            .extend((date.elapsed().as_millis() as f64).to_ne_bytes());
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        match v {
+            true => self.data.push(b'T'),
+            false => self.data.push(b'F'),
+        }
         Ok(())
     }
-}
 
-#[test]
-fn test_boolean() {
-    assert_eq!(to_vec(true).unwrap(), vec![0xFF, FORMAT_VERSION, 84]);
-    assert_eq!(to_vec(false).unwrap(), vec![0xFF, FORMAT_VERSION, 70]);
-}
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        self.data.push(b'I');
+        self.write_varint(v);
+        Ok(())
+    }
 
-#[test]
-fn test_numbers() {
-    // Upto 32 bit numbers, encoding is same.
-    assert_eq!(to_vec(1u32).unwrap(), vec![0xFF, FORMAT_VERSION, 85, 1]);
-    assert_eq!(to_vec(1i32).unwrap(), vec![0xFF, FORMAT_VERSION, 73, 2]);
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        self.data.push(b'I');
+        self.write_varint(v);
+        Ok(())
+    }
 
-    // For 64 bit (and above), it's different
-    assert_eq!(
-        to_vec(1u64).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 90, 16, 1, 0, 0, 0, 0, 0, 0, 0]
-    );
-    assert_eq!(
-        to_vec(1i64).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 90, 17, 1, 0, 0, 0, 0, 0, 0, 0]
-    );
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        if self.raw {
+            self.write_varint(v);
+            return Ok(());
+        }
+        self.data.push(b'I');
+        self.write_varint(v);
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        if self.options.compact_int64 {
+            if let Ok(v) = i32::try_from(v) {
+                self.write_int32(v);
+                return Ok(());
+            }
+            if let Ok(v) = u32::try_from(v) {
+                self.write_uint32(v);
+                return Ok(());
+            }
+        }
+        self.write_bigint_digits(v < 0, v.unsigned_abs().into());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        if self.raw {
+            self.data.push(v);
+            return Ok(());
+        }
+        self.data.push(b'U');
+        self.write_varint(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        self.data.push(b'U');
+        self.write_varint(v);
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        if self.raw {
+            self.write_varint(v);
+            return Ok(());
+        }
+        self.data.push(b'U');
+        self.write_varint(v);
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        if self.options.compact_int64 {
+            if let Ok(v) = u32::try_from(v) {
+                self.write_uint32(v);
+                return Ok(());
+            }
+        }
+        self.write_bigint_digits(false, v.into());
+        Ok(())
+    }
+
+    /// Always a BigInt, with as many 64-bit digits as the magnitude needs.
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        self.write_bigint_digits(v < 0, v.unsigned_abs());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        self.write_bigint_digits(false, v);
+        Ok(())
+    }
+
+    /// The format has no single precision numbers, every JS number is a
+    /// double (`Float32Array`s aside), so `v` is widened to one. That's
+    /// exact: `3.14f32` reads back as `3.14f32`, or as `3.140000104904175`
+    /// into an `f64`.
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+        self.write_double(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+        if self.raw {
+            self.write_f64(v);
+            return Ok(());
+        }
+        self.write_double(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        if self.raw {
+            self.assign_id();
+            self.data.push(v as u8);
+            return Ok(());
+        }
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        let two_byte = std::mem::take(&mut self.two_byte);
+        self.write_string(v, two_byte);
+        Ok(())
+    }
+
+    // Formats straight into the output, then inserts the length in front
+    fn collect_str<T: ?Sized + std::fmt::Display>(self, value: &T) -> Result<(), Error> {
+        use std::fmt::Write;
+
+        if std::mem::take(&mut self.two_byte) {
+            return self.collect_two_byte_str(value);
+        }
+        self.data.push(b'"');
+        let start = self.data.len();
+        let mut writer = AsciiWriter {
+            output: &mut self.data,
+            ascii: true,
+        };
+        write!(writer, "{}", value).map_err(ser::Error::custom)?;
+        if !writer.ascii {
+            // Not a one-byte string as is, write it again the slow way
+            let string = match self.data.written() {
+                Some(data) => String::from_utf8_lossy(&data[start..]).into_owned(),
+                None => value.to_string(),
+            };
+            self.data.truncate(start - 1);
+            self.write_string(&string, false);
+            return Ok(());
+        }
+        let len = self.data.len() - start;
+        self.data
+            .insert_from_slice(start, encode_varint(len, &mut [0; 10]));
+        Ok(())
+    }
+
+    // Types like `IpAddr` pick their compact form
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        if self.raw {
+            self.data.extend_from_slice(v);
+            return Ok(());
+        }
+        if let Some(ty) = self.view.take() {
+            let len = u32::try_from(v.len()).map_err(|_| {
+                Error::Message(String::from("Bytes cannot be larger than u32::MAX"))
+            })?;
+            return self.write_array_buffer_view(ty, 0, len, v);
+        }
+
+        self.assign_id();
+        self.data.push(b'B');
+        if v.len() > u32::MAX as usize {
+            return Err(Error::Message(String::from(
+                "Bytes cannot be larger than u32::MAX",
+            )));
+        }
+        self.write_varint(v.len() as u32);
+        self.data.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        self.data
+            .push(if self.options.none_as_null { '0' } else { '_' } as u8);
+        Ok(())
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    /// `()` is `undefined`, what a JS function without a return value gives.
+    /// Serde hands over only `()` itself here, empty tuples and arrays like
+    /// `[u8; 0]` go through `serialize_tuple` and become empty arrays.
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        self.data.push(b'_');
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Self::Ok, Self::Error> {
+        if name == NULL_TOKEN {
+            self.write_null();
+            return Ok(());
+        }
+
+        // empty object
+        self.assign_id();
+        self.data.push(b'o');
+        self.data.push(b'{');
+        self.data.push(0);
+        Ok(())
+    }
+
+    /// Enums are externally tagged, every variant is an object with its name
+    /// as the only key, `{ Variant: payload }`. A unit variant's payload is
+    /// `undefined`, whatever `none_as_null` says. With
+    /// `unit_variants_as_strings` it's just the name instead.
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        if self.options.unit_variants_as_strings {
+            return self.serialize_str(variant);
+        }
+        self.assign_id();
+        self.data.push(b'o');
+        self.serialize_str(variant)?;
+        self.serialize_unit()?;
+        self.data.push(b'{');
+        self.data.push(1);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        if name == TWO_BYTE_TOKEN {
+            self.two_byte = true;
+            let res = value.serialize(&mut *self);
+            self.two_byte = false;
+            return res;
+        }
+        if name.starts_with("$__v8_format_view_") {
+            if let Some(&(ty, _)) = VIEW_TOKENS.iter().find(|(_, token)| *token == name) {
+                self.view = Some(ty);
+                let res = value.serialize(&mut *self);
+                self.view = None;
+                return res;
+            }
+        }
+        if name == OBJECT_TOKEN {
+            let outer = self.object_at.replace(self.data.len());
+            let res = value.serialize(&mut *self);
+            self.object_at = outer;
+            return res;
+        }
+        if name == SET_TOKEN {
+            let outer = self.set_at.replace(self.data.len());
+            let res = value.serialize(&mut *self);
+            self.set_at = outer;
+            return res;
+        }
+        if name == HOOK_TOKEN {
+            // Only the value `serialize_hooked` set it for: serde may buffer
+            // a `Hooked` and replay it later, as something else, while
+            // another one is being serialized
+            let address = value as *const T as *const ();
+            let hooked = HOOKED.with(|hooked| match hooked.get() {
+                Some(pointer) if pointer as *const () == address => hooked.take(),
+                _ => None,
+            });
+            if let Some(hooked) = hooked {
+                // Set by `serialize_hooked`, which borrows it until this returns
+                let hooked = unsafe { &*hooked };
+                let hook = self.options.type_hooks.get(&(*hooked).type_id()).cloned();
+                if let (Some(hook), Some(serializer)) = (hook, W::buffered(self)) {
+                    return hook(hooked, serializer);
+                }
+            }
+            return value.serialize(self);
+        }
+        value.serialize(self)
+    }
+
+    /// `{ Variant: value }`, see `serialize_unit_variant`.
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        self.assign_id();
+        self.data.push(b'o');
+        self.serialize_str(variant)?;
+        value.serialize(&mut *self)?;
+        self.data.push(b'{');
+        self.data.push(1);
+        Ok(())
+    }
+
+    /// Written as an array, or a JS `Set` if it's the contents of an
+    /// `AsJsSet`.
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        let set = self.set_at.take() == Some(self.data.len());
+        self.assign_id();
+        if set {
+            // Its size only comes at the end
+            self.data.push(b'\'');
+            self.seqs.push(SeqFrame {
+                start_pos: None,
+                len: 0,
+                set,
+            });
+            return Ok(self);
+        }
+        self.data.push(b'A');
+        let start_pos = match len {
+            Some(len) => {
+                // Every element takes at least a byte
+                self.data.reserve(len);
+                self.write_varint(len as u32);
+                None
+            }
+            None => Some(self.data.len()),
+        };
+        self.seqs.push(SeqFrame {
+            start_pos,
+            len: 0,
+            set,
+        });
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.assign_id();
+        self.data.push(b'A');
+        self.write_varint(len as u32);
+        self.lens.push(LenFrame { len, written: 0 });
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        if name == SHARED_TOKEN {
+            self.tuple_structs.push(TupleStructFrame::Pointer);
+            return Ok(self);
+        }
+
+        if name == VALUE_TOKEN {
+            self.tuple_structs.push(TupleStructFrame::Value);
+            return Ok(self);
+        }
+
+        self.assign_id();
+        self.data.push(b'A');
+        self.write_varint(len as u32);
+        self.lens.push(LenFrame { len, written: 0 });
+        self.tuple_structs.push(TupleStructFrame::Array);
+        Ok(self)
+    }
+
+    /// `{ Variant: [fields] }`, see `serialize_unit_variant`.
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.assign_id();
+        self.data.push(b'o');
+        self.serialize_str(variant)?;
+        self.assign_id();
+        self.data.push(b'A');
+        self.write_varint(len as u32);
+        self.lens.push(LenFrame { len, written: 0 });
+        Ok(self)
+    }
+
+    /// Written as a JS `Map`, unless it's the contents of an `AsObject`, or
+    /// its length isn't given upfront and all its keys are strings, which
+    /// make it a plain object.
+    ///
+    /// Serde writes structs with `#[serde(flatten)]` fields as maps with no
+    /// length, this writes them as the objects they'd be without those. What
+    /// it is is only known once it ends, so its tag is written then.
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        let object = self.object_at.take() == Some(self.data.len());
+        self.assign_id();
+        let tag_at = match len {
+            None if !object => Some(self.data.len()),
+            _ => None,
+        };
+        self.data.push(if object { b'o' } else { b';' });
+        // The length hint is ignored, entries are counted as they're written
+        let shared = self.ids.len();
+        self.maps.push(MapFrame {
+            len: 0,
+            entries: Vec::new(),
+            shared,
+            tag_at,
+            object: object || tag_at.is_some(),
+        });
+        Ok(self)
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.assign_id();
+        self.data.push(b'o');
+        self.lens.push(LenFrame { len, written: 0 });
+        Ok(self)
+    }
+
+    /// `{ Variant: { fields } }`, see `serialize_unit_variant`.
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        self.assign_id();
+        self.data.push(b'o');
+        self.serialize_str(variant)?;
+        self.assign_id();
+        self.data.push(b'o');
+        self.lens.push(LenFrame { len, written: 0 });
+        Ok(self)
+    }
+}
+
+impl<W: Output> ser::SerializeSeq for &mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        if let Some(seq) = self.seqs.last_mut() {
+            seq.len += 1;
+        }
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let seq = self.seqs.pop().unwrap_or(SeqFrame {
+            start_pos: None,
+            len: 0,
+            set: false,
+        });
+        if seq.set {
+            self.data.push(b',');
+            self.write_varint(seq.len as u32);
+            return Ok(());
+        }
+        // It was a lazy one, so insert len at start_pos. Nested sequences
+        // only ever insert after it, so it's still in the right place.
+        if let Some(pos) = seq.start_pos {
+            self.data
+                .insert_from_slice(pos, encode_varint(seq.len as u32, &mut [0; 10]));
+        }
+        self.data.push(b'$');
+        self.data.push(0);
+        self.write_varint(seq.len as u32);
+        Ok(())
+    }
+}
+
+impl<W: Output> ser::SerializeTuple for &mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.count_element();
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.end_array()
+    }
+}
+
+impl<W: Output> ser::SerializeTupleStruct for &mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        match self.tuple_structs.last_mut() {
+            Some(TupleStructFrame::Pointer) => {
+                let address = value as *const T as *const u8 as usize;
+                self.serialize_pointee(address, value)
+            }
+            Some(TupleStructFrame::Reference) => Ok(()),
+            Some(TupleStructFrame::Value) => {
+                self.raw = true;
+                let res = value.serialize(&mut **self);
+                self.raw = false;
+                res
+            }
+            Some(TupleStructFrame::Array) => {
+                self.count_element();
+                value.serialize(&mut **self)
+            }
+            _ => value.serialize(&mut **self),
+        }
+    }
+
+    fn end(self) -> Result<(), Error> {
+        match self.tuple_structs.pop() {
+            Some(TupleStructFrame::Array) | None => {}
+            Some(TupleStructFrame::Pointer) | Some(TupleStructFrame::Value) => return Ok(()),
+            Some(TupleStructFrame::Shared { address, id }) => {
+                // Only objects get ids, forget targets that were primitives.
+                if self.next_id == id {
+                    self.ids.remove(&address);
+                }
+                return Ok(());
+            }
+            Some(TupleStructFrame::Reference) => return Ok(()),
+            Some(TupleStructFrame::Inline { address }) => {
+                self.ids.remove(&address);
+                return Ok(());
+            }
+        }
+
+        self.end_array()
+    }
+}
+
+impl<W: Output> ser::SerializeTupleVariant for &mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.count_element();
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.end_array()?;
+        // End the enum variant object
+        self.data.push(b'{');
+        self.data.push(1);
+        Ok(())
+    }
+}
+
+impl<W: Output> ser::SerializeMap for &mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        if let Some(frame) = self.maps.last_mut() {
+            if frame.tag_at.is_some() {
+                frame.object = frame.object && key.serialize(StrKey).unwrap_or(false);
+            }
+            if self.options.canonical {
+                let at = self.data.len();
+                frame.entries.push((at, at));
+            }
+        }
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        let at = self.data.len();
+        if let Some(frame) = self.maps.last_mut() {
+            frame.len += 1;
+            if let Some(entry) = frame.entries.last_mut() {
+                entry.1 = at;
+            }
+        }
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let frame = self.maps.pop().unwrap();
+        match frame.tag_at {
+            // Fields keep their order, like a struct's
+            Some(at) if frame.object => self.data.set(at, b'o'),
+            _ => self.sort_entries(&frame.entries, frame.shared)?,
+        }
+        if frame.object {
+            self.data.push(b'{');
+            self.write_varint(frame.len as u32);
+            return Ok(());
+        }
+        let len = frame.len;
+        self.data.push(b':');
+        // Actually * 2 length is used here because its two values per entry.
+        self.write_varint((len * 2) as u32);
+        Ok(())
+    }
+}
+
+/// Tells whether a map key is written as a string, which it has to be for
+/// the map to be written as a plain object.
+struct StrKey;
+
+fn not_a_string<T>() -> Result<T, Error> {
+    Err(Error::Message(String::from("Not a string")))
+}
+
+impl ser::Serializer for StrKey {
+    type Ok = bool;
+    type Error = Error;
+    type SerializeSeq = ser::Impossible<bool, Error>;
+    type SerializeTuple = ser::Impossible<bool, Error>;
+    type SerializeTupleStruct = ser::Impossible<bool, Error>;
+    type SerializeTupleVariant = ser::Impossible<bool, Error>;
+    type SerializeMap = ser::Impossible<bool, Error>;
+    type SerializeStruct = ser::Impossible<bool, Error>;
+    type SerializeStructVariant = ser::Impossible<bool, Error>;
+
+    fn serialize_str(self, _: &str) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn serialize_char(self, _: char) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn collect_str<T: ?Sized + fmt::Display>(self, _: &T) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    // `CharString` and the like
+    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> Result<bool, Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_i8(self, _: i8) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_i16(self, _: i16) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_i32(self, _: i32) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_i64(self, _: i64) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_u8(self, _: u8) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_u16(self, _: u16) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_u32(self, _: u32) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_u64(self, _: u64) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_f32(self, _: f32) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_f64(self, _: f64) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_none(self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_some<T>(self, _: &T) -> Result<bool, Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(false)
+    }
+
+    fn serialize_unit(self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<bool, Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(false)
+    }
+
+    // Compound keys can only be turned down with an error, which counts as
+    // `false`
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        not_a_string()
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+        not_a_string()
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        not_a_string()
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        not_a_string()
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        not_a_string()
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Error> {
+        not_a_string()
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        not_a_string()
+    }
+}
+
+impl<W: Output> ser::SerializeStruct for &mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.count_element();
+        self.serialize_field_key(key)?;
+        value.serialize(&mut **self)
+    }
+
+    // The fields actually written, a `Serialize` impl skipping some may
+    // have counted them in the length it gave
+    fn end(self) -> Result<(), Error> {
+        self.data.push(b'{');
+        let written = self.lens.pop().unwrap().written;
+        self.write_varint(written as u32);
+        Ok(())
+    }
+}
+
+impl<W: Output> ser::SerializeStructVariant for &mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.count_element();
+        self.serialize_field_key(key)?;
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        // End inner object (variant's value)
+        self.data.push(b'{');
+        let written = self.lens.pop().unwrap().written;
+        self.write_varint(written as u32);
+        // End outer object (variant)
+        self.data.push(b'{');
+        self.data.push(1);
+        Ok(())
+    }
+}
+
+pub trait SerializeDateExt {
+    type Ok;
+    type Error: std::error::Error;
+
+    fn serialize_date<T>(&mut self, date: std::time::Instant) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize;
+
+    /// Writes `time` as a `Date`, in milliseconds since the Unix epoch.
+    /// Times before the epoch are negative, both are rounded down to whole
+    /// milliseconds like JS does.
+    fn serialize_system_time(&mut self, time: std::time::SystemTime) -> Result<(), Self::Error>;
+}
+
+impl<W: Output> SerializeDateExt for &mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_date<T>(&mut self, date: std::time::Instant) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.assign_id();
+        self.data.push(b'D');
+        self.write_f64(date.elapsed().as_millis() as f64);
+        Ok(())
+    }
+
+    fn serialize_system_time(&mut self, time: std::time::SystemTime) -> Result<(), Self::Error> {
+        let millis = match time.duration_since(std::time::UNIX_EPOCH) {
+            Ok(since) => since.as_millis() as f64,
+            Err(error) => {
+                let before = error.duration().as_nanos();
+                -(before.div_ceil(1_000_000) as f64)
+            }
+        };
+        self.write_date(millis);
+        Ok(())
+    }
+}
+
+/// Written like `value_to_vec` writes it, but with the `Serializer`'s options
+/// like any other value, so e.g. canonical output sorts objects' properties.
+///
+/// Variants with a serde equivalent are written through it: objects like
+/// `AsObject` maps, arrays as sequences, maps as maps, sets like `AsJsSet`
+/// and so on. The rest (`null`, dates, regexps, references, etc.) are
+/// written as `VALUE_TOKEN` tuple structs, which other serializers see as a
+/// tuple of the tags and numbers making them up.
+impl Serialize for Value {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use Leaf::*;
+
+        match self {
+            Value::Undefined => serializer.serialize_unit(),
+            Value::Null => serializer.serialize_unit_struct(NULL_TOKEN),
+            Value::Boolean(value) => serializer.serialize_bool(*value),
+            Value::Int32(value) => serializer.serialize_i32(*value),
+            Value::Uint32(value) => serializer.serialize_u32(*value),
+            Value::Double(value) => serializer.serialize_f64(*value),
+            Value::BigInt(value) => BigInt((*value).into()).serialize(serializer),
+            Value::String(value, false) => serializer.serialize_str(value),
+            Value::String(value, true) => {
+                serializer.serialize_newtype_struct(TWO_BYTE_TOKEN, value.as_str())
+            }
+            Value::ObjectReference { id } => serialize_leaf(serializer, &[Byte(b'^'), Varint(*id)]),
+            Value::Object(value) => serialize_as_object(value, serializer),
+            Value::Array(value) => serializer.collect_seq(value),
+            Value::Date(value) => serialize_leaf(serializer, &[Tag(b'D'), Double(*value)]),
+            Value::NumberObject(value) => serialize_leaf(serializer, &[Tag(b'n'), Double(*value)]),
+            Value::BigIntObject() => serialize_leaf(serializer, &[Tag(b'z')]),
+            Value::StringObject(value) => serialize_leaf(serializer, &[Tag(b's'), Str(value)]),
+            Value::RegExp { expr, flags } => {
+                serialize_leaf(serializer, &[Tag(b'R'), Str(expr), Varint(*flags)])
+            }
+            Value::Map(value) => serializer.collect_map(value.iter().map(|(k, v)| (k, v))),
+            Value::Set(value) => serialize_as_js_set(value, serializer),
+            Value::ArrayBuffer(value) => serializer.serialize_bytes(value),
+            Value::ArrayBufferTransfer { transfer_id } => {
+                serialize_leaf(serializer, &[Tag(b't'), Varint(*transfer_id)])
+            }
+            Value::ArrayBufferView {
+                ty,
+                byte_offset,
+                byte_length,
+                buffer,
+            } => {
+                if !ty.is_valid_view(*byte_offset, *byte_length, buffer.len()) {
+                    return Err(ser::Error::custom(format_args!("Invalid {:?} view", ty)));
+                }
+                serialize_leaf(
+                    serializer,
+                    &[
+                        Tag(b'B'),
+                        Varint(buffer.len() as u32),
+                        Bytes(buffer),
+                        Tag(b'V'),
+                        Byte(view_tag(*ty)),
+                        Varint(*byte_offset),
+                        Varint(*byte_length),
+                    ],
+                )
+            }
+            Value::SharedArrayBuffer { transfer_id } => {
+                serialize_leaf(serializer, &[Tag(b'u'), Varint(*transfer_id)])
+            }
+            Value::Error { ty, message, stack } => {
+                let mut fields = vec![Tag(b'r')];
+                fields.extend(error_tag(*ty).map(Byte));
+                if let Some(message) = message {
+                    fields.extend([Byte(b'm'), Str(message)]);
+                }
+                if let Some(stack) = stack {
+                    fields.extend([Byte(b's'), Str(stack)]);
+                }
+                fields.push(Byte(b'.'));
+                serialize_leaf(serializer, &fields)
+            }
+            Value::WasmModule(wire_bytes) => serialize_leaf(
+                serializer,
+                &[
+                    Tag(b'W'),
+                    Byte(b'y'),
+                    Varint(wire_bytes.len() as u32),
+                    Bytes(wire_bytes),
+                    Byte(0),
+                ],
+            ),
+            Value::WasmMemory {
+                maximum_pages,
+                transfer_id,
+            } => serialize_leaf(
+                serializer,
+                &[
+                    Tag(b'm'),
+                    Int(*maximum_pages),
+                    Tag(b'u'),
+                    Varint(*transfer_id),
+                ],
+            ),
+            Value::HostObject(data) => serialize_leaf(serializer, &[Tag(b'\\'), Bytes(data)]),
+            Value::Unsupported { tag, data } => {
+                serialize_leaf(serializer, &[Tag(*tag), Bytes(data)])
+            }
+        }
+    }
+}
+
+/// A field of a `VALUE_TOKEN` tuple struct.
+enum Leaf<'a> {
+    /// An object's tag, which gives it an id.
+    Tag(u8),
+    /// Any other byte.
+    Byte(u8),
+    Varint(u32),
+    Int(i32),
+    Double(f64),
+    /// A string, with its own tag like anywhere else.
+    Str(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Serialize for Leaf<'a> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Leaf::Tag(tag) => serializer.serialize_char(tag as char),
+            Leaf::Byte(byte) => serializer.serialize_u8(byte),
+            Leaf::Varint(value) => serializer.serialize_u32(value),
+            Leaf::Int(value) => serializer.serialize_i32(value),
+            Leaf::Double(value) => serializer.serialize_f64(value),
+            Leaf::Str(value) => serializer.serialize_str(value),
+            Leaf::Bytes(value) => serializer.serialize_bytes(value),
+        }
+    }
+}
+
+fn serialize_leaf<S: ser::Serializer>(serializer: S, fields: &[Leaf]) -> Result<S::Ok, S::Error> {
+    use ser::SerializeTupleStruct;
+
+    let mut state = serializer.serialize_tuple_struct(VALUE_TOKEN, fields.len())?;
+    for field in fields {
+        state.serialize_field(field)?;
+    }
+    state.end()
+}
+
+/// The byte after a view's `'V'` tag.
+fn view_tag(ty: ArrayBufferViewType) -> u8 {
+    (match ty {
+        ArrayBufferViewType::Int8Array => 'b',
+        ArrayBufferViewType::Uint8Array => 'B',
+        ArrayBufferViewType::Uint8ClampedArray => 'C',
+        ArrayBufferViewType::Int16Array => 'w',
+        ArrayBufferViewType::Uint16Array => 'W',
+        ArrayBufferViewType::Int32Array => 'd',
+        ArrayBufferViewType::Uint32Array => 'D',
+        ArrayBufferViewType::Float16Array => 'h',
+        ArrayBufferViewType::Float32Array => 'f',
+        ArrayBufferViewType::Float64Array => 'F',
+        ArrayBufferViewType::BigInt64Array => 'q',
+        ArrayBufferViewType::BigUint64Array => 'Q',
+        ArrayBufferViewType::DataView => '?',
+    }) as u8
+}
+
+/// The byte after an error's `'r'` tag, if its type isn't the default.
+fn error_tag(ty: ErrorType) -> Option<u8> {
+    match ty {
+        ErrorType::EvalError => Some(b'E'),
+        ErrorType::RangeError => Some(b'R'),
+        ErrorType::ReferenceError => Some(b'F'),
+        ErrorType::SyntaxError => Some(b'C'),
+        ErrorType::TypeError => Some(b'T'),
+        ErrorType::UriError => Some(b'U'),
+        ErrorType::Unknown => None,
+    }
+}
+
+/// V8's BigInt bitfield: the sign in the lowest bit and the byte length of
+/// the digits above it, e.g. 16 for a single positive digit.
+fn bigint_flags(negative: bool, byte_length: u32) -> u32 {
+    (byte_length << 1) | negative as u32
+}
+
+/// Bytes written as a typed array (or `DataView`) over its own buffer, where
+/// `serialize_bytes` would give a plain `ArrayBuffer`.
+///
+/// The length must be a multiple of the type's element size, otherwise
+/// serializing fails. Other serializers see just the bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypedBytes {
+    pub ty: ArrayBufferViewType,
+    pub data: Vec<u8>,
+}
+
+impl Serialize for TypedBytes {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (_, token) = VIEW_TOKENS
+            .iter()
+            .find(|(ty, _)| *ty == self.ty)
+            .expect("every view type has a token");
+        serializer.serialize_newtype_struct(token, &RawBytes(&self.data))
+    }
+}
+
+/// An integer written as a BigInt whatever its value, even with
+/// `SerializerOptions::compact_int64`.
+///
+/// It's serialized as an `i128`, which is always a BigInt, and other
+/// serializers see just that.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BigInt(pub i128);
+
+impl Serialize for BigInt {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i128(self.0)
+    }
+}
+
+/// Characters written as one two-byte string, where a `[char]` would be an
+/// array of one character strings.
+///
+/// They're encoded as UTF-16 as they're written, without collecting them
+/// into a `String` first. Other serializers see a string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CharString<'a>(pub &'a [char]);
+
+impl<'a> Serialize for CharString<'a> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(TWO_BYTE_TOKEN, &Chars(self.0))
+    }
+}
+
+/// The characters of a `CharString`, handed to `collect_str`.
+struct Chars<'a>(&'a [char]);
+
+impl<'a> fmt::Display for Chars<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        use fmt::Write;
+        self.0.iter().try_for_each(|&c| formatter.write_char(c))
+    }
+}
+
+impl<'a> Serialize for Chars<'a> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// An IP address written as its octets in an `ArrayBuffer`, 4 bytes for
+/// IPv4 and 16 for IPv6. `IpAddr` itself is written as an object naming
+/// the version, holding the octets as an array of numbers.
+///
+/// Any serializer sees the octets as bytes, and it reads back from them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AsBinaryIp(pub IpAddr);
+
+impl Serialize for AsBinaryIp {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            IpAddr::V4(ip) => serializer.serialize_bytes(&ip.octets()),
+            IpAddr::V6(ip) => serializer.serialize_bytes(&ip.octets()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AsBinaryIp {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = AsBinaryIp;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("4 or 16 bytes")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<AsBinaryIp, E> {
+                if let Ok(octets) = <[u8; 4]>::try_from(v) {
+                    Ok(AsBinaryIp(IpAddr::from(octets)))
+                } else if let Ok(octets) = <[u8; 16]>::try_from(v) {
+                    Ok(AsBinaryIp(IpAddr::from(octets)))
+                } else {
+                    Err(E::invalid_length(v.len(), &self))
+                }
+            }
+        }
+
+        deserializer.deserialize_bytes(Visitor)
+    }
+}
+
+/// Bytes handed to `serialize_bytes`, a `&[u8]` would be a sequence.
+struct RawBytes<'a>(&'a [u8]);
+
+impl<'a> Serialize for RawBytes<'a> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Values that can be written once and referenced from several places.
+///
+/// Serde serializes smart pointers as their contents, so the `Serializer`
+/// can't tell that two fields point to the same allocation. Pointers wrapped
+/// in `Shared` or fields using `serialize_shared` are tracked by address: the
+/// first occurrence is written in full and later ones (including cycles back
+/// to an object still being written) become `'^'` object references.
+pub trait SerializeShared {
+    fn serialize_shared<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+}
+
+impl<T: ?Sized + Serialize> SerializeShared for Rc<T> {
+    fn serialize_shared<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_pointer(&**self, serializer)
+    }
+}
+
+impl<T: ?Sized + Serialize> SerializeShared for Arc<T> {
+    fn serialize_shared<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_pointer(&**self, serializer)
+    }
+}
+
+impl<T: ?Sized + SerializeShared> SerializeShared for &T {
+    fn serialize_shared<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize_shared(serializer)
+    }
+}
+
+/// Writes `value`, the target of a pointer, as the field of a `SHARED_TOKEN`
+/// tuple struct. It has to be the pointer's own target, not a copy, since
+/// that's identified by its address. Other serializers just see a tuple
+/// struct with a single field.
+fn serialize_pointer<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: ?Sized + Serialize,
+    S: ser::Serializer,
+{
+    use ser::SerializeTupleStruct;
+
+    let mut state = serializer.serialize_tuple_struct(SHARED_TOKEN, 1)?;
+    state.serialize_field(value)?;
+    state.end()
+}
+
+impl<T: SerializeShared> SerializeShared for Option<T> {
+    fn serialize_shared<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Some(value) => value.serialize_shared(serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+/// For use with `#[serde(serialize_with = "v8_format::serialize_shared")]`.
+pub fn serialize_shared<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: SerializeShared,
+    S: ser::Serializer,
+{
+    value.serialize_shared(serializer)
+}
+
+/// Wraps a map so it's written as a plain JS object instead of a `Map`.
+///
+/// Serde maps (`HashMap`, `BTreeMap`, ...) become a JS `Map`, which keeps
+/// keys of any type, unless they're written without a length, see
+/// `Serializer::serialize_map`. Structs become plain objects, and so does a map wrapped
+/// in `AsObject`, whose keys should then be strings or integers. Other
+/// serializers see the map as it is.
+pub struct AsObject<T>(pub T);
+
+impl<T, K, V> Serialize for AsObject<T>
+where
+    for<'a> &'a T: IntoIterator<Item = (&'a K, &'a V)>,
+    K: Serialize,
+    V: Serialize,
+{
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_as_object(&self.0, serializer)
+    }
+}
+
+/// For use with `#[serde(serialize_with = "v8_format::serialize_as_object")]`,
+/// see `AsObject`.
+pub fn serialize_as_object<T, K, V, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    for<'a> &'a T: IntoIterator<Item = (&'a K, &'a V)>,
+    K: Serialize,
+    V: Serialize,
+    S: ser::Serializer,
+{
+    serializer.serialize_newtype_struct(OBJECT_TOKEN, &Entries(value))
+}
+
+/// The entries of a map, serialized as one.
+struct Entries<'a, T>(&'a T);
+
+impl<'a, T, K, V> Serialize for Entries<'a, T>
+where
+    for<'b> &'b T: IntoIterator<Item = (&'b K, &'b V)>,
+    K: Serialize,
+    V: Serialize,
+{
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0)
+    }
+}
+
+/// Wraps a collection so it's written as a JS `Set` instead of an array.
+///
+/// Serde has no notion of sets, `HashSet` and `BTreeSet` are sequences like
+/// `Vec` and become arrays. Elements aren't deduplicated, a `Set` decoded by
+/// V8 drops repeated primitives. Other serializers see a sequence of the
+/// elements.
+pub struct AsJsSet<T>(pub T);
+
+impl<T, V> Serialize for AsJsSet<T>
+where
+    for<'a> &'a T: IntoIterator<Item = &'a V>,
+    V: Serialize,
+{
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_as_js_set(&self.0, serializer)
+    }
+}
+
+/// For use with `#[serde(serialize_with = "v8_format::serialize_as_js_set")]`,
+/// see `AsJsSet`.
+pub fn serialize_as_js_set<T, V, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    for<'a> &'a T: IntoIterator<Item = &'a V>,
+    V: Serialize,
+    S: ser::Serializer,
+{
+    serializer.serialize_newtype_struct(SET_TOKEN, &Elements(value))
+}
+
+/// The elements of a collection, serialized as a sequence.
+struct Elements<'a, T>(&'a T);
+
+impl<'a, T, V> Serialize for Elements<'a, T>
+where
+    for<'b> &'b T: IntoIterator<Item = &'b V>,
+    V: Serialize,
+{
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0)
+    }
+}
+
+thread_local! {
+    /// The value in the `Hooked` being serialized, for the `Serializer` to
+    /// hand to its hook. Serde only passes values on as generic `T`s, which
+    /// can't be turned into `&dyn Any`.
+    static HOOKED: Cell<Option<*const dyn Any>> = const { Cell::new(None) };
+}
+
+/// Wraps a value so it's written with the hook registered for its type, see
+/// `SerializerOptions::with_type_hook`. Other serializers (and ours without
+/// a hook for it) see the value as it is.
+pub struct Hooked<T>(pub T);
+
+impl<T: Any + Serialize> Serialize for Hooked<T> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_hooked(&self.0, serializer)
+    }
+}
+
+/// For use with `#[serde(serialize_with = "v8_format::serialize_hooked")]`,
+/// see `Hooked`.
+pub fn serialize_hooked<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Any + Serialize,
+    S: ser::Serializer,
+{
+    // Puts back the outer value, if any, however this returns
+    struct Restore(Option<*const dyn Any>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            HOOKED.with(|hooked| hooked.set(self.0));
+        }
+    }
+
+    let outer = HOOKED.with(|hooked| hooked.replace(Some(value as &dyn Any as *const dyn Any)));
+    let _restore = Restore(outer);
+    serializer.serialize_newtype_struct(HOOK_TOKEN, value)
+}
+
+/// Wraps a pointer so it's written with `serialize_shared`.
+pub struct Shared<T>(pub T);
+
+impl<T: SerializeShared> Serialize for Shared<T> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_shared(serializer)
+    }
+}
+
+#[test]
+fn test_boolean() {
+    assert_eq!(to_vec(true).unwrap(), vec![0xFF, FORMAT_VERSION, 84]);
+    assert_eq!(to_vec(false).unwrap(), vec![0xFF, FORMAT_VERSION, 70]);
+}
+
+#[test]
+#[allow(clippy::approx_constant)]
+fn test_numbers() {
+    // Upto 32 bit numbers, encoding is same.
+    assert_eq!(to_vec(1u32).unwrap(), vec![0xFF, FORMAT_VERSION, 85, 1]);
+    assert_eq!(to_vec(1i32).unwrap(), vec![0xFF, FORMAT_VERSION, 73, 2]);
+
+    // For 64 bit (and above), it's different
+    assert_eq!(
+        to_vec(1u64).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 90, 16, 1, 0, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        to_vec(1i64).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 90, 16, 1, 0, 0, 0, 0, 0, 0, 0]
+    );
 
     // For floats too it is different encoding, but it's at least same for both f32 and f64.
     // But there's a quirk with this format: it uses native endianness
@@ -765,290 +2726,2185 @@ fn test_numbers() {
 }
 
 #[test]
-fn test_char() {
-    assert_eq!(to_vec('h').unwrap(), vec![0xFF, FORMAT_VERSION, 34, 1, 104]);
+#[allow(clippy::approx_constant)]
+fn test_f32() {
+    let data = to_vec(3.14f32).unwrap();
+    assert_eq!(data, to_vec(3.14f32 as f64).unwrap());
+    assert_eq!(crate::from_slice::<f32>(&data), Ok(3.14f32));
+    assert_eq!(crate::from_slice::<f64>(&data), Ok(3.140000104904175));
+    assert_ne!(data, to_vec(3.14f64).unwrap());
+}
+
+#[test]
+fn test_bigint() {
+    // Compared against `v8.serialize()` in Node.js, minus its header.
+    // 0n
+    assert_eq!(to_vec(0u64).unwrap(), vec![0xFF, FORMAT_VERSION, 90, 0]);
+    assert_eq!(to_vec(0i64).unwrap(), vec![0xFF, FORMAT_VERSION, 90, 0]);
+    // -1n
+    assert_eq!(
+        to_vec(-1i64).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 90, 17, 1, 0, 0, 0, 0, 0, 0, 0]
+    );
+    // 2n ** 64n - 1n
+    assert_eq!(
+        to_vec(u64::MAX).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            90,
+            16,
+            255,
+            255,
+            255,
+            255,
+            255,
+            255,
+            255,
+            255
+        ]
+    );
+    // 1n and 256n, the flags are the byte length (8) shifted past the sign
+    assert_eq!(
+        to_vec(1u64).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 90, 16, 1, 0, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        to_vec(256u64).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 90, 16, 0, 1, 0, 0, 0, 0, 0, 0]
+    );
+    for value in [1, 256, u64::MAX] {
+        let decoded = crate::from_slice::<u64>(&to_vec(value).unwrap());
+        assert_eq!(decoded, Ok(value));
+    }
+    // -(2n ** 63n)
+    assert_eq!(
+        to_vec(i64::MIN).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 90, 17, 0, 0, 0, 0, 0, 0, 0, 128]
+    );
+
+    // The manual path agrees
+    let mut serializer = Serializer::new();
+    serializer.write_bigint(-1);
+    serializer.write_bigint(0);
+    assert_eq!(
+        serializer.as_bytes(),
+        [90, 17, 1, 0, 0, 0, 0, 0, 0, 0, 90, 0]
+    );
+}
+
+#[test]
+fn test_compact_int64() {
+    let mut serializer = SerializerBuilder::new().compact_int64(true).build();
+    let data = serializer.serialize_to_vec(5i64).unwrap();
+    assert_eq!(data, [0xFF, FORMAT_VERSION, b'I', 10]);
+    assert_eq!(crate::from_slice::<Value>(&data), Ok(Value::Int32(5)));
+    assert_eq!(crate::from_slice::<i64>(&data), Ok(5));
+
+    // Past `i32` but within `u32`
+    let data = serializer.serialize_to_vec(3_000_000_000i64).unwrap();
+    assert_eq!(
+        crate::from_slice::<Value>(&data),
+        Ok(Value::Uint32(3_000_000_000))
+    );
+    let data = serializer.serialize_to_vec(5u64).unwrap();
+    assert_eq!(data, [0xFF, FORMAT_VERSION, b'U', 5]);
+
+    for value in [5_000_000_000i64, -5_000_000_000, i64::MIN] {
+        let data = serializer.serialize_to_vec(value).unwrap();
+        assert_eq!(data[2], b'Z');
+        assert_eq!(crate::from_slice::<Value>(&data), Ok(Value::BigInt(value)));
+    }
+    let data = serializer.serialize_to_vec(u64::MAX).unwrap();
+    assert_eq!(crate::from_slice::<u64>(&data), Ok(u64::MAX));
+
+    // `BigInt` isn't affected, nor are BigInt values
+    let data = serializer.serialize_to_vec(BigInt(5)).unwrap();
+    assert_eq!(crate::from_slice::<Value>(&data), Ok(Value::BigInt(5)));
+    let data = serializer.serialize_to_vec(Value::BigInt(5)).unwrap();
+    assert_eq!(crate::from_slice::<Value>(&data), Ok(Value::BigInt(5)));
+}
+
+#[test]
+fn test_char() {
+    assert_eq!(to_vec('h').unwrap(), vec![0xFF, FORMAT_VERSION, 34, 1, 104]);
+    // Not truncated to a byte
+    assert_eq!(to_vec('€').unwrap(), to_vec("€").unwrap());
+}
+
+#[test]
+fn test_char_string() {
+    let chars: Vec<char> = "hé€".chars().collect();
+    let data = to_vec(CharString(&chars)).unwrap();
+    assert_eq!(
+        data,
+        [0xFF, FORMAT_VERSION, b'c', 6, b'h', 0, 0xE9, 0, 0xAC, 0x20]
+    );
+    assert_eq!(
+        crate::from_slice_checked(&data),
+        Ok(Value::String(String::from("hé€"), true))
+    );
+    // The same string as `&str`, in one string rather than an array of them
+    assert_eq!(crate::from_slice::<String>(&data), Ok(String::from("hé€")));
+    assert_eq!(
+        to_vec(Value::String(String::from("hé€"), true)).unwrap(),
+        data
+    );
+    assert_eq!(serialized_size(&CharString(&chars)).unwrap(), data.len());
+
+    // Padded like other two-byte strings, which here would start at 7
+    let mut serializer = SerializerBuilder::new().v8_compat(true).build();
+    let data = serializer
+        .serialize_to_vec((true, CharString(&chars)))
+        .unwrap();
+    assert_eq!(data[2..8], [b'A', 2, b'T', 0, b'c', 6]);
+    assert_eq!(
+        data,
+        serializer
+            .serialize_to_vec((true, Value::String(String::from("hé€"), true)))
+            .unwrap()
+    );
+
+    let data = to_vec(&chars).unwrap();
+    assert_eq!(crate::from_slice(&data), Ok(chars.clone()));
+
+    // Only a string anywhere else
+    #[cfg(feature = "json")]
+    assert_eq!(
+        serde_json::to_string(&CharString(&chars)).unwrap(),
+        r#""hé€""#
+    );
+}
+
+#[test]
+fn test_str() {
+    assert_eq!(
+        to_vec("test").unwrap(),
+        vec![0xFF, FORMAT_VERSION, 34, 4, 116, 101, 115, 116]
+    );
+    // One byte per character while they're all Latin-1, UTF-16 after that
+    assert_eq!(
+        to_vec("é").unwrap(),
+        vec![0xFF, FORMAT_VERSION, b'"', 1, 0xE9]
+    );
+    assert_eq!(
+        to_vec("€").unwrap(),
+        vec![0xFF, FORMAT_VERSION, b'c', 2, 0xAC, 0x20]
+    );
+    let data = to_vec(format_args!("{}€", 1)).unwrap();
+    assert_eq!(crate::from_slice(&data), Ok(String::from("1€")));
+}
+
+#[test]
+fn test_bytes() {
+    use serde_bytes::Bytes;
+
+    let bytes: [u8; 3] = [1, 2, 3];
+    assert_eq!(
+        to_vec(Bytes::new(&bytes)).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 66, 3, 1, 2, 3]
+    );
+}
+
+#[test]
+fn test_undefined() {
+    assert_eq!(
+        to_vec(None as Option<()>).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 95]
+    );
+    assert_eq!(to_vec(()).unwrap(), vec![0xFF, FORMAT_VERSION, 95]);
+}
+
+#[test]
+fn test_some() {
+    assert_eq!(to_vec(Some(true)).unwrap(), vec![0xFF, FORMAT_VERSION, 84]);
+}
+
+#[test]
+fn test_unit_struct() {
+    #[derive(Serialize)]
+    struct Unit;
+    let unit = Unit;
+    assert_eq!(
+        to_vec(unit).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 111, 123, 0]
+    );
+}
+
+#[test]
+fn test_unit_variant() {
+    #[derive(Serialize)]
+    enum Enum {
+        Variant,
+    }
+
+    assert_eq!(
+        to_vec(Enum::Variant).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            111,
+            34,
+            7,
+            86,
+            97,
+            114,
+            105,
+            97,
+            110,
+            116,
+            95,
+            123,
+            1
+        ]
+    )
+}
+
+#[test]
+fn test_newtype_struct() {
+    #[derive(Serialize)]
+    struct Newtype(bool);
+
+    assert_eq!(
+        to_vec(Newtype(true)).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 84]
+    );
+}
+
+#[test]
+fn test_newtype_variant() {
+    #[derive(Serialize)]
+    enum Enum {
+        Variant(bool),
+    }
+
+    assert_eq!(
+        to_vec(Enum::Variant(true)).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            111,
+            34,
+            7,
+            86,
+            97,
+            114,
+            105,
+            97,
+            110,
+            116,
+            84,
+            123,
+            1
+        ]
+    )
+}
+
+#[test]
+fn test_seq() {
+    assert_eq!(
+        to_vec([true, false]).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 65, 2, 84, 70, 36, 0, 2]
+    );
+}
+
+#[test]
+fn test_seq_without_len() {
+    // Filtering loses the exact size, so sequences are serialized with no length
+    struct Filtered(Vec<Vec<i32>>);
+    struct FilteredInner<'a>(&'a [i32]);
+
+    impl Serialize for Filtered {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(
+                self.0
+                    .iter()
+                    .map(|inner| FilteredInner(inner))
+                    .filter(|_| true),
+            )
+        }
+    }
+
+    impl<'a> Serialize for FilteredInner<'a> {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(self.0.iter().filter(|_| true))
+        }
+    }
+
+    assert_eq!(
+        to_vec(Filtered(vec![vec![1, 2], vec![3]])).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            b'A',
+            2,
+            b'A',
+            2,
+            b'I',
+            2,
+            b'I',
+            4,
+            b'$',
+            0,
+            2,
+            b'A',
+            1,
+            b'I',
+            6,
+            b'$',
+            0,
+            1,
+            b'$',
+            0,
+            2
+        ],
+    );
+
+    // Lengths that take more than one byte
+    let value = vec![vec![0; 200], Vec::new(), (0..300).collect()];
+    let data = to_vec(Filtered(value.clone())).unwrap();
+    assert_eq!(&data[2..6], [b'A', 3, b'A', 200]);
+    assert_eq!(data[6], 1);
+    assert_eq!(crate::de::from_slice::<Vec<Vec<i32>>>(&data), Ok(value));
+}
+
+#[test]
+fn test_tuple() {
+    assert_eq!(
+        to_vec((false, true)).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 65, 2, 70, 84, 36, 0, 2]
+    );
+}
+
+#[test]
+fn test_tuple_struct() {
+    #[derive(Serialize)]
+    struct Tuple(bool, bool);
+
+    assert_eq!(
+        to_vec(Tuple(false, true)).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 65, 2, 70, 84, 36, 0, 2]
+    );
+}
+
+#[test]
+fn test_tuple_variant() {
+    #[derive(Serialize)]
+    enum Enum {
+        Variant(bool, bool),
+    }
+
+    assert_eq!(
+        to_vec(Enum::Variant(true, false)).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            111,
+            34,
+            7,
+            86,
+            97,
+            114,
+            105,
+            97,
+            110,
+            116,
+            65,
+            2,
+            84,
+            70,
+            36,
+            0,
+            2,
+            123,
+            1
+        ],
+    );
+}
+
+#[test]
+fn test_map() {
+    let mut map = std::collections::HashMap::new();
+    map.insert("Hello", "World");
+
+    assert_eq!(
+        to_vec(map).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            59,
+            34,
+            5,
+            72,
+            101,
+            108,
+            108,
+            111,
+            34,
+            5,
+            87,
+            111,
+            114,
+            108,
+            100,
+            58,
+            2
+        ],
+    )
+}
+
+#[test]
+fn test_as_object() {
+    use std::collections::BTreeMap;
+
+    let mut map = BTreeMap::new();
+    map.insert("a", 1);
+    map.insert("b", 2);
+
+    let as_map = to_vec(&map).unwrap();
+    assert_eq!(
+        as_map,
+        [
+            0xFF,
+            FORMAT_VERSION,
+            b';',
+            b'"',
+            1,
+            b'a',
+            b'I',
+            2,
+            b'"',
+            1,
+            b'b',
+            b'I',
+            4,
+            b':',
+            4
+        ]
+    );
+
+    // Other serializers see the map, `serde_json` an object like ours
+    #[cfg(feature = "json")]
+    assert_eq!(
+        serde_json::to_string(&AsObject(map.clone())).unwrap(),
+        r#"{"a":1,"b":2}"#
+    );
+
+    let as_object = to_vec(AsObject(map)).unwrap();
+    assert_eq!(
+        as_object,
+        [
+            0xFF,
+            FORMAT_VERSION,
+            b'o',
+            b'"',
+            1,
+            b'a',
+            b'I',
+            2,
+            b'"',
+            1,
+            b'b',
+            b'I',
+            4,
+            b'{',
+            2
+        ]
+    );
+    let value = crate::Deserializer::new().deserialize(&as_object).unwrap();
+    assert_eq!(value.as_object().unwrap()["b"], Value::Int32(2));
+
+    // Nested maps keep their own framing
+    #[derive(Serialize)]
+    struct Config {
+        #[serde(serialize_with = "serialize_as_object")]
+        env: HashMap<String, HashMap<i32, bool>>,
+    }
+    let mut inner = HashMap::new();
+    inner.insert(1, true);
+    let mut env = HashMap::new();
+    env.insert(String::from("x"), inner);
+    let data = to_vec(Config { env }).unwrap();
+    let value = crate::Deserializer::new().deserialize(&data).unwrap();
+    let env = value.as_object().unwrap()["env"].as_object().unwrap();
+    assert_eq!(
+        env["x"],
+        Value::Map(vec![(Value::Int32(1), Value::Boolean(true))])
+    );
+}
+
+#[test]
+fn test_canonical() {
+    let map: HashMap<String, Vec<u32>> = (0..50).map(|i| (i.to_string(), vec![i])).collect();
+    let mut other: HashMap<String, Vec<u32>> = HashMap::new();
+    for i in (0..50).rev() {
+        other.insert(i.to_string(), vec![i]);
+    }
+
+    let mut serializer = SerializerBuilder::new().canonical(true).build();
+    let data = serializer.serialize_to_vec(&map).unwrap();
+    assert_eq!(serializer.serialize_to_vec(&map).unwrap(), data);
+    assert_eq!(serializer.serialize_to_vec(&other).unwrap(), data);
+    assert_eq!(
+        serializer.serialize_to_vec(AsObject(map.clone())).unwrap()[3..data.len() - 2],
+        data[3..data.len() - 2]
+    );
+
+    let value = crate::Deserializer::new().deserialize(&data).unwrap();
+    let keys: Vec<_> = match value {
+        Value::Map(entries) => entries.into_iter().map(|(key, _)| key).collect(),
+        value => panic!("{:?}", value),
+    };
+    let mut sorted = keys.clone();
+    sorted.sort_by_key(|key| value_to_vec(key).unwrap());
+    assert_eq!(keys, sorted);
+
+    // Value::Object keeps its insertion order with preserve_order, but
+    // canonical output doesn't depend on it, nested objects included
+    let object = |keys: &mut dyn Iterator<Item = u32>| -> Value {
+        keys.map(|i| {
+            let inner: Value = vec![(i.to_string(), Value::from(i))].into_iter().collect();
+            (i.to_string(), inner)
+        })
+        .collect()
+    };
+    let forward = object(&mut (0..50));
+    let backward = object(&mut (0..50).rev());
+    let data = serializer.serialize_to_vec(&forward).unwrap();
+    assert_eq!(serializer.serialize_to_vec(&backward).unwrap(), data);
+    // Without preserve_order objects are read back sorted by key instead
+    #[cfg(feature = "preserve_order")]
+    {
+        let value = crate::Deserializer::new().deserialize(&data).unwrap();
+        let keys: Vec<_> = match value {
+            Value::Object(properties) => properties.keys().cloned().collect(),
+            value => panic!("{:?}", value),
+        };
+        let mut sorted = keys.clone();
+        sorted.sort_by_key(|key| value_to_vec(&Value::from(key.as_str())).unwrap());
+        assert_eq!(keys, sorted);
+    }
+
+    // Keys are compared encoded, so shorter strings come first. Reordering
+    // those would move the first occurrence of a shared value.
+    let shared = Rc::new(vec![0]);
+    let mut map = std::collections::BTreeMap::new();
+    map.insert("aa", Shared(shared.clone()));
+    map.insert("b", Shared(shared));
+    assert!(serializer.serialize_to_vec(&map).is_err());
+    assert!(to_vec(&map).is_ok());
+}
+
+#[test]
+fn test_map_without_len() {
+    use std::collections::BTreeMap;
+
+    // Filtering loses the exact size, so the map is serialized with no length
+    struct Filtered<'a>(&'a BTreeMap<&'static str, BTreeMap<&'static str, u8>>);
+
+    impl<'a> Serialize for Filtered<'a> {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().filter(|_| true))
+        }
+    }
+
+    let mut inner = BTreeMap::new();
+    inner.insert("a", 1);
+    inner.insert("b", 2);
+    let mut outer = BTreeMap::new();
+    outer.insert("x", inner);
+
+    // With string keys that makes it an object, see `serialize_map`
+    assert_eq!(
+        to_vec(Filtered(&outer)).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            b'o',
+            b'"',
+            1,
+            b'x',
+            b';',
+            b'"',
+            1,
+            b'a',
+            b'U',
+            1,
+            b'"',
+            1,
+            b'b',
+            b'U',
+            2,
+            b':',
+            4,
+            b'{',
+            1
+        ],
+    );
+    assert_eq!(
+        to_vec(Filtered(&BTreeMap::new())).unwrap(),
+        vec![0xFF, FORMAT_VERSION, b';', b':', 0],
+    );
+}
+
+#[test]
+fn test_struct() {
+    #[derive(Serialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    assert_eq!(
+        to_vec(Point { x: 69, y: 70 }).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            111,
+            34,
+            1,
+            120,
+            73,
+            138,
+            1,
+            34,
+            1,
+            121,
+            73,
+            140,
+            1,
+            123,
+            2
+        ],
+    )
+}
+
+#[test]
+fn test_skipped_fields() {
+    use ser::SerializeStruct;
+
+    #[derive(Serialize)]
+    struct User {
+        id: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    }
+
+    let data = to_vec(User { id: 1, name: None }).unwrap();
+    assert_eq!(
+        data,
+        [
+            0xFF,
+            FORMAT_VERSION,
+            b'o',
+            b'"',
+            2,
+            b'i',
+            b'd',
+            b'U',
+            1,
+            b'{',
+            1
+        ]
+    );
+    let value = crate::from_slice_checked(&data).unwrap();
+    assert_eq!(value.as_object().unwrap().len(), 1);
+
+    // Claims three fields upfront but skips the ones that are `None`
+    struct Sparse(Option<u32>, Option<u32>, Option<u32>);
+
+    impl Serialize for Sparse {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("Sparse", 3)?;
+            for (key, value) in [("a", self.0), ("b", self.1), ("c", self.2)] {
+                match value {
+                    Some(value) => state.serialize_field(key, &value)?,
+                    None => state.skip_field(key)?,
+                }
+            }
+            state.end()
+        }
+    }
+
+    let data = to_vec(Sparse(Some(1), None, Some(3))).unwrap();
+    assert_eq!(data[data.len() - 2..], [b'{', 2]);
+    let value = crate::from_slice_checked(&data).unwrap();
+    assert_eq!(value.as_object().unwrap().len(), 2);
+    let data = to_vec(vec![Sparse(None, None, None)]).unwrap();
+    assert_eq!(
+        crate::from_slice_checked(&data).unwrap()[0]
+            .as_object()
+            .unwrap()
+            .len(),
+        0
+    );
+
+    #[derive(Serialize)]
+    enum Event {
+        Login {
+            user: u32,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            via: Option<String>,
+        },
+    }
+
+    let data = to_vec(Event::Login { user: 1, via: None }).unwrap();
+    assert_eq!(data[data.len() - 4..], [b'{', 1, b'{', 1]);
+}
+
+#[test]
+fn test_flatten() {
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Page {
+        number: u32,
+        size: u32,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Query {
+        term: String,
+        #[serde(flatten)]
+        page: Page,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sort: Option<String>,
+        #[serde(flatten)]
+        extra: BTreeMap<String, u32>,
+    }
+
+    let mut extra = BTreeMap::new();
+    extra.insert(String::from("limit"), 3);
+    let query = Query {
+        term: String::from("v8"),
+        page: Page { number: 1, size: 2 },
+        sort: None,
+        extra,
+    };
+    // Serde writes it as a map with no length
+    let data = to_vec(&query).unwrap();
+    assert_eq!(data[2], b'o');
+    assert_eq!(data[data.len() - 2..], [b'{', 4]);
+
+    let value = crate::from_slice_checked(&data).unwrap();
+    let properties = value.as_object().unwrap();
+    let keys: Vec<_> = properties.keys().map(String::as_str).collect();
+    #[cfg(feature = "preserve_order")]
+    assert_eq!(keys, ["term", "number", "size", "limit"]);
+    #[cfg(not(feature = "preserve_order"))]
+    assert_eq!(keys, ["limit", "number", "size", "term"]);
+    assert_eq!(crate::from_slice::<Query>(&data), Ok(query));
+
+    // Maps of their own are still maps
+    #[derive(Serialize)]
+    struct Outer {
+        inner: BTreeMap<String, u32>,
+    }
+    let outer = Outer {
+        inner: BTreeMap::new(),
+    };
+    let data = to_vec(&outer).unwrap();
+    assert_eq!(
+        data[2..],
+        [b'o', b'"', 5, b'i', b'n', b'n', b'e', b'r', b';', b':', 0, b'{', 1]
+    );
+}
+
+#[test]
+fn test_map_with_str_keys() {
+    use ser::SerializeMap;
+
+    // Keys are `str`s, like a flattened struct's field names
+    struct Entries(Option<usize>);
+
+    impl Serialize for Entries {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(self.0)?;
+            map.serialize_entry("a", &1)?;
+            map.serialize_entry("b", &2)?;
+            map.end()
+        }
+    }
+
+    // Without a length it's an object, like a struct with flattened fields
+    let data = to_vec(Entries(None)).unwrap();
+    assert_eq!(data[2], b'o');
+    assert_eq!(data[data.len() - 2..], [b'{', 2]);
+    assert_eq!(
+        crate::from_slice_checked(&data),
+        Ok(vec![
+            (String::from("a"), Value::Int32(1)),
+            (String::from("b"), Value::Int32(2)),
+        ]
+        .into_iter()
+        .collect())
+    );
+    assert_eq!(serialized_size(&Entries(None)), Ok(data.len()));
+    let mut out = [0; 16];
+    assert_eq!(to_slice(Entries(None), &mut out), Ok(data.len()));
+    assert_eq!(out[..data.len()], data[..]);
+
+    let data = to_vec(Entries(Some(2))).unwrap();
+    assert_eq!(data[2], b';');
+    assert_eq!(
+        crate::from_slice_checked(&data),
+        Ok(Value::Map(vec![
+            (Value::from("a"), Value::Int32(1)),
+            (Value::from("b"), Value::Int32(2)),
+        ]))
+    );
+
+    // One key that isn't a string keeps it a `Map`
+    struct Mixed;
+
+    impl Serialize for Mixed {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut map = serializer.serialize_map(None)?;
+            map.serialize_entry("a", &1)?;
+            map.serialize_entry(&[2], &3)?;
+            map.end()
+        }
+    }
+
+    let data = to_vec(Mixed).unwrap();
+    assert_eq!(data[2], b';');
+    assert_eq!(data[data.len() - 2..], [b':', 4]);
+    assert_eq!(serialized_size(&Mixed), Ok(data.len()));
+}
+
+#[test]
+fn test_struct_variant() {
+    #[derive(Serialize)]
+    enum Enum {
+        Point { x: i32, y: i32 },
+    }
+
+    assert_eq!(
+        to_vec(Enum::Point { x: 69, y: 70 }).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            111,
+            34,
+            5,
+            80,
+            111,
+            105,
+            110,
+            116,
+            111,
+            34,
+            1,
+            120,
+            73,
+            138,
+            1,
+            34,
+            1,
+            121,
+            73,
+            140,
+            1,
+            123,
+            2,
+            123,
+            1
+        ]
+    );
+}
+
+#[test]
+fn test_enum_framing() {
+    #[derive(Serialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+        z: i32,
+    }
+
+    #[derive(Serialize)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Line(Point, Point),
+        Labeled { label: &'static str, at: Point },
+    }
+
+    let object = |key: &str, value: Value| -> Value {
+        vec![(String::from(key), value)].into_iter().collect()
+    };
+    let point = |x, y, z| -> Value {
+        vec![
+            (String::from("x"), Value::Int32(x)),
+            (String::from("y"), Value::Int32(y)),
+            (String::from("z"), Value::Int32(z)),
+        ]
+        .into_iter()
+        .collect()
+    };
+    let decode = |shape: &Shape| crate::from_slice_checked(&to_vec(shape).unwrap());
+
+    assert_eq!(decode(&Shape::Empty), Ok(object("Empty", Value::Undefined)));
+    assert_eq!(
+        decode(&Shape::Circle(0.5)),
+        Ok(object("Circle", Value::Double(0.5)))
+    );
+    // Nested payloads with more fields than the variant don't throw off the
+    // variant's counts
+    assert_eq!(
+        decode(&Shape::Line(
+            Point { x: 1, y: 2, z: 3 },
+            Point { x: 4, y: 5, z: 6 }
+        )),
+        Ok(object(
+            "Line",
+            Value::Array(vec![point(1, 2, 3), point(4, 5, 6)])
+        ))
+    );
+    let labeled = Shape::Labeled {
+        label: "origin",
+        at: Point { x: 0, y: 0, z: 0 },
+    };
+    assert_eq!(
+        decode(&labeled),
+        Ok(object(
+            "Labeled",
+            vec![
+                (String::from("label"), Value::from("origin")),
+                (String::from("at"), point(0, 0, 0)),
+            ]
+            .into_iter()
+            .collect()
+        ))
+    );
+
+    let mut serializer = SerializerBuilder::new().none_as_null(true).build();
+    let data = serializer.serialize_to_vec(Shape::Empty).unwrap();
+    assert_eq!(
+        data[2..],
+        [b'o', b'"', 5, b'E', b'm', b'p', b't', b'y', b'_', b'{', 1]
+    );
+
+    // Unit variants can be just their name, variants with a payload are
+    // still objects
+    let mut serializer = SerializerBuilder::new()
+        .unit_variants_as_strings(true)
+        .build();
+    let data = serializer.serialize_to_vec(Shape::Empty).unwrap();
+    assert_eq!(data[2..], [b'"', 5, b'E', b'm', b'p', b't', b'y']);
+    let data = serializer.serialize_to_vec(Shape::Circle(0.5)).unwrap();
+    assert_eq!(data, to_vec(Shape::Circle(0.5)).unwrap());
+}
+
+#[test]
+fn test_empty_collections() {
+    use std::collections::HashMap;
+
+    // Like an iterator without a size hint, the length is only known at the end
+    struct Lazy;
+
+    impl Serialize for Lazy {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use ser::SerializeSeq;
+            serializer.serialize_seq(None)?.end()
+        }
+    }
+
+    let empty_array = [0xFF, FORMAT_VERSION, b'A', 0, b'$', 0, 0];
+    assert_eq!(to_vec(Vec::<i32>::new()).unwrap(), empty_array);
+    assert_eq!(to_vec(Lazy).unwrap(), empty_array);
+    assert_eq!(
+        crate::from_slice_checked(&empty_array),
+        Ok(Value::Array(Vec::new()))
+    );
+    assert_eq!(crate::from_slice(&empty_array), Ok(Vec::<i32>::new()));
+
+    let data = to_vec(HashMap::<String, i32>::new()).unwrap();
+    assert_eq!(data, [0xFF, FORMAT_VERSION, b';', b':', 0]);
+    assert_eq!(crate::from_slice_checked(&data), Ok(Value::Map(Vec::new())));
+    assert_eq!(crate::from_slice(&data), Ok(HashMap::<String, i32>::new()));
+
+    let data = value_to_vec(&Value::Set(Vec::new())).unwrap();
+    assert_eq!(data, [0xFF, FORMAT_VERSION, b'\'', b',', 0]);
+    assert_eq!(crate::from_slice_checked(&data), Ok(Value::Set(Vec::new())));
+
+    let data = value_to_vec(&Value::Object(ObjectMap::new())).unwrap();
+    assert_eq!(data, [0xFF, FORMAT_VERSION, b'o', b'{', 0]);
+    assert_eq!(
+        crate::from_slice_checked(&data),
+        Ok(Value::Object(ObjectMap::new()))
+    );
+}
+
+#[test]
+fn test_unit_and_empty_tuples() {
+    assert_eq!(to_vec(()).unwrap(), [0xFF, FORMAT_VERSION, b'_']);
+    assert_eq!(
+        to_vec(((),)).unwrap(),
+        [0xFF, FORMAT_VERSION, b'A', 1, b'_', b'$', 0, 1]
+    );
+    assert_eq!(
+        to_vec([(); 0]).unwrap(),
+        [0xFF, FORMAT_VERSION, b'A', 0, b'$', 0, 0]
+    );
+    assert_eq!(
+        to_vec([(), ()]).unwrap(),
+        [0xFF, FORMAT_VERSION, b'A', 2, b'_', b'_', b'$', 0, 2]
+    );
+
+    assert_eq!(crate::from_slice::<()>(&to_vec(()).unwrap()), Ok(()));
+    let data = to_vec([(); 0]).unwrap();
+    assert_eq!(crate::from_slice::<[(); 0]>(&data), Ok([]));
+    assert!(crate::from_slice::<()>(&data).is_err());
+}
+
+#[test]
+fn test_tuple_length() {
+    use ser::SerializeTuple;
+
+    // Claims more elements than it writes
+    struct Short;
+
+    impl Serialize for Short {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut tuple = serializer.serialize_tuple(3)?;
+            tuple.serialize_element(&1)?;
+            tuple.serialize_element(&2)?;
+            tuple.end()
+        }
+    }
+
+    assert_eq!(
+        to_vec(Short),
+        Err(Error::LengthMismatch {
+            expected: 3,
+            actual: 2
+        })
+    );
+    assert_eq!(
+        to_vec((1, Short)),
+        Err(Error::LengthMismatch {
+            expected: 3,
+            actual: 2
+        })
+    );
+    // Nested tuples are counted separately
+    assert!(to_vec((1, (2, 3, 4), [5, 6])).is_ok());
 }
 
+#[cfg(feature = "smallvec")]
 #[test]
-fn test_str() {
+fn test_inline_buffer() {
+    let message = "a short message, well under the inline size";
+    let mut serializer = Serializer::new();
+    serializer.reset();
+    message.serialize(&mut serializer).unwrap();
+    assert!(!serializer.data.spilled());
+
+    let bytes = serializer.as_bytes();
+    assert_eq!(bytes.len(), 2 + 2 + message.len());
+    assert!(bytes.len() <= INLINE_CAPACITY);
+    assert_eq!(to_vec(message).unwrap(), bytes);
+
+    // Past the inline capacity it's written to the heap like before
+    let long = "x".repeat(INLINE_CAPACITY * 2);
+    long.serialize(&mut serializer).unwrap();
+    assert!(serializer.data.spilled());
+}
+
+#[test]
+fn test_date_ext() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let date = |time| {
+        let mut serializer = Serializer::new();
+        (&mut serializer).serialize_system_time(time).unwrap();
+        crate::de::Deserializer::new()
+            .deserialize(&serializer.data)
+            .unwrap()
+    };
+
+    // 2021-01-01T00:00:00.000Z
+    let time = UNIX_EPOCH + Duration::from_millis(1_609_459_200_000);
+    assert_eq!(date(time), Value::Date(1_609_459_200_000.0));
     assert_eq!(
-        to_vec("test").unwrap(),
-        vec![0xFF, FORMAT_VERSION, 34, 4, 116, 101, 115, 116]
+        date(time + Duration::from_micros(999)),
+        Value::Date(1_609_459_200_000.0)
+    );
+
+    assert_eq!(date(UNIX_EPOCH), Value::Date(0.0));
+    assert_eq!(
+        date(UNIX_EPOCH - Duration::from_millis(1500)),
+        Value::Date(-1500.0)
+    );
+    assert_eq!(
+        date(UNIX_EPOCH - Duration::from_micros(1)),
+        Value::Date(-1.0)
     );
 }
 
 #[test]
-fn test_bytes() {
-    use serde_bytes::Bytes;
+fn test_shared() {
+    #[derive(Serialize)]
+    struct Pair {
+        #[serde(serialize_with = "serialize_shared")]
+        a: Rc<(bool, bool)>,
+        #[serde(serialize_with = "serialize_shared")]
+        b: Rc<(bool, bool)>,
+    }
 
-    let bytes: [u8; 3] = [1, 2, 3];
+    let inner = Rc::new((true, false));
+    let pair = Pair {
+        a: inner.clone(),
+        b: inner,
+    };
     assert_eq!(
-        to_vec(Bytes::new(&bytes)).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 66, 3, 1, 2, 3]
+        to_vec(pair).unwrap(),
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            111,
+            34,
+            1,
+            97,
+            65,
+            2,
+            84,
+            70,
+            36,
+            0,
+            2,
+            34,
+            1,
+            98,
+            94,
+            1,
+            123,
+            2
+        ]
+    );
+
+    // Primitives don't get ids, so they're just written twice
+    let inner = Rc::new(true);
+    assert_eq!(
+        to_vec((Shared(inner.clone()), Shared(inner))).unwrap(),
+        vec![0xFF, FORMAT_VERSION, 65, 2, 84, 84, 36, 0, 2]
     );
 }
 
 #[test]
-fn test_undefined() {
+fn test_shared_cycle() {
+    use std::cell::RefCell;
+
+    #[derive(Serialize)]
+    struct Node {
+        name: &'static str,
+        #[serde(serialize_with = "serialize_shared")]
+        next: Option<Rc<RefCell<Node>>>,
+    }
+
+    let a = Rc::new(RefCell::new(Node {
+        name: "a",
+        next: None,
+    }));
+    let b = Rc::new(RefCell::new(Node {
+        name: "b",
+        next: Some(a.clone()),
+    }));
+    a.borrow_mut().next = Some(b);
+
+    let data = to_vec(Shared(a.clone())).unwrap();
+    // Break the cycle so both nodes are freed
+    a.borrow_mut().next = None;
+
     assert_eq!(
-        to_vec(None as Option<()>).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 95]
+        data,
+        vec![
+            0xFF,
+            FORMAT_VERSION,
+            // a = { name: "a", next: b }
+            111,
+            34,
+            4,
+            110,
+            97,
+            109,
+            101,
+            34,
+            1,
+            97,
+            34,
+            4,
+            110,
+            101,
+            120,
+            116,
+            // b = { name: "b", next: ^0 }
+            111,
+            34,
+            4,
+            110,
+            97,
+            109,
+            101,
+            34,
+            1,
+            98,
+            34,
+            4,
+            110,
+            101,
+            120,
+            116,
+            94,
+            0,
+            123,
+            2,
+            123,
+            2
+        ]
     );
-    assert_eq!(to_vec(()).unwrap(), vec![0xFF, FORMAT_VERSION, 95]);
 }
 
 #[test]
-fn test_some() {
-    assert_eq!(to_vec(Some(true)).unwrap(), vec![0xFF, FORMAT_VERSION, 84]);
+fn test_value() {
+    assert_eq!(to_vec(Value::Int32(1)).unwrap(), to_vec(1i32).unwrap());
+    assert_eq!(to_vec(Value::Null).unwrap(), vec![0xFF, FORMAT_VERSION, 48]);
+    assert_eq!(
+        to_vec(Value::String(String::from("test"), false)).unwrap(),
+        to_vec("test").unwrap()
+    );
+
+    let value: Value = vec![
+        (String::from("list"), Value::Array(vec![Value::Null])),
+        (
+            String::from("set"),
+            Value::Set(vec![Value::Date(1.0), Value::BigInt(-1)]),
+        ),
+    ]
+    .into_iter()
+    .collect();
+    let mut expected = vec![0xFF, FORMAT_VERSION];
+    expected.extend(Serializer::new().serialize(&value).unwrap());
+    assert_eq!(to_vec(&value).unwrap(), expected);
+
+    // Objects written for a Value count towards later reference ids
+    let inner = Rc::new((true, false));
+    let data = to_vec((
+        Value::Array(vec![Value::Object(ObjectMap::new())]),
+        Shared(inner.clone()),
+        Shared(inner),
+    ))
+    .unwrap();
+    assert_eq!(data[data.len() - 5..data.len() - 3], [94, 3]);
+}
+
+#[test]
+fn test_value_options() {
+    let value = Value::Array(vec![
+        Value::Null,
+        Value::String(String::from("two-byte"), true),
+        Value::ObjectReference { id: 0 },
+        Value::Date(1.5),
+        Value::NumberObject(-2.0),
+        Value::BigIntObject(),
+        Value::StringObject(String::from("s")),
+        Value::RegExp {
+            expr: String::from("a+"),
+            flags: 3,
+        },
+        Value::ArrayBufferTransfer { transfer_id: 1 },
+        Value::ArrayBufferView {
+            ty: ArrayBufferViewType::Uint16Array,
+            byte_offset: 2,
+            byte_length: 2,
+            buffer: vec![1, 2, 3, 4],
+        },
+        Value::SharedArrayBuffer { transfer_id: 2 },
+        Value::Error {
+            ty: ErrorType::TypeError,
+            message: Some(String::from("bad")),
+            stack: Some(String::from("at f")),
+        },
+        Value::WasmModule(vec![0, 0x61, 0x73, 0x6D]),
+        Value::WasmMemory {
+            maximum_pages: -1,
+            transfer_id: 3,
+        },
+        Value::HostObject(vec![7, 8]),
+        Value::Unsupported {
+            tag: b'X',
+            data: vec![9],
+        },
+    ]);
+    // Written through serde, the same as directly
+    assert_eq!(to_vec(&value).unwrap(), value_to_vec(&value).unwrap());
+    // Each is numbered, two objects for the view and two for the memory
+    let shared = Rc::new(vec![true]);
+    let data = to_vec((&value, Shared(shared.clone()), Shared(shared))).unwrap();
+    assert_eq!(data[data.len() - 5..data.len() - 3], [b'^', 17]);
+
+    // Values nested anywhere are written with the serializer's options
+    let nested = Value::Array(vec![
+        Value::Date(1.5),
+        Value::String(String::from("é€"), false),
+    ]);
+    let mut serializer = SerializerBuilder::new()
+        .little_endian(false)
+        .v8_compat(true)
+        .build();
+    let data = serializer.serialize_to_vec(&nested).unwrap();
+    let mut expected = vec![0xFF, FORMAT_VERSION, b'A', 2, b'D'];
+    expected.extend(1.5f64.to_be_bytes());
+    expected.extend([0, b'c', 4, 0, 0xE9, 0x20, 0xAC, b'$', 0, 2]);
+    assert_eq!(data, expected);
+
+    let mut serializer = SerializerBuilder::new().reject_nonfinite(true).build();
+    let nan = Value::Array(vec![Value::Double(f64::NAN)]);
+    assert_eq!(
+        serializer.serialize_to_vec(&nan),
+        Err(Error::NonFiniteFloat)
+    );
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_value_other_serializers() {
+    let value = Value::Array(vec![
+        Value::Null,
+        Value::Undefined,
+        Value::from(1),
+        Value::String(String::from("a"), true),
+        Value::Map(vec![(Value::from("k"), Value::from(true))]),
+    ]);
+    assert_eq!(
+        serde_json::to_string(&value).unwrap(),
+        r#"[null,null,1,"a",{"k":true}]"#
+    );
+    assert_eq!(
+        serde_json::to_string(&Value::Date(1.0)).unwrap(),
+        r#"["D",1.0]"#
+    );
+
+    let object: Value = vec![
+        (String::from("set"), Value::Set(vec![Value::from(1)])),
+        (
+            String::from("view"),
+            Value::ArrayBufferView {
+                ty: ArrayBufferViewType::Uint8Array,
+                byte_offset: 1,
+                byte_length: 1,
+                buffer: vec![1, 2],
+            },
+        ),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        serde_json::to_string(&object).unwrap(),
+        r#"{"set":[1],"view":["B",2,[1,2],"V",66,1,1]}"#
+    );
+}
+
+#[test]
+fn test_typed_bytes() {
+    let bytes = TypedBytes {
+        ty: ArrayBufferViewType::Uint8Array,
+        data: vec![1, 2, 3],
+    };
+    assert_eq!(
+        to_vec(&bytes).unwrap(),
+        [0xFF, FORMAT_VERSION, b'B', 3, 1, 2, 3, b'V', b'B', 0, 3]
+    );
+
+    let data: Vec<u8> = [0.5f64, -2.0]
+        .iter()
+        .flat_map(|v| v.to_ne_bytes())
+        .collect();
+    let floats = TypedBytes {
+        ty: ArrayBufferViewType::Float64Array,
+        data: data.clone(),
+    };
+    // Views count as objects of their own, so the reference is to id 3
+    let shared = Rc::new(vec![true]);
+    let encoded = to_vec((floats, Shared(shared.clone()), Shared(shared))).unwrap();
+    assert_eq!(encoded[encoded.len() - 5..encoded.len() - 3], [b'^', 3]);
+    assert_eq!(
+        crate::Deserializer::new().deserialize(&encoded).unwrap(),
+        Value::Array(vec![
+            Value::ArrayBufferView {
+                ty: ArrayBufferViewType::Float64Array,
+                byte_offset: 0,
+                byte_length: 16,
+                buffer: data,
+            },
+            Value::Array(vec![Value::Boolean(true)]),
+            Value::Array(vec![Value::Boolean(true)]),
+        ])
+    );
+
+    let misaligned = TypedBytes {
+        ty: ArrayBufferViewType::Float64Array,
+        data: vec![0; 12],
+    };
+    assert!(to_vec(&misaligned).is_err());
+
+    // Only the bytes are written anywhere else
+    #[cfg(feature = "json")]
+    assert_eq!(serde_json::to_string(&bytes).unwrap(), "[1,2,3]");
+}
+
+#[test]
+fn test_invalid_view() {
+    let view = |ty, byte_offset, byte_length| Value::ArrayBufferView {
+        ty,
+        byte_offset,
+        byte_length,
+        buffer: vec![0; 16],
+    };
+    assert!(value_to_vec(&view(ArrayBufferViewType::Int32Array, 4, 12)).is_ok());
+
+    // Misaligned length, then offset
+    assert_eq!(
+        value_to_vec(&view(ArrayBufferViewType::Int32Array, 0, 6)),
+        Err(Error::InvalidView {
+            ty: ArrayBufferViewType::Int32Array,
+            at: 2,
+        })
+    );
+    assert!(value_to_vec(&view(ArrayBufferViewType::Int32Array, 2, 4)).is_err());
+
+    // Out of range, including when nested
+    let out_of_range = view(ArrayBufferViewType::Uint8Array, 12, 8);
+    assert!(matches!(
+        value_to_vec(&out_of_range),
+        Err(Error::InvalidView { .. })
+    ));
+    assert!(value_to_vec(&Value::Array(vec![out_of_range.clone()])).is_err());
+    assert!(to_vec(&out_of_range).is_err());
+}
+
+#[test]
+fn test_value_to_vec() {
+    let map = Value::Map(vec![(Value::from(1), Value::from("one"))]);
+    let property: Value = vec![(String::from("key"), Value::Undefined)]
+        .into_iter()
+        .collect();
+    let value = Value::Array(vec![
+        Value::Null,
+        Value::Double(0.5),
+        Value::BigInt(-7),
+        Value::Date(86_400_000.0),
+        Value::RegExp {
+            expr: String::from("^a"),
+            flags: 1,
+        },
+        map.clone(),
+        Value::ObjectReference { id: 3 },
+        property,
+        Value::Set(vec![Value::from(true)]),
+        Value::ArrayBuffer(vec![1, 2]),
+        Value::Error {
+            ty: ErrorType::RangeError,
+            message: Some(String::from("out of range")),
+            stack: None,
+        },
+    ]);
+
+    let data = value_to_vec(&value).unwrap();
+    assert_eq!(data[..3], [0xFF, FORMAT_VERSION, b'A']);
+    // The reference to the map (object 0 is the array, 1 the date, 2 the
+    // regexp) decodes into a copy of it.
+    let mut expected = value.clone();
+    if let Value::Array(values) = &mut expected {
+        values[6] = map;
+    }
+    assert_eq!(crate::Deserializer::new().deserialize(&data), Ok(expected));
+}
+
+#[test]
+fn test_array_framing() {
+    let values = vec![vec![1, 2], vec![], vec![300]];
+    let value = Value::Array(
+        values
+            .iter()
+            .map(|inner| Value::Array(inner.iter().map(|&v| Value::Int32(v)).collect()))
+            .collect(),
+    );
+    assert_eq!(value_to_vec(&value).unwrap(), to_vec(&values).unwrap());
+    assert_eq!(
+        value_to_vec(&Value::Array(Vec::new())).unwrap(),
+        to_vec(Vec::<i32>::new()).unwrap()
+    );
+
+    // Longer than a single varint byte
+    let values: Vec<bool> = (0..200).map(|i| i % 2 == 0).collect();
+    let value = Value::Array(values.iter().map(|&v| Value::Boolean(v)).collect());
+    let data = value_to_vec(&value).unwrap();
+    assert_eq!(data, to_vec(&values).unwrap());
+    assert_eq!(data[data.len() - 4..], [b'$', 0, 0xC8, 0x01]);
+}
+
+#[test]
+fn test_value_roundtrip() {
+    let value = Value::Array(vec![
+        Value::Null,
+        Value::Undefined,
+        Value::Array(vec![Value::Boolean(true), Value::Boolean(false)]),
+    ]);
+    let data = to_vec(&value).unwrap();
+    assert_eq!(
+        crate::Deserializer::new().deserialize(&data).unwrap(),
+        value
+    );
+}
+
+#[test]
+fn test_type_hook() {
+    #[derive(Serialize)]
+    struct Uuid([u8; 16]);
+
+    #[derive(Serialize)]
+    struct Record {
+        #[serde(serialize_with = "serialize_hooked")]
+        id: Uuid,
+        parent: Option<Hooked<Uuid>>,
+        other: Uuid,
+    }
+
+    let options = SerializerOptions::default().with_type_hook(|uuid: &Uuid, serializer| {
+        Value::ArrayBufferView {
+            ty: ArrayBufferViewType::Uint8Array,
+            byte_offset: 0,
+            byte_length: 16,
+            buffer: uuid.0.to_vec(),
+        }
+        .serialize(serializer)
+    });
+    let record = Record {
+        id: Uuid([7; 16]),
+        parent: Some(Hooked(Uuid([8; 16]))),
+        other: Uuid([9; 16]),
+    };
+
+    let mut expected = vec![0xFF, FORMAT_VERSION, b'o', b'"', 2, b'i', b'd', b'B', 16];
+    expected.extend([7; 16]);
+    expected.extend([b'V', b'B', 0, 16]);
+    expected.extend([b'"', 6, b'p', b'a', b'r', b'e', b'n', b't', b'B', 16]);
+    expected.extend([8; 16]);
+    expected.extend([b'V', b'B', 0, 16]);
+    // Only values marked as hooked are
+    expected.extend([b'"', 5, b'o', b't', b'h', b'e', b'r']);
+    expected.extend(to_vec([9u8; 16]).unwrap()[2..].iter());
+    expected.extend([b'{', 3]);
+    assert_eq!(to_vec_with_options(&record, options).unwrap(), expected);
+
+    // Without the hook the bytes are written as an array of numbers
+    let data = to_vec(&record).unwrap();
+    assert_eq!(&data[7..9], [b'A', 16]);
+
+    // Serde buffers the fields of a tuple variant in an internally tagged
+    // enum, and replays them while the outer `Hooked` is still around. Its
+    // hook mustn't be taken for theirs.
+    #[derive(Serialize)]
+    enum Inner {
+        Pair(Hooked<Uuid>, u8),
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "kind")]
+    enum Outer {
+        Wrapped(Hooked<Inner>),
+    }
+
+    let options = SerializerOptions::default()
+        .with_type_hook(|_: &Inner, serializer| "inner".serialize(serializer))
+        .with_type_hook(|_: &Uuid, serializer| "uuid".serialize(serializer));
+    let outer = Outer::Wrapped(Hooked(Inner::Pair(Hooked(Uuid([1; 16])), 2)));
+    assert_eq!(
+        to_vec_with_options(&outer, options).unwrap(),
+        to_vec(&outer).unwrap()
+    );
+}
+
+#[test]
+fn test_serializer_builder() {
+    let mut serializer = SerializerBuilder::new().little_endian(false).build();
+    let mut expected = vec![0xFF, FORMAT_VERSION, b'N'];
+    expected.extend(0.1f64.to_be_bytes());
+    assert_eq!(serializer.serialize_to_vec(0.1).unwrap(), expected);
+    // Reusing the serializer starts over
+    assert_eq!(serializer.serialize_to_vec(0.1).unwrap(), expected);
+
+    let mut serializer = SerializerBuilder::new().little_endian(true).build();
+    let data = serializer.serialize_to_vec(0.1).unwrap();
+    assert_eq!(data[3..], 0.1f64.to_le_bytes());
+
+    let shared = Rc::new(vec![1]);
+    let pair = (Shared(shared.clone()), Shared(shared));
+    let deduped = to_vec(&pair).unwrap();
+    assert_eq!(deduped[deduped.len() - 5..deduped.len() - 3], [b'^', 1]);
+
+    let mut serializer = SerializerBuilder::new().dedup_references(false).build();
+    let data = serializer.serialize_to_vec(&pair).unwrap();
+    assert_eq!(data, to_vec((vec![1], vec![1])).unwrap());
+}
+
+#[test]
+fn test_header() {
+    let mut serializer = SerializerBuilder::new().build();
+    let first = serializer.serialize_to_vec(vec![true]).unwrap();
+    let second = serializer.serialize_to_vec("a").unwrap();
+    assert_eq!(first, [0xFF, FORMAT_VERSION, b'A', 1, b'T', b'$', 0, 1]);
+    assert_eq!(second, [0xFF, FORMAT_VERSION, b'"', 1, b'a']);
+
+    // A failed value doesn't leave anything behind
+    struct Fails;
+    impl Serialize for Fails {
+        fn serialize<S: ser::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(ser::Error::custom("fails"))
+        }
+    }
+    assert!(serializer.serialize_to_vec(vec![Fails]).is_err());
+    assert!(serializer.data.is_empty());
+    assert_eq!(
+        serializer.serialize_to_vec(()).unwrap(),
+        to_vec(()).unwrap()
+    );
+}
+
+#[test]
+fn test_shared_without_references() {
+    use std::cell::RefCell;
+
+    #[derive(Serialize)]
+    struct Node {
+        #[serde(serialize_with = "serialize_shared")]
+        next: Option<Rc<RefCell<Node>>>,
+    }
+
+    let node = Rc::new(RefCell::new(Node { next: None }));
+    node.borrow_mut().next = Some(node.clone());
+    let res = SerializerBuilder::new()
+        .dedup_references(false)
+        .build()
+        .serialize_to_vec(Shared(node.clone()));
+    node.borrow_mut().next = None;
+    assert!(matches!(res, Err(Error::Message(_))));
+}
+
+#[test]
+fn test_key_map() {
+    #[derive(Serialize)]
+    struct User {
+        first_name: &'static str,
+        id: u8,
+    }
+
+    let mut key_map = HashMap::new();
+    key_map.insert(String::from("first_name"), String::from("firstName"));
+    let options = SerializerOptions {
+        key_map,
+        ..Default::default()
+    };
+    let user = User {
+        first_name: "Ann",
+        id: 1,
+    };
+
+    let mut expected = vec![0xFF, FORMAT_VERSION, b'o', b'"', 9];
+    expected.extend(b"firstName");
+    expected.extend([
+        b'"', 3, b'A', b'n', b'n', b'"', 2, b'i', b'd', b'U', 1, b'{', 2,
+    ]);
+    assert_eq!(to_vec_with_options(&user, options).unwrap(), expected);
+}
+
+#[test]
+fn test_bigint_newtype() {
+    assert_eq!(to_vec(1).unwrap(), [0xFF, FORMAT_VERSION, b'I', 2]);
+    assert_eq!(
+        to_vec(BigInt(1)).unwrap(),
+        [0xFF, FORMAT_VERSION, b'Z', 16, 1, 0, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(to_vec(BigInt(0)).unwrap(), [0xFF, FORMAT_VERSION, b'Z', 0]);
+
+    // Two digits, negative
+    let encoded = to_vec(BigInt(-(1 << 64))).unwrap();
+    assert_eq!(encoded[2..4], [b'Z', 33]);
+    assert_eq!(
+        encoded[4..],
+        [0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]
+    );
+    let encoded = to_vec(BigInt(i128::MIN)).unwrap();
+    assert_eq!(encoded[3], 33);
+    assert_eq!(encoded[encoded.len() - 1], 0x80);
+
+    assert_eq!(
+        crate::from_slice::<Value>(&to_vec((BigInt(-5), 5)).unwrap()).unwrap(),
+        Value::Array(vec![Value::BigInt(-5), Value::Int32(5)])
+    );
+}
+
+#[test]
+fn test_i128() {
+    // Compared against `v8.serialize()` in Node.js
+    let mut expected = vec![0xFF, FORMAT_VERSION, b'Z', 32];
+    expected.extend([0xFF; 15]);
+    expected.push(0x7F);
+    assert_eq!(to_vec(i128::MAX).unwrap(), expected);
+
+    let mut expected = vec![0xFF, FORMAT_VERSION, b'Z', 32];
+    expected.extend([0xFF; 16]);
+    assert_eq!(to_vec(u128::MAX).unwrap(), expected);
+
+    let mut expected = vec![0xFF, FORMAT_VERSION, b'Z', 33];
+    expected.extend([0; 15]);
+    expected.push(0x80);
+    assert_eq!(to_vec(i128::MIN).unwrap(), expected);
+
+    // Small ones take a single digit, and agree with `BigInt`
+    assert_eq!(to_vec(-1i128).unwrap(), to_vec(-1i64).unwrap());
+    assert_eq!(to_vec(0u128).unwrap(), [0xFF, FORMAT_VERSION, b'Z', 0]);
+    assert_eq!(to_vec(5i128).unwrap(), to_vec(BigInt(5)).unwrap());
+    assert_eq!(crate::from_slice::<i64>(&to_vec(-5i128).unwrap()), Ok(-5));
+}
+
+#[test]
+fn test_binary_forms() {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    // Human readable formats get "127.0.0.1", this one gets the octets
+    let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let encoded = to_vec(ip).unwrap();
+    let octets = Value::Array([127, 0, 0, 1].iter().map(|&n| Value::Uint32(n)).collect());
+    assert_eq!(
+        crate::from_slice::<Value>(&encoded).unwrap(),
+        vec![(String::from("V4"), octets)].into_iter().collect()
+    );
+    assert_eq!(crate::from_slice::<IpAddr>(&encoded).unwrap(), ip);
+
+    struct Display;
+
+    impl Serialize for Display {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(&format_args!("{}-{}", "a", 12))
+        }
+    }
+
+    assert_eq!(to_vec(Display).unwrap(), to_vec("a-12").unwrap());
+}
+
+#[test]
+fn test_binary_ip() {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let v4 = AsBinaryIp(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)));
+    let data = to_vec(v4).unwrap();
+    assert_eq!(data, [0xFF, FORMAT_VERSION, b'B', 4, 192, 168, 0, 1]);
+    assert_eq!(crate::from_slice(&data), Ok(v4));
+
+    let v6 = AsBinaryIp(IpAddr::V6(Ipv6Addr::LOCALHOST));
+    let data = to_vec(v6).unwrap();
+    assert_eq!(data[2..4], [b'B', 16]);
+    assert_eq!(data[4..], Ipv6Addr::LOCALHOST.octets());
+    assert_eq!(crate::from_slice(&data), Ok(v6));
+
+    assert!(
+        crate::from_slice::<AsBinaryIp>(&to_vec(serde_bytes::Bytes::new(&[1, 2, 3])).unwrap())
+            .is_err()
+    );
 }
 
 #[test]
-fn test_unit_struct() {
-    #[derive(Serialize)]
-    struct Unit;
-    let unit = Unit;
+fn test_v8_compat_padding() {
+    // `v8.serialize(["a", "€"])` in Node.js
+    let node = [
+        0xFF, 0x0F, b'A', 2, b'"', 1, b'a', 0, b'c', 2, 0xAC, 0x20, b'$', 0, 2,
+    ];
     assert_eq!(
-        to_vec(unit).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 111, 123, 0]
+        crate::from_slice::<Value>(&node).unwrap(),
+        Value::Array(vec![Value::from("a"), Value::from("€")])
     );
+
+    let encode = |value: &Value| {
+        let mut serializer = SerializerBuilder::new()
+            .v8_compat(true)
+            .little_endian(true)
+            .build();
+        serializer.write_header();
+        serializer.serialize(value).unwrap()
+    };
+    let value = Value::Array(vec![
+        Value::from("a"),
+        Value::String(String::from("€"), true),
+    ]);
+    assert_eq!(encode(&value)[2..], node[2..]);
+    assert_eq!(value_to_vec(&value).unwrap()[7], b'c');
+
+    // Already aligned
+    assert_eq!(encode(&Value::String(String::from("b"), true))[2], b'c');
 }
 
 #[test]
-fn test_unit_variant() {
-    #[derive(Serialize)]
-    enum Enum {
-        Variant,
+fn test_reset() {
+    let mut serializer = Serializer::with_capacity(256);
+    let capacity = serializer.data.capacity();
+    let ptr = serializer.data.as_ptr();
+    for i in 0..1000 {
+        serializer.reset();
+        vec![i; 16].serialize(&mut serializer).unwrap();
+        assert_eq!(serializer.as_bytes(), &to_vec(vec![i; 16]).unwrap()[..]);
     }
+    assert_eq!(serializer.data.capacity(), capacity);
+    assert_eq!(serializer.data.as_ptr(), ptr);
 
-    assert_eq!(
-        to_vec(Enum::Variant).unwrap(),
-        vec![
-            0xFF,
-            FORMAT_VERSION,
-            111,
-            34,
-            7,
-            86,
-            97,
-            114,
-            105,
-            97,
-            110,
-            116,
-            95,
-            123,
-            1
-        ]
-    )
+    // Ids start over too
+    let shared = Rc::new(vec![true]);
+    let pair = (Shared(shared.clone()), Shared(shared));
+    serializer.reset();
+    pair.serialize(&mut serializer).unwrap();
+    let first = serializer.as_bytes().to_vec();
+    serializer.reset();
+    pair.serialize(&mut serializer).unwrap();
+    assert_eq!(serializer.as_bytes(), &first[..]);
 }
 
 #[test]
-fn test_newtype_struct() {
-    #[derive(Serialize)]
-    struct Newtype(bool);
+fn test_to_vec_with_capacity() {
+    let values: Vec<u32> = (0..10_000).collect();
+    let expected = to_vec(&values).unwrap();
+
+    // A large enough buffer is never grown, so it keeps its exact capacity
+    let data = to_vec_with_capacity(&values, expected.len()).unwrap();
+    assert_eq!(data, expected);
+    assert_eq!(data.capacity(), expected.len());
+
+    // Sequences of known length reserve a byte per element
+    let mut serializer = Serializer::new();
+    ser::Serializer::serialize_seq(&mut serializer, Some(values.len())).unwrap();
+    assert!(serializer.data.capacity() >= values.len());
+}
 
+#[test]
+fn test_as_js_set() {
+    use std::collections::BTreeSet;
+
+    let set: BTreeSet<i32> = [3, 1, 2].iter().copied().collect();
+    let elements = [b'I', 2, b'I', 4, b'I', 6];
+
+    let mut array = vec![0xFF, FORMAT_VERSION, b'A', 3];
+    array.extend(&elements);
+    array.extend(&[b'$', 0, 3]);
+    assert_eq!(to_vec(&set).unwrap(), array);
+
+    let mut js_set = vec![0xFF, FORMAT_VERSION, b'\''];
+    js_set.extend(&elements);
+    js_set.extend(&[b',', 3]);
+    assert_eq!(to_vec(AsJsSet(set.clone())).unwrap(), js_set);
+    assert_eq!(serialized_size(&AsJsSet(set.clone())), Ok(js_set.len()));
+    // Other serializers see the elements, `serde_json` an array
+    #[cfg(feature = "json")]
     assert_eq!(
-        to_vec(Newtype(true)).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 84]
+        serde_json::to_string(&AsJsSet(set.clone())).unwrap(),
+        "[1,2,3]"
+    );
+    assert_eq!(
+        crate::Deserializer::new().deserialize(&js_set).unwrap(),
+        Value::Set(vec![Value::Int32(1), Value::Int32(2), Value::Int32(3)])
+    );
+
+    assert_eq!(
+        to_vec(AsJsSet(Vec::<i32>::new())).unwrap(),
+        [0xFF, FORMAT_VERSION, b'\'', b',', 0]
     );
 }
 
 #[test]
-fn test_newtype_variant() {
-    #[derive(Serialize)]
-    enum Enum {
-        Variant(bool),
-    }
-
+fn test_none_as_null() {
+    let values = (None::<i32>, Some(1), ());
     assert_eq!(
-        to_vec(Enum::Variant(true)).unwrap(),
-        vec![
+        to_vec(values).unwrap(),
+        [
             0xFF,
             FORMAT_VERSION,
-            111,
-            34,
-            7,
-            86,
-            97,
-            114,
-            105,
-            97,
-            110,
-            116,
-            84,
-            123,
-            1
+            b'A',
+            3,
+            b'_',
+            b'I',
+            2,
+            b'_',
+            b'$',
+            0,
+            3
         ]
-    )
-}
+    );
 
-#[test]
-fn test_seq() {
+    let mut serializer = SerializerBuilder::new().none_as_null(true).build();
+    let data = serializer.serialize_to_vec(values).unwrap();
     assert_eq!(
-        to_vec([true, false]).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 65, 2, 84, 70, 36, 0, 2]
+        data,
+        [
+            0xFF,
+            FORMAT_VERSION,
+            b'A',
+            3,
+            b'0',
+            b'I',
+            2,
+            b'_',
+            b'$',
+            0,
+            3
+        ]
+    );
+    assert_eq!(
+        crate::from_slice::<(Option<i32>, Option<i32>, ())>(&data),
+        Ok(values)
     );
 }
 
 #[test]
-fn test_tuple() {
+fn test_nonfinite() {
+    let data = to_vec(f64::NAN).unwrap();
+    assert!(crate::from_slice::<f64>(&data).unwrap().is_nan());
+    let data = to_vec(f32::INFINITY).unwrap();
+    assert_eq!(crate::from_slice::<f64>(&data), Ok(f64::INFINITY));
+
+    let mut serializer = SerializerBuilder::new().reject_nonfinite(true).build();
     assert_eq!(
-        to_vec((false, true)).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 65, 2, 70, 84, 36, 0, 2]
+        serializer.serialize_to_vec(f64::NAN),
+        Err(Error::NonFiniteFloat)
     );
+    assert_eq!(
+        serializer.serialize_to_vec(vec![1.0, f64::NEG_INFINITY]),
+        Err(Error::NonFiniteFloat)
+    );
+    assert!(serializer.serialize_to_vec(1.5).is_ok());
 }
 
 #[test]
-fn test_tuple_struct() {
-    #[derive(Serialize)]
-    struct Tuple(bool, bool);
+fn test_negative_zero() {
+    let data = to_vec(-0.0f64).unwrap();
+    assert_eq!(data[3..], (-0.0f64).to_ne_bytes());
+    let decoded = crate::from_slice::<f64>(&data).unwrap();
+    assert_eq!(decoded.to_bits(), (-0.0f64).to_bits());
 
-    assert_eq!(
-        to_vec(Tuple(false, true)).unwrap(),
-        vec![0xFF, FORMAT_VERSION, 65, 2, 70, 84, 36, 0, 2]
-    );
+    let data = value_to_vec(&Value::Double(-0.0)).unwrap();
+    match crate::from_slice_checked(&data) {
+        Ok(Value::Double(value)) => assert_eq!(value.to_bits(), (-0.0f64).to_bits()),
+        other => panic!("expected a double, got {:?}", other),
+    }
 }
 
 #[test]
-fn test_tuple_variant() {
+fn test_serialized_size() {
+    use std::collections::BTreeMap;
+
     #[derive(Serialize)]
-    enum Enum {
-        Variant(bool, bool),
+    struct Frame {
+        id: u32,
+        name: String,
+        points: Vec<(f64, f64)>,
+        tags: BTreeMap<String, bool>,
+        parent: Option<Box<Frame>>,
     }
 
+    let mut tags = BTreeMap::new();
+    tags.insert(String::from("visible"), true);
+    let frame = Frame {
+        id: 7,
+        name: String::from("root"),
+        points: vec![(0.0, 1.0), (2.5, -3.0)],
+        tags,
+        parent: None,
+    };
+    let nested = Frame {
+        id: 8,
+        name: String::new(),
+        points: Vec::new(),
+        tags: BTreeMap::new(),
+        parent: Some(Box::new(frame)),
+    };
+    let shared = Rc::new(vec![1, 2]);
+
+    assert_eq!(serialized_size(&()).unwrap(), to_vec(()).unwrap().len());
     assert_eq!(
-        to_vec(Enum::Variant(true, false)).unwrap(),
-        vec![
-            0xFF,
-            FORMAT_VERSION,
-            111,
-            34,
-            7,
-            86,
-            97,
-            114,
-            105,
-            97,
-            110,
-            116,
-            65,
-            2,
-            84,
-            70,
-            36,
-            0,
-            2,
-            123,
-            1
-        ],
+        serialized_size(&-1i64).unwrap(),
+        to_vec(-1i64).unwrap().len()
+    );
+    assert_eq!(serialized_size(&"é").unwrap(), to_vec("é").unwrap().len());
+    // Formatted strings are counted as they're formatted, or formatted again
+    // if they turn out not to be ASCII
+    struct Formatted(&'static str);
+    impl Serialize for Formatted {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(&format_args!("{}!", self.0))
+        }
+    }
+    for string in ["ascii", "né", "€"] {
+        assert_eq!(
+            serialized_size(&Formatted(string)).unwrap(),
+            to_vec(Formatted(string)).unwrap().len()
+        );
+    }
+    assert_eq!(
+        serialized_size(&nested).unwrap(),
+        to_vec(&nested).unwrap().len()
+    );
+    let pair = (Shared(shared.clone()), Shared(shared));
+    assert_eq!(
+        serialized_size(&pair).unwrap(),
+        to_vec(&pair).unwrap().len()
     );
+    let value = Value::Array(vec![Value::Date(0.0), Value::BigInt(1)]);
+    assert_eq!(
+        serialized_size(&value).unwrap(),
+        to_vec(&value).unwrap().len()
+    );
+
+    // Failures don't leave anything behind for the next call
+    let view = TypedBytes {
+        ty: ArrayBufferViewType::Float64Array,
+        data: vec![0; 3],
+    };
+    assert!(serialized_size(&view).is_err());
+    assert_eq!(serialized_size(&true).unwrap(), 3);
 }
 
 #[test]
-fn test_map() {
-    let mut map = std::collections::HashMap::new();
-    map.insert("Hello", "World");
+fn test_to_slice() {
+    let value = (7u32, "hello", vec![1.5, 2.5], Some(true));
+    let expected = to_vec(&value).unwrap();
+
+    let mut out = [0xAA; 64];
+    let len = to_slice(&value, &mut out).unwrap();
+    assert_eq!(out[..len], expected[..]);
+    assert!(out[len..].iter().all(|&b| b == 0xAA));
 
+    // Exactly enough room is fine, one byte less isn't
+    let mut out = [0; 64];
+    assert_eq!(to_slice(&value, &mut out[..len]), Ok(len));
     assert_eq!(
-        to_vec(map).unwrap(),
-        vec![
-            0xFF,
-            FORMAT_VERSION,
-            59,
-            34,
-            5,
-            72,
-            101,
-            108,
-            108,
-            111,
-            34,
-            5,
-            87,
-            111,
-            114,
-            108,
-            100,
-            58,
-            2
-        ],
-    )
+        to_slice(&value, &mut out[..len - 1]),
+        Err(Error::BufferTooSmall {
+            len,
+            capacity: len - 1
+        })
+    );
+    assert_eq!(
+        to_slice((), &mut []),
+        Err(Error::BufferTooSmall {
+            len: 3,
+            capacity: 0
+        })
+    );
+
+    // Lengths inserted once they're known, and strings read back, work in
+    // place too
+    struct Formatted(u32);
+    impl Serialize for Formatted {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self.0 {
+                1 => serializer.collect_str("né"),
+                i => serializer.collect_str(&i),
+            }
+        }
+    }
+    struct Lazy;
+    impl Serialize for Lazy {
+        fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq((0..3).filter(|_| true).map(Formatted))
+        }
+    }
+    let expected = to_vec(Lazy).unwrap();
+    let mut out = [0; 32];
+    let len = to_slice(Lazy, &mut out).unwrap();
+    assert_eq!(out[..len], expected[..]);
+    for capacity in 0..len {
+        assert_eq!(
+            to_slice(Lazy, &mut out[..capacity]),
+            Err(Error::BufferTooSmall { len, capacity })
+        );
+    }
 }
 
 #[test]
-fn test_struct() {
-    #[derive(Serialize)]
-    struct Point {
-        x: i32,
-        y: i32,
-    }
+fn test_two_byte_string() {
+    // U+00E9 and U+1F600, the latter as a surrogate pair
+    let value = Value::String(String::from("é😀"), true);
+    let mut serializer = SerializerBuilder::new().little_endian(true).build();
+    serializer.write_header();
+    assert_eq!(
+        serializer.serialize(&value).unwrap()[2..],
+        [b'c', 6, 0xE9, 0x00, 0x3D, 0xD8, 0x00, 0xDE]
+    );
 
+    let mut serializer = SerializerBuilder::new().little_endian(false).build();
+    serializer.write_header();
     assert_eq!(
-        to_vec(Point { x: 69, y: 70 }).unwrap(),
-        vec![
-            0xFF,
-            FORMAT_VERSION,
-            111,
-            34,
-            1,
-            120,
-            73,
-            138,
-            1,
-            34,
-            1,
-            121,
-            73,
-            140,
-            1,
-            123,
-            2
-        ],
-    )
+        serializer.serialize(&value).unwrap()[2..],
+        [b'c', 6, 0x00, 0xE9, 0xD8, 0x3D, 0xDE, 0x00]
+    );
+
+    assert_eq!(
+        crate::Deserializer::new().deserialize(&value_to_vec(&value).unwrap()),
+        Ok(value)
+    );
 }
 
 #[test]
-fn test_struct_variant() {
+fn test_bigint_flags() {
+    assert_eq!(bigint_flags(false, 0), 0);
+    assert_eq!(bigint_flags(false, 1), 2);
+    assert_eq!(bigint_flags(true, 1), 3);
+    assert_eq!(bigint_flags(false, 8), 16);
+    assert_eq!(bigint_flags(true, 8), 17);
+    assert_eq!(bigint_flags(false, 16), 32);
+    assert_eq!(bigint_flags(true, 16), 33);
+}
+
+#[test]
+fn test_shared_fields() {
     #[derive(Serialize)]
-    enum Enum {
-        Point { x: i32, y: i32 },
+    struct Scene<'a> {
+        #[serde(serialize_with = "serialize_shared")]
+        first: Rc<Vec<i32>>,
+        #[serde(serialize_with = "serialize_shared")]
+        second: Rc<Vec<i32>>,
+        #[serde(serialize_with = "serialize_shared")]
+        third: &'a Arc<Vec<i32>>,
+        #[serde(serialize_with = "serialize_shared")]
+        fourth: Arc<Vec<i32>>,
+        // Without `serialize_shared` pointers are their contents
+        plain: &'a [i32],
     }
 
-    assert_eq!(
-        to_vec(Enum::Point { x: 69, y: 70 }).unwrap(),
-        vec![
-            0xFF,
-            FORMAT_VERSION,
-            111,
-            34,
-            5,
-            80,
-            111,
-            105,
-            110,
-            116,
-            111,
-            34,
-            1,
-            120,
-            73,
-            138,
-            1,
-            34,
-            1,
-            121,
-            73,
-            140,
-            1,
-            123,
-            2,
-            123,
-            1
-        ]
-    );
-}
+    let points = Rc::new(vec![1, 2]);
+    let other = Arc::new(vec![3]);
+    let scene = Scene {
+        first: points.clone(),
+        second: points.clone(),
+        third: &other,
+        fourth: other.clone(),
+        plain: &points,
+    };
+    let value = crate::Deserializer::new()
+        .deserialize(&to_vec(&scene).unwrap())
+        .unwrap();
+    let object = value.as_object().unwrap();
+    let points = Value::Array(vec![Value::Int32(1), Value::Int32(2)]);
+    let other = Value::Array(vec![Value::Int32(3)]);
+    // References are resolved to copies on decoding
+    assert_eq!(object["first"], points);
+    assert_eq!(object["second"], points);
+    assert_eq!(object["third"], other);
+    assert_eq!(object["fourth"], other);
+    assert_eq!(object["plain"], points);
 
-#[test]
-fn test_date_ext() {
-    // todo    
+    // The scene is object 0 and `first` object 1
+    let encoded = to_vec(&scene).unwrap();
+    let reference = [b'"', 6, b's', b'e', b'c', b'o', b'n', b'd', b'^', 1];
+    assert!(encoded.windows(reference.len()).any(|w| w == reference));
 }
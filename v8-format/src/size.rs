@@ -0,0 +1,78 @@
+use std::mem::size_of;
+
+use crate::common::Value;
+
+/// What an object property costs besides its key and value, a guess that
+/// covers the hash and index slot of an `IndexMap` or the share of a
+/// `BTreeMap` node.
+const PROPERTY_OVERHEAD: usize = 2 * size_of::<usize>();
+
+impl Value {
+    /// Roughly how many bytes this value takes up, itself and everything
+    /// it owns, e.g. to bound a cache of decoded values.
+    ///
+    /// Strings and buffers count their capacity, arrays, maps and sets
+    /// their spare capacity too. Object properties are estimated, the
+    /// exact overhead depends on the map and its load.
+    pub fn deep_size_of(&self) -> usize {
+        size_of::<Value>() + self.heap_size()
+    }
+
+    /// Bytes owned by the value, not counting the value itself.
+    fn heap_size(&self) -> usize {
+        match self {
+            Value::String(value, _) | Value::StringObject(value) => value.capacity(),
+            Value::RegExp { expr, .. } => expr.capacity(),
+            Value::Object(properties) => properties
+                .iter()
+                .map(|(key, value)| {
+                    PROPERTY_OVERHEAD + size_of::<String>() + key.capacity() + value.deep_size_of()
+                })
+                .sum(),
+            Value::Array(values) | Value::Set(values) => {
+                values.capacity() * size_of::<Value>()
+                    + values.iter().map(Value::heap_size).sum::<usize>()
+            }
+            Value::Map(entries) => {
+                entries.capacity() * size_of::<(Value, Value)>()
+                    + entries
+                        .iter()
+                        .map(|(key, value)| key.heap_size() + value.heap_size())
+                        .sum::<usize>()
+            }
+            Value::ArrayBuffer(data) | Value::WasmModule(data) | Value::HostObject(data) => {
+                data.capacity()
+            }
+            Value::ArrayBufferView { buffer, .. } => buffer.capacity(),
+            Value::Error { message, stack, .. } => {
+                message.as_ref().map_or(0, String::capacity)
+                    + stack.as_ref().map_or(0, String::capacity)
+            }
+            Value::Unsupported { data, .. } => data.capacity(),
+            _ => 0,
+        }
+    }
+}
+
+#[test]
+fn test_deep_size_of() {
+    let object = |len: usize| -> Value {
+        (0..len)
+            .map(|i| (format!("key{}", i), Value::from("x".repeat(100))))
+            .collect()
+    };
+    let small = object(2);
+    let large = object(200);
+    assert!(small.deep_size_of() > 2 * 100);
+    assert!(large.deep_size_of() > 200 * 100);
+    assert!(large.deep_size_of() > 50 * small.deep_size_of());
+
+    assert_eq!(Value::Null.deep_size_of(), size_of::<Value>());
+    assert_eq!(
+        Value::ArrayBuffer(vec![0; 1000]).deep_size_of(),
+        size_of::<Value>() + 1000
+    );
+    // Elements are counted once, inline in the array's allocation
+    let array = Value::Array(vec![Value::Int32(1); 10]);
+    assert_eq!(array.deep_size_of(), 11 * size_of::<Value>());
+}
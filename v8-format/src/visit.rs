@@ -0,0 +1,130 @@
+use crate::common::ObjectMap;
+use crate::common::Value;
+
+/// Callbacks for `Value::walk`, all of which do nothing by default.
+///
+/// Containers are visited before their contents. Variants without a
+/// callback of their own (dates, buffers, boxed primitives, etc.) go to
+/// `visit_other`.
+#[allow(unused_variables)]
+pub trait ValueVisitor {
+    fn visit_undefined(&mut self) {}
+
+    fn visit_null(&mut self) {}
+
+    fn visit_bool(&mut self, value: bool) {}
+
+    fn visit_int32(&mut self, value: i32) {}
+
+    fn visit_uint32(&mut self, value: u32) {}
+
+    fn visit_double(&mut self, value: f64) {}
+
+    fn visit_bigint(&mut self, value: i64) {}
+
+    fn visit_string(&mut self, value: &str) {}
+
+    fn visit_object(&mut self, properties: &ObjectMap) {}
+
+    /// Called with each property's key, before its value is visited.
+    fn visit_key(&mut self, key: &str) {}
+
+    fn visit_array(&mut self, values: &[Value]) {}
+
+    fn visit_map(&mut self, entries: &[(Value, Value)]) {}
+
+    fn visit_set(&mut self, values: &[Value]) {}
+
+    fn visit_other(&mut self, value: &Value) {}
+}
+
+impl Value {
+    /// Visits this value and everything in it, depth first. Map keys are
+    /// visited before their values.
+    pub fn walk<V: ValueVisitor>(&self, visitor: &mut V) {
+        match self {
+            Value::Undefined => visitor.visit_undefined(),
+            Value::Null => visitor.visit_null(),
+            Value::Boolean(value) => visitor.visit_bool(*value),
+            Value::Int32(value) => visitor.visit_int32(*value),
+            Value::Uint32(value) => visitor.visit_uint32(*value),
+            Value::Double(value) => visitor.visit_double(*value),
+            Value::BigInt(value) => visitor.visit_bigint(*value),
+            Value::String(value, _) => visitor.visit_string(value),
+            Value::Object(properties) => {
+                visitor.visit_object(properties);
+                for (key, value) in properties {
+                    visitor.visit_key(key);
+                    value.walk(visitor);
+                }
+            }
+            Value::Array(values) => {
+                visitor.visit_array(values);
+                for value in values {
+                    value.walk(visitor);
+                }
+            }
+            Value::Map(entries) => {
+                visitor.visit_map(entries);
+                for (key, value) in entries {
+                    key.walk(visitor);
+                    value.walk(visitor);
+                }
+            }
+            Value::Set(values) => {
+                visitor.visit_set(values);
+                for value in values {
+                    value.walk(visitor);
+                }
+            }
+            value => visitor.visit_other(value),
+        }
+    }
+}
+
+#[test]
+fn test_walk() {
+    #[derive(Default)]
+    struct Strings {
+        strings: usize,
+        keys: usize,
+        other: usize,
+    }
+
+    impl ValueVisitor for Strings {
+        fn visit_string(&mut self, _: &str) {
+            self.strings += 1;
+        }
+
+        fn visit_key(&mut self, _: &str) {
+            self.keys += 1;
+        }
+
+        fn visit_other(&mut self, _: &Value) {
+            self.other += 1;
+        }
+    }
+
+    let user: Value = vec![
+        (String::from("name"), Value::from("Ada")),
+        (String::from("age"), Value::Int32(36)),
+        (
+            String::from("tags"),
+            Value::Array(vec![Value::from("admin"), Value::Null]),
+        ),
+        (
+            String::from("meta"),
+            Value::Map(vec![(Value::from("since"), Value::Date(0.0))]),
+        ),
+    ]
+    .into_iter()
+    .collect();
+    let root = Value::Set(vec![user, Value::StringObject(String::from("boxed"))]);
+
+    let mut counter = Strings::default();
+    root.walk(&mut counter);
+    assert_eq!(counter.strings, 3);
+    assert_eq!(counter.keys, 4);
+    // The date and the string object
+    assert_eq!(counter.other, 2);
+}
@@ -0,0 +1,61 @@
+//! Checks that small messages are serialized without allocating when the
+//! `Serializer`'s output is kept inline. Counting allocations takes a global
+//! allocator, which gets a test binary of its own.
+#![cfg(feature = "smallvec")]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use serde::Serialize;
+use v8_format::{Serializer, INLINE_CAPACITY};
+
+/// Counts the allocations made by each thread.
+#[global_allocator]
+static COUNTING_ALLOCATOR: CountingAllocator = CountingAllocator;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[test]
+fn test_small_message_does_not_allocate() {
+    let message = "a short message, well under the inline size";
+    let mut serializer = Serializer::with_capacity(0);
+    let before = allocations();
+    serializer.reset();
+    message.serialize(&mut serializer).unwrap();
+    assert_eq!(allocations(), before);
+    assert!(serializer.as_bytes().len() <= INLINE_CAPACITY);
+
+    // The bookkeeping for containers allocates the first time round, after
+    // that a reused serializer doesn't either
+    (1, true, 2.5).serialize(&mut serializer).unwrap();
+    let before = allocations();
+    serializer.reset();
+    (1, true, 2.5).serialize(&mut serializer).unwrap();
+    assert_eq!(allocations(), before);
+
+    // Past the inline capacity it's written to the heap like before
+    let long = "x".repeat(INLINE_CAPACITY * 2);
+    let before = allocations();
+    serializer.reset();
+    long.serialize(&mut serializer).unwrap();
+    assert!(allocations() > before);
+}
@@ -0,0 +1,192 @@
+//! Checks values against V8's own `ValueSerializer`, through Node.js's
+//! `v8.serialize` and `v8.deserialize`, in both directions.
+#![cfg(feature = "node_interop")]
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use serde::Serialize;
+use v8_format::{ObjectMap, Value};
+
+/// Serializes each line of stdin, evaluated as JS, and prints it as hex.
+const SERIALIZE: &str = r#"
+const v8 = require("v8");
+const lines = require("fs").readFileSync(0, "utf8").split("\n").filter(Boolean);
+for (const line of lines) {
+    console.log(v8.serialize(eval(line)).toString("hex"));
+}
+"#;
+
+/// Deserializes the hex before the tab on each line of stdin and prints
+/// whether it's deeply equal to the JS after it.
+const DESERIALIZE: &str = r#"
+const v8 = require("v8");
+const util = require("util");
+const lines = require("fs").readFileSync(0, "utf8").split("\n").filter(Boolean);
+for (const line of lines) {
+    const [hex, source] = line.split("\t");
+    try {
+        const value = v8.deserialize(Buffer.from(hex, "hex"));
+        const expected = eval(source);
+        console.log(util.isDeepStrictEqual(value, expected) || util.inspect(value));
+    } catch (error) {
+        console.log(error.message);
+    }
+}
+"#;
+
+fn node(script: &str, input: &str) -> Vec<String> {
+    let mut child = Command::new("node")
+        .args(["-e", script])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("node_interop needs `node` on the PATH");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn object(properties: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        properties
+            .into_iter()
+            .map(|(key, value)| (String::from(key), value))
+            .collect::<ObjectMap>(),
+    )
+}
+
+/// JS expressions and the `Value` each should decode to.
+fn cases() -> Vec<(&'static str, Value)> {
+    vec![
+        ("undefined", Value::Undefined),
+        ("null", Value::Null),
+        ("true", Value::Boolean(true)),
+        ("1", Value::Int32(1)),
+        ("-1", Value::Int32(-1)),
+        ("1.5", Value::Double(1.5)),
+        ("-0", Value::Double(-0.0)),
+        ("NaN", Value::Double(f64::NAN)),
+        ("2 ** 53", Value::Double(9007199254740992.0)),
+        ("10n", Value::BigInt(10)),
+        ("-(2n ** 63n)", Value::BigInt(i64::MIN)),
+        ("''", Value::from("")),
+        ("'hello'", Value::from("hello")),
+        ("'h\\u00e9llo'", Value::from("héllo")),
+        ("'\\u20ac1'", Value::String(String::from("€1"), true)),
+        ("'\\u{1F600}'", Value::String(String::from("😀"), true)),
+        ("[]", Value::Array(Vec::new())),
+        (
+            "[1, 'a', null, [true]]",
+            Value::Array(vec![
+                Value::Int32(1),
+                Value::from("a"),
+                Value::Null,
+                Value::Array(vec![Value::Boolean(true)]),
+            ]),
+        ),
+        ("({})", object(Vec::new())),
+        (
+            "({ id: 7, name: '\\u20ac', tags: ['a'], nested: { ok: false } })",
+            object(vec![
+                ("id", Value::Int32(7)),
+                ("name", Value::String(String::from("€"), true)),
+                ("tags", Value::Array(vec![Value::from("a")])),
+                ("nested", object(vec![("ok", Value::Boolean(false))])),
+            ]),
+        ),
+        ("new Map()", Value::Map(Vec::new())),
+        (
+            "new Map([[1, 'one'], ['two', 2]])",
+            Value::Map(vec![
+                (Value::Int32(1), Value::from("one")),
+                (Value::from("two"), Value::Int32(2)),
+            ]),
+        ),
+        (
+            "new Set(['a', 1])",
+            Value::Set(vec![Value::from("a"), Value::Int32(1)]),
+        ),
+        ("new Date(0)", Value::Date(0.0)),
+        ("new Date(1700000000123)", Value::Date(1700000000123.0)),
+    ]
+}
+
+#[test]
+fn test_node_to_rust() {
+    let cases = cases();
+    let input: String = cases.iter().map(|(js, _)| format!("{}\n", js)).collect();
+    let output = node(SERIALIZE, &input);
+    assert_eq!(output.len(), cases.len());
+
+    for ((js, expected), hex) in cases.iter().zip(&output) {
+        let data = from_hex(hex);
+        let value = v8_format::from_slice_checked(&data)
+            .unwrap_or_else(|error| panic!("{} ({}): {}", js, hex, error));
+        assert_eq!(value.diff(expected), Vec::new(), "{}: {:?}", js, value);
+    }
+}
+
+#[test]
+fn test_rust_to_node() {
+    let mut input = String::new();
+    for (js, value) in cases() {
+        let data = v8_format::value_to_vec(&value).unwrap();
+        input.push_str(&format!("{}\t{}\n", to_hex(&data), js));
+    }
+
+    #[derive(Serialize)]
+    struct Point {
+        x: i32,
+        y: f64,
+        label: Option<String>,
+    }
+
+    let serde_cases = [
+        (
+            v8_format::to_vec(Point {
+                x: 1,
+                y: 2.5,
+                label: Some(String::from("\u{e9}\u{20ac}")),
+            }),
+            "({ x: 1, y: 2.5, label: '\\u00e9\\u20ac' })",
+        ),
+        (
+            v8_format::to_vec((1u8, "a", vec![true])),
+            "[1, 'a', [true]]",
+        ),
+        (v8_format::to_vec(u64::MAX), "2n ** 64n - 1n"),
+        (v8_format::to_vec('\u{1F600}'), "'\\u{1F600}'"),
+    ];
+    for (data, js) in &serde_cases {
+        input.push_str(&format!("{}\t{}\n", to_hex(data.as_ref().unwrap()), js));
+    }
+
+    let output = node(DESERIALIZE, &input);
+    let sources = input.lines().map(|line| line.split('\t').nth(1).unwrap());
+    assert_eq!(output.len(), input.lines().count());
+    for (js, result) in sources.zip(&output) {
+        assert_eq!(result, "true", "{}", js);
+    }
+}